@@ -0,0 +1,129 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use sha2::{Digest, Sha256};
+
+/// The rules of a `.dockerignore` file.
+pub struct DockerIgnore {
+    /// Patterns with whether they re-include (`!`) matching paths.
+    rules: Vec<(Pattern, bool)>,
+}
+
+impl DockerIgnore {
+    pub fn read(context: &Path) -> Result<Self> {
+        let path = context.join(".dockerignore");
+        if !path.exists() {
+            return Ok(DockerIgnore { rules: vec![] });
+        }
+        let text =
+            std::fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).context(format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rules = vec![];
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (include, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern.trim()),
+                None => (false, line),
+            };
+            let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+            rules.push((Pattern::new(pattern)?, include));
+        }
+        Ok(DockerIgnore { rules })
+    }
+
+    /// Whether a path relative to the build context is excluded. Like docker,
+    /// a pattern that matches a directory excludes everything inside it, and
+    /// the last matching rule wins.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        let mut excluded = false;
+        for (pattern, include) in self.rules.iter() {
+            let matches = path
+                .ancestors()
+                .filter(|p| !p.as_os_str().is_empty())
+                .any(|p| pattern.matches_path_with(p, options));
+            if matches {
+                excluded = !include;
+            }
+        }
+        excluded
+    }
+
+    fn has_exceptions(&self) -> bool {
+        self.rules.iter().any(|(_, include)| *include)
+    }
+}
+
+/// Hashes everything that goes into a docker build: the files in the build
+/// context that are not excluded by `.dockerignore`, the Dockerfile, and the
+/// given build settings.
+pub fn hash_build(context: &Path, dockerfile: &Path, settings: &[String]) -> Result<String> {
+    let ignore = DockerIgnore::read(context)?;
+    let mut files = vec![];
+    collect_files(context, Path::new(""), &ignore, &mut files)?;
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for setting in settings {
+        hasher.update(setting.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(
+        std::fs::read(dockerfile).context(format!("Failed to read {}", dockerfile.display()))?,
+    );
+    for file in files {
+        let path = context.join(&file);
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        let metadata = std::fs::symlink_metadata(&path)?;
+        if metadata.is_symlink() {
+            hasher.update(std::fs::read_link(&path)?.to_string_lossy().as_bytes());
+        } else {
+            #[cfg(unix)]
+            hasher.update(
+                std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()).to_le_bytes(),
+            );
+            hasher.update(
+                std::fs::read(&path).context(format!("Failed to read {}", path.display()))?,
+            );
+        }
+        hasher.update([0]);
+    }
+    let digest = hasher.finalize();
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn collect_files(
+    root: &Path,
+    relative: &Path,
+    ignore: &DockerIgnore,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let dir = root.join(relative);
+    let entries = std::fs::read_dir(&dir).context(format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            // Exceptions can re-include files inside an excluded directory.
+            if ignore.is_excluded(&path) && !ignore.has_exceptions() {
+                continue;
+            }
+            collect_files(root, &path, ignore, files)?;
+        } else if !ignore.is_excluded(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,395 @@
+//! `dep config validate`: checks deployment.yaml and the compose files, and
+//! reports every problem with its file and line instead of stopping at the
+//! first one.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::config::{self, DepConfig, InventoryHost};
+use crate::dockerfile::{DockerContainer, DockerFile};
+use crate::{compose, compose_files, schema};
+
+/// A problem in a file, e.g. `deployment.yaml:3: Unknown key servr`.
+#[derive(Debug)]
+pub struct Problem {
+    pub file: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file, line, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// The problems found in the deployment config at `path`, usually
+/// deployment.yaml, and the compose files of the project in the current
+/// directory.
+pub fn check(path: &Path) -> Vec<Problem> {
+    let mut problems = Problems {
+        path: path.to_path_buf(),
+        file: path.display().to_string(),
+        text: String::new(),
+        list: vec![],
+    };
+    if let Some(config) = check_config(&mut problems) {
+        check_servers(&config, &mut problems);
+        check_registry(&config, &mut problems);
+        check_files(&config, &mut problems);
+        check_compose(&config, &mut problems);
+    }
+    problems.list
+}
+
+/// The problems found so far, and the text of the file being checked, for
+/// finding the line of a problem.
+struct Problems {
+    /// The deployment config.
+    path: PathBuf,
+    file: String,
+    text: String,
+    list: Vec<Problem>,
+}
+
+impl Problems {
+    fn add(&mut self, line: Option<usize>, message: impl Into<String>) {
+        self.list.push(Problem {
+            file: self.file.clone(),
+            line,
+            message: message.into(),
+        });
+    }
+
+    /// Adds a problem on the line of the top level `key`.
+    fn at_key(&mut self, key: &str, message: impl Into<String>) {
+        let prefix = format!("{}:", key);
+        let line = self.line(|line| line.starts_with(&prefix));
+        self.add(line, message);
+    }
+
+    /// Adds a problem on the first line that starts with `key` at any
+    /// indentation.
+    fn at_entry(&mut self, key: &str, message: impl Into<String>) {
+        let prefix = format!("{}:", key);
+        let line = self.line(|line| line.trim_start().starts_with(&prefix));
+        self.add(line, message);
+    }
+
+    /// Adds a problem on the first line that mentions `value`.
+    fn at_value(&mut self, value: &str, message: impl Into<String>) {
+        let line = self.line(|line| !line.trim_start().starts_with('#') && line.contains(value));
+        self.add(line, message);
+    }
+
+    fn line(&self, matches: impl Fn(&str) -> bool) -> Option<usize> {
+        let index = self.text.lines().position(matches)?;
+        Some(index + 1)
+    }
+
+    /// Adds an error, with the line from the YAML error in it if it has one.
+    fn error(&mut self, error: &anyhow::Error) {
+        let yaml = error
+            .chain()
+            .find_map(|e| e.downcast_ref::<serde_yaml::Error>());
+        let line = yaml.and_then(|e| e.location()).map(|l| l.line());
+        self.add(line, format!("{:#}", error));
+    }
+}
+
+/// Checks the keys and values of deployment.yaml, each top level key on its
+/// own so that every wrong value is reported.
+fn check_config(problems: &mut Problems) -> Option<DepConfig> {
+    let text = match std::fs::read_to_string(&problems.path) {
+        Ok(text) => text,
+        Err(e) => {
+            problems.add(None, format!("Failed to open: {}", e));
+            return None;
+        }
+    };
+    problems.text = text.clone();
+    let mut value: Value = match serde_yaml::from_str(&text) {
+        Ok(value) => value,
+        Err(e) => {
+            problems.error(&e.into());
+            return None;
+        }
+    };
+    let interpolated = match config::interpolate_config(&mut value) {
+        Ok(interpolated) => interpolated,
+        Err(e) => {
+            problems.error(&e);
+            return None;
+        }
+    };
+    let path = problems.path.clone();
+    // Parsing the text keeps the line numbers in errors.
+    let parse = || {
+        let mut config = match interpolated {
+            true => config::from_value(value.clone())?,
+            false => config::parse(&text)?,
+        };
+        config.path = path.clone();
+        anyhow::Ok(config)
+    };
+    let Value::Mapping(mapping) = &value else {
+        problems.add(None, "Expected a mapping of settings");
+        return None;
+    };
+
+    let schema = schema::schema();
+    let known = |properties: &serde_json::Value| -> BTreeSet<String> {
+        let properties = properties.as_object().into_iter().flatten();
+        properties.map(|(key, _)| key.clone()).collect()
+    };
+    let keys = known(&schema["properties"]);
+    let environment_keys = known(&schema["definitions"]["Environment"]["properties"]);
+    let mut valid = true;
+    for (key, value) in mapping.iter() {
+        let Some(key) = key.as_str() else {
+            problems.add(None, format!("Invalid key {:?}", key));
+            valid = false;
+            continue;
+        };
+        if !keys.contains(key) {
+            problems.at_key(key, format!("Unknown key {}", key));
+            continue;
+        }
+        if key == "environments" {
+            for (name, environment) in value.as_mapping().into_iter().flatten() {
+                let environment = environment.as_mapping().into_iter().flatten();
+                for key in environment.filter_map(|(key, _)| key.as_str()) {
+                    if !environment_keys.contains(key) {
+                        let name = name.as_str().unwrap_or_default();
+                        problems
+                            .at_entry(key, format!("Unknown key {} in environment {}", key, name));
+                    }
+                }
+            }
+        }
+        // The name is required, so it is added to check the other keys.
+        let mut single = Mapping::new();
+        single.insert("name".into(), "name".into());
+        single.insert(key.into(), value.clone());
+        if let Err(e) = config::from_value(Value::Mapping(single)) {
+            problems.at_key(key, format!("{}: {}", key, e));
+            valid = false;
+        }
+    }
+    if !valid {
+        return None;
+    }
+
+    let mut config = match parse() {
+        Ok(config) => config,
+        Err(e) => {
+            problems.error(&e);
+            return None;
+        }
+    };
+    for name in config.environments.keys() {
+        let environment = parse().and_then(|mut config| {
+            config.apply_environment(name)?;
+            config.validate()
+        });
+        if let Err(e) = environment {
+            problems.at_entry(name, format!("Environment {}: {:#}", name, e));
+        }
+    }
+    if let Err(e) = config.validate() {
+        problems.add(None, format!("{:#}", e));
+    }
+    Some(config)
+}
+
+/// Checks that the servers are host names that ssh accepts, optionally with
+/// a user.
+fn check_servers(config: &DepConfig, problems: &mut Problems) {
+    let inventory = config.inventory.values().flatten().map(InventoryHost::host);
+    let servers = std::iter::once(config.server.as_str())
+        .chain(config.servers.iter().map(String::as_str))
+        .chain(inventory);
+    let mut checked = BTreeSet::new();
+    for server in servers.filter(|s| !s.is_empty()) {
+        if !checked.insert(server) {
+            continue;
+        }
+        let host = server.rsplit('@').next().unwrap_or(server);
+        let valid = |c: char| c.is_ascii_alphanumeric() || "-._:[]".contains(c);
+        if host.is_empty() || !host.chars().all(valid) {
+            problems.at_value(
+                server,
+                format!(
+                    "Invalid server {:?}. Expected a host name, optionally with a user, like deploy@example.org",
+                    server
+                ),
+            );
+        }
+    }
+    if let Some(canary) = &config.canary {
+        if !config.servers.contains(canary) {
+            problems.at_key(
+                "canary",
+                format!("Canary {} is not one of the servers", canary),
+            );
+        }
+    }
+}
+
+/// Checks that the registry is a host with an optional port and path, like
+/// in an image name.
+fn check_registry(config: &DepConfig, problems: &mut Problems) {
+    let registry = &config.registry;
+    if registry.is_empty() {
+        return;
+    }
+    let message = if registry.contains("://") {
+        "without the scheme"
+    } else if registry.ends_with('/') {
+        "without a trailing /"
+    } else if registry.chars().any(|c| c.is_ascii_uppercase()) {
+        "in lowercase"
+    } else if !registry
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-._:/".contains(c))
+    {
+        "as a host with an optional port and path"
+    } else {
+        return;
+    };
+    problems.at_key(
+        "registry",
+        format!(
+            "Invalid registry {:?}. Write it {}, e.g. registry.example.org:5000/team",
+            registry, message
+        ),
+    );
+}
+
+/// Checks that the files that are pushed or decrypted exist.
+fn check_files(config: &DepConfig, problems: &mut Problems) {
+    let additional = config.additional_files.iter().flatten();
+    let files = additional
+        .map(|path| ("Additional file", path.as_path()))
+        .chain(
+            config
+                .compose_files
+                .iter()
+                .map(|path| ("Compose file", path.as_path())),
+        )
+        .chain(config.secrets.iter().map(|s| ("Secret", s.path())))
+        .chain(
+            config
+                .build_secrets
+                .values()
+                .filter_map(|s| s.file.as_deref())
+                .map(|path| ("Build secret", path)),
+        );
+    for (kind, path) in files {
+        if !path.exists() {
+            problems.at_value(
+                &path.display().to_string(),
+                format!("{} {} does not exist", kind, path.display()),
+            );
+        }
+    }
+}
+
+/// Checks that the compose files parse, that every service has an image or
+/// is built from a directory with a Dockerfile, and that the services that
+/// deployment.yaml refers to exist.
+fn check_compose(config: &DepConfig, problems: &mut Problems) {
+    let paths = compose_files(config);
+    if paths.iter().any(|path| !path.exists()) {
+        return;
+    }
+    problems.file = paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    problems.text = match paths.as_slice() {
+        [path] => std::fs::read_to_string(path).unwrap_or_default(),
+        _ => String::new(),
+    };
+    let compose = match compose::read(&paths) {
+        Ok(compose) => compose,
+        Err(e) => {
+            problems.error(&e);
+            return;
+        }
+    };
+    let Some(services) = compose.get("services").and_then(Value::as_mapping) else {
+        problems.add(None, "No services");
+        return;
+    };
+    for (name, service) in services {
+        let name = name.as_str().unwrap_or_default();
+        if service.get("build").is_none() && service.get("image").is_none() {
+            problems.at_entry(
+                name,
+                format!("Service {} has neither build nor image", name),
+            );
+        }
+    }
+
+    let containers = serde_yaml::from_value::<DockerFile>(compose.clone())
+        .map_err(anyhow::Error::from)
+        .and_then(DockerContainer::from_docker_file);
+    match containers {
+        Ok(containers) => {
+            for container in containers {
+                check_build(&container, problems);
+            }
+        }
+        Err(e) => problems.error(&e),
+    }
+
+    problems.file = problems.path.display().to_string();
+    problems.text = std::fs::read_to_string(&problems.path).unwrap_or_default();
+    for migration in config.migrations.iter() {
+        if !services.contains_key(migration.service.as_str()) {
+            problems.at_value(
+                &migration.service,
+                format!(
+                    "Migration runs in service {}, which is not in the compose files",
+                    migration.service
+                ),
+            );
+        }
+    }
+}
+
+/// Checks that the build context of a local directory has the Dockerfile.
+fn check_build(container: &DockerContainer, problems: &mut Problems) {
+    // Contexts may also be git repositories and URLs.
+    if container.build_dir.contains("://") || container.build_dir.starts_with("git@") {
+        return;
+    }
+    let context = Path::new(&container.build_dir);
+    if !context.is_dir() {
+        problems.at_entry(
+            &container.name,
+            format!(
+                "The build context {} of service {} is not a directory",
+                container.build_dir, container.name
+            ),
+        );
+        return;
+    }
+    let dockerfile = container.dockerfile.as_deref().unwrap_or("Dockerfile");
+    if !context.join(dockerfile).is_file() {
+        problems.at_entry(
+            &container.name,
+            format!(
+                "Service {} has no {} in {}",
+                container.name, dockerfile, container.build_dir
+            ),
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Running in CI: GitHub Actions annotations for the headers, warnings and
+//! errors, and a Markdown job summary of the images and the deploy.
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+static CI: AtomicBool = AtomicBool::new(false);
+
+/// Whether a `::group::` was printed without its `::endgroup::`.
+static GROUP: AtomicBool = AtomicBool::new(false);
+
+/// The events of this run, for the job summary.
+static EVENTS: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+/// Whether the `CI` environment variable, which most CI systems set, says
+/// that this is a CI job.
+pub fn detect() -> bool {
+    std::env::var("CI").is_ok_and(|ci| !ci.is_empty() && ci != "false" && ci != "0")
+}
+
+/// Sets whether everything in this process runs in CI mode.
+pub fn set_active(ci: bool) {
+    CI.store(ci, Ordering::SeqCst);
+}
+
+pub fn is_active() -> bool {
+    CI.load(Ordering::SeqCst)
+}
+
+/// Whether annotations for GitHub Actions are printed.
+pub fn is_github() -> bool {
+    is_active() && std::env::var("GITHUB_ACTIONS").is_ok_and(|v| v == "true")
+}
+
+/// Starts a collapsed group in the GitHub Actions log, ending the previous
+/// one.
+pub fn start_group(title: &str) {
+    end_group();
+    println!("::group::{}", escape(title));
+    GROUP.store(true, Ordering::SeqCst);
+}
+
+pub fn end_group() {
+    if GROUP.swap(false, Ordering::SeqCst) {
+        println!("::endgroup::");
+    }
+}
+
+/// A GitHub Actions annotation such as `::error::message` of `kind` for a
+/// message that starts with `kind: `, e.g. from `warn!`.
+pub fn annotation(kind: &str, message: &str) -> String {
+    let message = message
+        .strip_prefix(&format!("{}: ", kind))
+        .unwrap_or(message);
+    format!("::{}::{}", kind, escape(message))
+}
+
+/// Escapes the characters that end an annotation.
+fn escape(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Keeps an event for the job summary.
+pub fn record(event: &Value) {
+    if is_active() {
+        EVENTS.lock().unwrap().push(event.clone());
+    }
+}
+
+/// Appends a Markdown summary of `command` to the file in
+/// `GITHUB_STEP_SUMMARY`: the images that were built and pushed with their
+/// digests, and the result and duration of the deploy.
+pub fn write_summary(command: &str, error: Option<&anyhow::Error>) -> Result<()> {
+    let Some(path) = std::env::var_os("GITHUB_STEP_SUMMARY").filter(|_| is_active()) else {
+        return Ok(());
+    };
+    let events = EVENTS.lock().unwrap();
+    let of = |name: &str| {
+        events
+            .iter()
+            .filter(move |event| event["event"] == name)
+            .collect::<Vec<_>>()
+    };
+
+    let mut summary = String::new();
+    let result = match error {
+        None => "succeeded",
+        Some(_) => "failed",
+    };
+    let _ = writeln!(summary, "### dep {} {}\n", command, result);
+
+    let built = of("image_built");
+    if !built.is_empty() {
+        let _ = writeln!(summary, "| Service | Image | Digest | Build |");
+        let _ = writeln!(summary, "| --- | --- | --- | --- |");
+        let pushed = of("image_pushed");
+        for event in built {
+            let digest = pushed
+                .iter()
+                .find(|p| p["service"] == event["service"])
+                .and_then(|p| p["digest"].as_str())
+                .unwrap_or("");
+            let _ = writeln!(
+                summary,
+                "| {} | `{}` | {} | {:.1} s |",
+                text(&event["service"]),
+                text(&event["image"]),
+                match digest.is_empty() {
+                    true => String::new(),
+                    false => format!("`{}`", digest),
+                },
+                event["seconds"].as_f64().unwrap_or(0.0)
+            );
+        }
+        summary.push('\n');
+    }
+
+    for event in of("deploy_finished") {
+        let servers: Vec<String> = event["servers"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(text)
+            .collect();
+        let _ = writeln!(
+            summary,
+            "{} `{}` to {} in {:.1} seconds.\n",
+            match event["ok"].as_bool() {
+                Some(true) => "Deployed",
+                _ => "Failed to deploy",
+            },
+            text(&event["version"]),
+            servers.join(", "),
+            event["seconds"].as_f64().unwrap_or(0.0)
+        );
+    }
+    if let Some(error) = error {
+        let _ = writeln!(summary, "```\n{:?}\n```", error);
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .context("Failed to open GITHUB_STEP_SUMMARY")?;
+    file.write_all(summary.as_bytes())
+        .context("Failed to write GITHUB_STEP_SUMMARY")
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
@@ -0,0 +1,79 @@
+//! Shell completion scripts for `dep completions`, generated by clap_complete
+//! from the clap definition of the command line. In bash and fish, service
+//! names are also completed by calling the hidden `dep __services` command,
+//! which lists the services in the compose files of the current directory.
+
+use clap::Command;
+
+pub use clap_complete::Shell;
+
+/// The completion script for `shell`.
+pub fn generate(mut command: Command, shell: Shell) -> String {
+    let mut script = vec![];
+    clap_complete::generate(shell, &mut command, "dep", &mut script);
+    let mut script = String::from_utf8_lossy(&script).into_owned();
+    let subcommands = service_subcommands(&command);
+    match shell {
+        Shell::Bash => script.push_str(&bash_services(&command, &subcommands)),
+        Shell::Fish => script.push_str(&fish_services(&subcommands)),
+        _ => {}
+    }
+    script
+}
+
+/// The subcommands whose positional arguments are service names.
+fn service_subcommands(command: &Command) -> Vec<&str> {
+    command
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set())
+        .filter(|c| {
+            c.get_positionals()
+                .any(|a| matches!(a.get_id().as_str(), "services" | "service"))
+        })
+        .map(|c| c.get_name())
+        .collect()
+}
+
+/// Wraps the generated `_dep` function to add the service names after the
+/// `subcommands`.
+fn bash_services(command: &Command, subcommands: &[&str]) -> String {
+    let mut value_flags: Vec<String> = command
+        .get_arguments()
+        .filter(|a| !a.is_positional() && a.get_action().takes_values())
+        .flat_map(|a| {
+            let short = a.get_short().map(|s| format!("-{}", s));
+            let long = a.get_long().map(|l| format!("--{}", l));
+            short.into_iter().chain(long)
+        })
+        .collect();
+    value_flags.sort();
+    format!(
+        r#"
+_dep_services() {{
+    _dep "$@"
+    local cur=${{COMP_WORDS[COMP_CWORD]}} i
+    [[ $cur == -* ]] && return
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case ${{COMP_WORDS[i]}} in
+            {value_flags}) ((i++)) ;;
+            -*) ;;
+            {subcommands})
+                COMPREPLY+=($(compgen -W "$(dep __services 2>/dev/null)" -- "$cur"))
+                return ;;
+            *) return ;;
+        esac
+    done
+}}
+complete -F _dep_services -o bashdefault -o default dep
+"#,
+        value_flags = value_flags.join("|"),
+        subcommands = subcommands.join("|"),
+    )
+}
+
+fn fish_services(subcommands: &[&str]) -> String {
+    format!(
+        "complete -c dep -n '__fish_seen_subcommand_from {}' -f -a '(dep __services 2>/dev/null)'\n",
+        subcommands.join(" ")
+    )
+}
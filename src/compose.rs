@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde_yaml::{Mapping, Value};
+
+/// Keys whose value replaces the value from earlier files instead of being
+/// merged with it.
+const REPLACED_KEYS: &[&str] = &["command", "entrypoint", "test"];
+
+/// Keys that may be written either as a mapping or as a list of `KEY=VALUE`.
+const KEY_VALUE_KEYS: &[&str] = &["environment", "labels", "args"];
+
+/// Reads the compose files and merges them in order, like
+/// `docker compose -f a.yaml -f b.yaml` does.
+pub fn read(paths: &[PathBuf]) -> Result<Value> {
+    let mut merged = Value::Null;
+    for path in paths {
+        let text =
+            std::fs::read_to_string(path).context(format!("Failed to open {}", path.display()))?;
+        let value: Value =
+            serde_yaml::from_str(&text).context(format!("Failed to parse {}", path.display()))?;
+        merged = merge(merged, value, None);
+    }
+    Ok(merged)
+}
+
+/// Merges `over` into `base`. Mappings are merged recursively, lists are
+/// concatenated without duplicates, and service volumes are merged by their
+/// target path.
+fn merge(base: Value, over: Value, key: Option<&str>) -> Value {
+    if let Some(key) = key {
+        if REPLACED_KEYS.contains(&key) {
+            return over;
+        }
+        if KEY_VALUE_KEYS.contains(&key) && (base.is_sequence() || over.is_sequence()) {
+            return merge(key_value_mapping(base), key_value_mapping(over), None);
+        }
+    }
+    match (base, over) {
+        (Value::Mapping(mut base), Value::Mapping(over)) => {
+            for (k, v) in over {
+                match base.get_mut(&k) {
+                    Some(existing) => {
+                        let previous = std::mem::replace(existing, Value::Null);
+                        *existing = merge(previous, v, k.as_str());
+                    }
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+            Value::Mapping(base)
+        }
+        (Value::Sequence(mut base), Value::Sequence(over)) => {
+            for item in over {
+                if key == Some("volumes") {
+                    if let Some(target) = volume_target(&item) {
+                        base.retain(|v| volume_target(v).as_deref() != Some(target.as_str()));
+                    }
+                }
+                if !base.contains(&item) {
+                    base.push(item);
+                }
+            }
+            Value::Sequence(base)
+        }
+        (_, over) => over,
+    }
+}
+
+/// Converts a list of `KEY=VALUE` strings to a mapping.
+fn key_value_mapping(value: Value) -> Value {
+    let Value::Sequence(items) = value else {
+        return value;
+    };
+    let mut mapping = Mapping::new();
+    for item in items {
+        if let Value::String(item) = item {
+            match item.split_once('=') {
+                Some((k, v)) => mapping.insert(k.into(), v.into()),
+                None => mapping.insert(item.into(), Value::Null),
+            };
+        }
+    }
+    Value::Mapping(mapping)
+}
+
+/// The path inside the container of a volume in short (`src:target:mode`) or
+/// long syntax.
+fn volume_target(volume: &Value) -> Option<String> {
+    match volume {
+        Value::String(s) => {
+            let mut parts = s.split(':');
+            let first = parts.next()?;
+            Some(parts.next().unwrap_or(first).to_string())
+        }
+        Value::Mapping(m) => m.get("target")?.as_str().map(|s| s.to_string()),
+        _ => None,
+    }
+}
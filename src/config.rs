@@ -1,20 +1,985 @@
+use std::collections::BTreeMap;
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
+use anyhow::{bail, Context, Result};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize, Debug)]
+use crate::import::{self, RemoteProject};
+use crate::notify::Notification;
+use crate::template::Template;
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
+#[schemars(deny_unknown_fields)]
 pub struct DepConfig {
+    /// The file the config was read from, for error messages.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub path: PathBuf,
+    /// Name of the project, used for the image names and the remote
+    /// directory.
     pub name: String,
+    /// Server to deploy to. A list in deployment.yaml is moved to `servers`.
+    #[serde(default)]
+    #[schemars(with = "ServerOrList")]
     pub server: String,
+    /// Servers to deploy the same project to, instead of a single `server`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<String>,
+    /// One of the `servers` that is deployed to first. The other servers
+    /// are only deployed to when it is healthy and `canary_check` passes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canary: Option<String>,
+    /// Smoke test that runs after deploying to the `canary`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canary_check: Option<Hook>,
+    /// Deploy to the servers one at a time, waiting for each to be healthy,
+    /// and stop at the first server that fails.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rolling: Option<Rolling>,
+    /// Ask for the project name before deploying, to avoid deploying to
+    /// production by mistake. `--yes` skips the question.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub protected: bool,
+    /// Refuse to push and deploy with uncommitted changes or commits that
+    /// are not pushed. `--allow-dirty` overrides it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub require_clean: bool,
+    /// Branches that may be pushed and deployed, such as `main` or
+    /// `release/*`. Any branch may be deployed when it is empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_branches: Vec<String>,
+    /// Only push and deploy commits that are tagged.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub require_tag: bool,
+    /// Groups of servers, such as `web` and `workers`. The servers of all
+    /// groups are deployed to, unless groups are selected with `--group`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub inventory: BTreeMap<String, Vec<InventoryHost>>,
+    /// Registry to push images to. Not needed with `transport: ssh`.
+    #[serde(default)]
     pub registry: String,
+    /// Directory on the server to deploy to. Defaults to `name`, relative to
+    /// the home directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_path: Option<String>,
+    /// Compose command on the server, e.g. `docker-compose` or
+    /// `podman-compose`. Detected when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compose_command: Option<String>,
+    /// Name of the compose project on the server. Defaults to the name of
+    /// the remote directory, like docker compose does.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(regex(pattern = r"^[a-z0-9][a-z0-9_-]*$"))]
+    pub compose_project: Option<String>,
+    /// How docker compose is run for the server.
+    #[serde(default, skip_serializing_if = "DeployMode::is_ssh")]
+    pub deploy_mode: DeployMode,
+    /// Docker context of the server with `deploy_mode: docker-context`.
+    /// Defaults to `DOCKER_HOST=ssh://<server>`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docker_context: Option<String>,
+    /// Options for every ssh and rsync connection to the server.
+    #[serde(default, skip_serializing_if = "SshConfig::is_empty")]
+    pub ssh: SshConfig,
+    /// Options for pushing files with rsync.
+    #[serde(default, skip_serializing_if = "RsyncConfig::is_default")]
+    pub rsync: RsyncConfig,
+    /// Options for `docker compose up -d` on the server.
+    #[serde(default, skip_serializing_if = "ComposeUpConfig::is_default")]
+    pub compose_up: ComposeUpConfig,
+    /// Checks of the server before anything is built or pushed.
+    #[serde(default, skip_serializing_if = "PreflightConfig::is_default")]
+    pub preflight: PreflightConfig,
+    /// Retries of pushes and remote commands that fail on the network.
+    #[serde(default, skip_serializing_if = "RetryConfig::is_default")]
+    pub retry: RetryConfig,
+    /// How long remote commands may run before they are killed.
+    #[serde(default, skip_serializing_if = "TimeoutConfig::is_default")]
+    pub timeouts: TimeoutConfig,
+    /// How images get to the server.
+    #[serde(default, skip_serializing_if = "Transport::is_registry")]
+    pub transport: Transport,
+    /// Where the images are built.
+    #[serde(default, skip_serializing_if = "BuildOn::is_local")]
+    pub build_on: BuildOn,
+    /// The container engine that builds and pushes images locally.
+    #[serde(default, skip_serializing_if = "Engine::is_docker")]
+    pub engine: Engine,
+    /// Path of the engine binary, when it is not `docker` or `podman` on the
+    /// PATH.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine_binary: Option<String>,
+    /// Files that are pushed to the server next to the compose file, such as
+    /// `config/` or `config/*.toml`.
     #[serde(rename = "additionalFiles")]
     pub additional_files: Option<Vec<PathBuf>>,
+    /// Compose files that are merged into the pushed docker-compose.yaml,
+    /// e.g. `[docker-compose.yaml, docker-compose.prod.yaml]`. Defaults to
+    /// `docker-compose.yaml`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub compose_files: Vec<PathBuf>,
+    /// Bash script that runs before building.
     pub build: Option<String>,
+    /// Template for the image tag, e.g. `{date}-{sha}` or `{branch}-{sha}`.
+    /// Defaults to `{date}-{describe}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_format: Option<String>,
+    /// Tags that images are pushed with in addition to the version, e.g.
+    /// `[latest, "{branch}"]`. Supports the `version_format` placeholders.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_tags: Vec<String>,
+    /// Git tag to create and push after a successful deploy, e.g.
+    /// `deploy/production/{version}`. Supports `{version}` and the
+    /// `version_format` placeholders.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag_deploys: Option<String>,
+    /// Platforms to build images for, such as `linux/arm64`. Builds use
+    /// `docker buildx` when set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<String>,
+    /// BuildKit cache sources for all services, e.g.
+    /// `type=registry,ref={registry}/{service}:buildcache`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cache_from: Vec<String>,
+    /// BuildKit cache destinations for all services.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cache_to: Vec<String>,
+    /// Number of docker builds to run in parallel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 1))]
+    pub jobs: Option<usize>,
+    /// Labels added to every built image, next to the OCI labels for the
+    /// git revision, build date and version.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Deploy images by their registry digest instead of their tag.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pin_digests: bool,
+    /// BuildKit secrets for every build, by id. Use them in a Dockerfile
+    /// with `RUN --mount=type=secret,id=<id>`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub build_secrets: BTreeMap<String, BuildSecret>,
+    /// ssh agent sockets or keys to forward to every build, passed to
+    /// `docker build --ssh`, e.g. `default`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub build_ssh: Vec<String>,
+    /// Reuse the previously pushed image of services whose build context
+    /// has not changed instead of building them again.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip_unchanged: bool,
+    /// Named environments, such as staging and production, that override
+    /// the top level settings when selected with `--env`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environments: BTreeMap<String, Environment>,
+    /// Seconds to wait for services with a healthcheck to become healthy
+    /// after deploying. No waiting is done when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub healthcheck_timeout: Option<u64>,
+    /// Redeploy the previous version when a deploy fails.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rollback_on_failure: bool,
+    /// Number of deployed versions whose images are kept on the server.
+    /// Older images are removed after each deploy when set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<usize>,
+    /// Push every version into its own `releases/<version>` directory on
+    /// the server, and switch a `current` symlink to it when deploying.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub releases: bool,
+    /// Script that runs after building, before anything is pushed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_push: Option<Hook>,
+    /// Script that runs after pushing, before `docker compose up -d`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_deploy: Option<Hook>,
+    /// Commands that run in one-off containers of the new version before it
+    /// is started, such as database migrations.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub migrations: Vec<Migration>,
+    /// Checks that run after the new version is started. A deploy fails when
+    /// any of them fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub smoke_tests: Vec<SmokeTest>,
+    /// Seconds to show the logs of the services after deploying, while
+    /// checking that no container keeps restarting or exits with an error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watch: Option<u64>,
+    /// Script that runs after a successful deploy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_deploy: Option<Hook>,
+    /// How the new version replaces the running one.
+    #[serde(default, skip_serializing_if = "Strategy::is_recreate")]
+    pub strategy: Strategy,
+    /// Script that sends traffic to the new colour in a blue-green deploy.
+    /// The colour and its compose project are in `$DEP_COLOR` and
+    /// `$DEP_PROJECT`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub switch_traffic: Option<Hook>,
+    /// Credentials used to log in to the registry before pushing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registry_auth: Option<RegistryAuth>,
+    /// Webhooks that are notified when a deploy succeeds or fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notifications: Vec<Notification>,
+    /// Sentry release that is created for the version, with a deploy marker
+    /// after a successful deploy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sentry: Option<SentryConfig>,
+    /// sops or age encrypted files that are decrypted and pushed to the server.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secrets: Vec<Secret>,
+}
+
+/// Settings of a named environment, which override the top level ones.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
+#[schemars(deny_unknown_fields)]
+pub struct Environment {
+    pub name: Option<String>,
+    #[schemars(with = "Option<ServerOrList>")]
+    pub server: Option<String>,
+    pub servers: Option<Vec<String>>,
+    pub canary: Option<String>,
+    pub inventory: Option<BTreeMap<String, Vec<InventoryHost>>>,
+    pub registry: Option<String>,
+    #[serde(rename = "additionalFiles")]
+    pub additional_files: Option<Vec<PathBuf>>,
+    pub compose_files: Option<Vec<PathBuf>>,
+    pub secrets: Option<Vec<Secret>>,
+    pub registry_auth: Option<RegistryAuth>,
+    pub ssh: Option<SshConfig>,
+    pub remote_path: Option<String>,
+    pub compose_project: Option<String>,
+    pub protected: Option<bool>,
+    pub require_clean: Option<bool>,
+    pub allowed_branches: Option<Vec<String>>,
+    pub require_tag: Option<bool>,
+    pub tag_deploys: Option<String>,
+    pub sentry: Option<SentryConfig>,
+    pub notifications: Option<Vec<Notification>>,
+}
+
+/// The schema of `server`: a host, or a list of hosts that is the same as
+/// setting `servers`.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum ServerOrList {
+    Host(String),
+    Hosts(Vec<String>),
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+#[schemars(deny_unknown_fields)]
+pub struct SshConfig {
+    /// User to log in as.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Port of the ssh server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 1))]
+    pub port: Option<u16>,
+    /// Private key to log in with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<PathBuf>,
+    /// Jump host, passed to `ssh -J`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_jump: Option<String>,
+    /// Additional arguments for ssh, e.g. `["-o", "StrictHostKeyChecking=accept-new"]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_args: Vec<String>,
+}
+
+impl SshConfig {
+    pub fn is_empty(&self) -> bool {
+        self.args().is_empty()
+    }
+
+    /// Arguments to pass to ssh before the host name.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(user) = &self.user {
+            args.push("-l".to_string());
+            args.push(user.clone());
+        }
+        if let Some(port) = self.port {
+            args.push("-p".to_string());
+            args.push(port.to_string());
+        }
+        if let Some(identity_file) = &self.identity_file {
+            args.push("-i".to_string());
+            args.push(identity_file.display().to_string());
+        }
+        if let Some(proxy_jump) = &self.proxy_jump {
+            args.push("-J".to_string());
+            args.push(proxy_jump.clone());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
+pub struct RsyncConfig {
+    /// Patterns that are never pushed, passed to `rsync --exclude`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Delete files on the server that are not pushed.
+    #[serde(default)]
+    pub delete: bool,
+    /// Paths on the server that `delete` leaves alone.
+    #[serde(default)]
+    pub protect: Vec<String>,
+    /// Bandwidth limit, e.g. `5m` for 5 MB/s.
+    pub bwlimit: Option<String>,
+    /// Compress file data during the transfer.
+    #[serde(default)]
+    pub compress: bool,
+}
+
+impl RsyncConfig {
+    pub fn is_default(&self) -> bool {
+        *self == RsyncConfig::default()
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[schemars(deny_unknown_fields)]
+pub struct ComposeUpConfig {
+    /// Wait for the services to be running or healthy, passed as `--wait`.
+    #[serde(default)]
+    pub wait: bool,
+    /// Remove containers of services that are no longer in the compose file.
+    #[serde(default)]
+    pub remove_orphans: bool,
+    /// Recreate containers even if nothing changed.
+    #[serde(default)]
+    pub force_recreate: bool,
+    /// Don't recreate containers that already exist.
+    #[serde(default)]
+    pub no_recreate: bool,
+    /// Seconds to wait for containers to stop, passed as `--timeout`.
+    pub timeout: Option<u64>,
+    /// Additional arguments for `docker compose up -d`.
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+impl ComposeUpConfig {
+    pub fn is_default(&self) -> bool {
+        *self == ComposeUpConfig::default()
+    }
+
+    /// Arguments to pass to `docker compose up -d`.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        let flags = [
+            (self.wait, "--wait"),
+            (self.remove_orphans, "--remove-orphans"),
+            (self.force_recreate, "--force-recreate"),
+            (self.no_recreate, "--no-recreate"),
+        ];
+        for (enabled, flag) in flags {
+            if enabled {
+                args.push(flag.to_string());
+            }
+        }
+        if let Some(timeout) = self.timeout {
+            args.push(format!("--timeout={}", timeout));
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct PreflightConfig {
+    /// Check the servers before building and pushing.
+    pub enabled: bool,
+    /// Minimum docker version on the server.
+    pub min_docker_version: String,
+    /// Minimum compose version on the server.
+    pub min_compose_version: String,
+    /// Free space needed in the remote directory, e.g. `500M` or `2G`.
+    #[schemars(regex(pattern = r"^[0-9]+[KMGTkmgt]?[Bb]?$"))]
+    pub min_free_space: String,
+    /// Create the remote directory when it does not exist, instead of
+    /// failing.
+    pub create_remote_path: bool,
+}
+
+impl Default for PreflightConfig {
+    fn default() -> Self {
+        PreflightConfig {
+            enabled: true,
+            min_docker_version: "20.10".to_string(),
+            min_compose_version: "2.0".to_string(),
+            min_free_space: "1G".to_string(),
+            create_remote_path: true,
+        }
+    }
+}
+
+impl PreflightConfig {
+    pub fn is_default(&self) -> bool {
+        *self == PreflightConfig::default()
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// How often a command is tried, including the first time.
+    #[schemars(range(min = 1))]
+    pub attempts: u32,
+    /// Seconds before the first retry, doubled after every retry.
+    pub delay: u64,
+    /// The most seconds between two retries.
+    pub max_delay: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: 3,
+            delay: 2,
+            max_delay: 60,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn is_default(&self) -> bool {
+        *self == RetryConfig::default()
+    }
+}
+
+/// Seconds that commands may run before they are killed, where 0 is no
+/// limit.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Commands on the server over ssh.
+    pub ssh: u64,
+    /// Pushing files with rsync.
+    pub rsync: u64,
+    /// Pushing an image to the registry.
+    pub push: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            ssh: 3600,
+            rsync: 3600,
+            push: 3600,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// No limits, for commands that run as long as the user wants.
+    pub fn none() -> Self {
+        TimeoutConfig {
+            ssh: 0,
+            rsync: 0,
+            push: 0,
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == TimeoutConfig::default()
+    }
+}
+
+/// Creates Sentry releases and deploy markers with sentry-cli, which reads
+/// the token from `SENTRY_AUTH_TOKEN`.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(deny_unknown_fields)]
+pub struct SentryConfig {
+    /// Sentry organization of the release.
+    pub org: String,
+    /// Sentry project of the release.
+    pub project: String,
+    /// Sentry environment of the deploy markers.
+    #[serde(default = "default_sentry_environment")]
+    pub environment: String,
+    /// URL of a self-hosted Sentry. Defaults to https://sentry.io.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+fn default_sentry_environment() -> String {
+    "production".to_string()
+}
+
+/// Parses a size like `500M` or `2G` into bytes. Units are powers of 1024.
+pub fn parse_size(text: &str) -> Result<u64> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, ""),
+    };
+    let exponent = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => bail!(
+            "Invalid size {:?}. Use a number with K, M, G or T, e.g. 2G",
+            text
+        ),
+    };
+    let number: u64 = number.parse().context(format!(
+        "Invalid size {:?}. Use a number with K, M, G or T, e.g. 2G",
+        text
+    ))?;
+    Ok(number * 1024u64.pow(exponent))
+}
+
+/// The numbers of a version like `24.0.7` or `v2.21.0-desktop.1`.
+pub fn parse_version(text: &str) -> Option<Vec<u64>> {
+    let text = text.trim().trim_start_matches('v');
+    let text = text.split(['-', '+', ' ']).next()?;
+    text.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Either a username with the password in an environment variable, or a
+/// docker credential helper such as `ecr-login` or `pass`.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(deny_unknown_fields)]
+pub struct RegistryAuth {
+    /// User to log in to the registry as.
+    pub username: Option<String>,
+    /// Environment variable holding the password.
+    pub password_env: Option<String>,
+    /// Name of a `docker-credential-<name>` helper.
+    pub credential_helper: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    /// Push images to the registry and pull them on the server.
+    #[default]
+    Registry,
+    /// Stream images with `docker save | ssh server docker load`.
+    Ssh,
+    /// Like `ssh`, but compressed with zstd.
+    SshZstd,
+}
+
+impl Transport {
+    pub fn is_registry(&self) -> bool {
+        *self == Transport::Registry
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeployMode {
+    /// Push the files to the server with rsync and run docker compose there
+    /// over ssh.
+    #[default]
+    Ssh,
+    /// Run docker compose locally against the docker daemon of the server,
+    /// so the compose file never leaves this machine.
+    DockerContext,
+}
+
+impl DeployMode {
+    pub fn is_ssh(&self) -> bool {
+        *self == DeployMode::Ssh
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildOn {
+    #[default]
+    Local,
+    /// Copy the build contexts to the server with rsync and build the images
+    /// there, so they never have to be uploaded.
+    Remote,
+}
+
+impl BuildOn {
+    pub fn is_local(&self) -> bool {
+        *self == BuildOn::Local
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Engine {
+    #[default]
+    Docker,
+    /// Build with `podman build` and push with `podman push`.
+    Podman,
+}
+
+impl Engine {
+    pub fn is_docker(&self) -> bool {
+        *self == Engine::Docker
+    }
+
+    pub fn binary(&self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Recreate the changed containers with `docker compose up -d`.
+    #[default]
+    Recreate,
+    /// Start the new version next to the old one as a separate compose
+    /// project, and stop the old one once traffic is switched over.
+    BlueGreen,
+}
+
+impl Strategy {
+    pub fn is_recreate(&self) -> bool {
+        *self == Strategy::Recreate
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct Migration {
+    /// Service whose image and configuration the command runs with.
+    pub service: String,
+    /// Command to run, like the arguments of `docker compose run`.
+    pub command: String,
+}
+
+/// A check that the deployed version works, which is retried until it
+/// passes or its timeout runs out.
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(untagged)]
+#[schemars(deny_unknown_fields)]
+pub enum SmokeTest {
+    /// Requests the url from this machine and expects the status.
+    Http {
+        /// URL to request with curl.
+        url: String,
+        /// Expected HTTP status. Defaults to 200.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<u16>,
+        /// Seconds to retry for. Defaults to 30.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+    },
+    /// Runs the command in the remote project directory and expects it to
+    /// succeed.
+    Command {
+        /// Command to run in the remote project directory.
+        command: String,
+        /// Seconds to retry for. Defaults to 30.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+    },
+}
+
+impl SmokeTest {
+    pub fn timeout(&self) -> u64 {
+        let timeout = match self {
+            SmokeTest::Http { timeout, .. } | SmokeTest::Command { timeout, .. } => timeout,
+        };
+        timeout.unwrap_or(30)
+    }
+}
+
+impl std::fmt::Display for SmokeTest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SmokeTest::Http { url, .. } => write!(f, "{}", url),
+            SmokeTest::Command { command, .. } => write!(f, "`{}`", command),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
+#[schemars(deny_unknown_fields)]
+pub struct Rolling {
+    /// Seconds to wait after a server is deployed before deploying the next.
+    #[serde(default)]
+    pub delay: u64,
+}
+
+/// A server in the inventory, either just its name or with settings that
+/// override the top level ones for that server.
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(untagged)]
+pub enum InventoryHost {
+    Name(String),
+    Advanced(InventoryHostAdvanced),
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct InventoryHostAdvanced {
+    /// Host name of the server, optionally with a user.
+    pub host: String,
+    /// ssh options for this server instead of the top level `ssh`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh: Option<SshConfig>,
+    /// Directory on this server instead of the top level `remote_path`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_path: Option<String>,
+}
+
+impl InventoryHost {
+    pub fn host(&self) -> &str {
+        match self {
+            InventoryHost::Name(host) => host,
+            InventoryHost::Advanced(adv) => &adv.host,
+        }
+    }
+}
+
+/// A bash script that runs locally, or over ssh in the remote project
+/// directory.
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(untagged)]
+pub enum Hook {
+    Script(String),
+    Advanced(HookAdvanced),
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct HookAdvanced {
+    /// Bash script to run.
+    pub script: String,
+    /// Run the script over ssh in the remote project directory.
+    #[serde(default)]
+    pub remote: bool,
+}
+
+impl Hook {
+    pub fn script(&self) -> &str {
+        match self {
+            Hook::Script(script) => script,
+            Hook::Advanced(adv) => &adv.script,
+        }
+    }
+
+    pub fn is_remote(&self) -> bool {
+        match self {
+            Hook::Script(_) => false,
+            Hook::Advanced(adv) => adv.remote,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(untagged)]
+pub enum Secret {
+    Path(PathBuf),
+    Advanced(SecretAdvanced),
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct SecretAdvanced {
+    /// Encrypted file to decrypt.
+    pub path: PathBuf,
+    /// Name of the decrypted file on the server.
+    pub output: Option<String>,
+    /// Tool that decrypts the file. Defaults to age for `.age` files and
+    /// sops for everything else.
+    pub tool: Option<SecretTool>,
+    /// age identity file. Defaults to `$DEP_AGE_IDENTITY`.
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretTool {
+    Sops,
+    Age,
+}
+
+impl Secret {
+    pub fn path(&self) -> &Path {
+        match self {
+            Secret::Path(path) => path,
+            Secret::Advanced(adv) => &adv.path,
+        }
+    }
+
+    /// The tool used to decrypt the file. Files ending with `.age` use age,
+    /// everything else uses sops.
+    pub fn tool(&self) -> SecretTool {
+        if let Secret::Advanced(SecretAdvanced {
+            tool: Some(tool), ..
+        }) = self
+        {
+            return *tool;
+        }
+        match self.path().extension().and_then(|e| e.to_str()) {
+            Some("age") => SecretTool::Age,
+            _ => SecretTool::Sops,
+        }
+    }
+
+    /// Name of the decrypted file. Defaults to the file name without its
+    /// encryption marker, so `.env.enc` becomes `.env` and
+    /// `secrets.sops.yaml` becomes `secrets.yaml`.
+    pub fn output_name(&self) -> Result<String> {
+        if let Secret::Advanced(SecretAdvanced {
+            output: Some(output),
+            ..
+        }) = self
+        {
+            return Ok(output.clone());
+        }
+        let Some(name) = self.path().file_name().and_then(|n| n.to_str()) else {
+            bail!("Invalid secret path {}", self.path().display());
+        };
+        for suffix in [".age", ".enc", ".sops"] {
+            if let Some(stripped) = name.strip_suffix(suffix) {
+                return Ok(stripped.to_string());
+            }
+        }
+        for infix in [".enc.", ".sops."] {
+            if name.contains(infix) {
+                return Ok(name.replacen(infix, ".", 1));
+            }
+        }
+        Ok(name.to_string())
+    }
+
+    /// Command that prints the decrypted file to stdout.
+    pub fn decrypt_command(&self) -> Result<Command> {
+        let mut command;
+        match self.tool() {
+            SecretTool::Sops => {
+                command = Command::new("sops");
+                command.arg("--decrypt");
+            }
+            SecretTool::Age => {
+                let identity = match self {
+                    Secret::Advanced(SecretAdvanced {
+                        identity: Some(identity),
+                        ..
+                    }) => identity.clone(),
+                    _ => match std::env::var_os("DEP_AGE_IDENTITY") {
+                        Some(identity) => PathBuf::from(identity),
+                        None => bail!(
+                            "No age identity for {}. Set `identity` or DEP_AGE_IDENTITY",
+                            self.path().display()
+                        ),
+                    },
+                };
+                command = Command::new("age");
+                command.arg("--decrypt").arg("--identity").arg(identity);
+            }
+        }
+        command.arg(self.path());
+        Ok(command)
+    }
+}
+
+/// A BuildKit secret read from a file or an environment variable, in the
+/// same format as the top level `secrets` of a compose file.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[schemars(deny_unknown_fields)]
+pub struct BuildSecret {
+    /// File with the secret.
+    pub file: Option<PathBuf>,
+    /// Environment variable with the secret.
+    pub environment: Option<String>,
+}
+
+impl BuildSecret {
+    /// The value for `docker build --secret`.
+    pub fn arg(&self, id: &str) -> Result<String> {
+        match (&self.file, &self.environment) {
+            (Some(file), None) => Ok(format!("id={},src={}", id, file.display())),
+            (None, Some(env)) => Ok(format!("id={},env={}", id, env)),
+            _ => bail!("Build secret {} needs either `file` or `environment`", id),
+        }
+    }
+}
+
+/// The answers to the questions of `dep init` that were given on the command
+/// line.
+#[derive(Debug, Default)]
+pub struct InitAnswers {
+    /// The deployment config that is created.
+    pub config_path: PathBuf,
+    pub name: Option<String>,
+    pub server: Option<String>,
+    pub registry: Option<String>,
+    /// Use the defaults for the other answers instead of asking, and fail
+    /// when there is no default.
+    pub non_interactive: bool,
+}
+
+impl InitAnswers {
+    fn name(&self, default: Option<String>) -> Result<String> {
+        let question = "What the name of this program?";
+        self.answer(&self.name, question, default, "--name", &validate_name)
+    }
+
+    fn server(&self, default: Option<String>) -> Result<String> {
+        let question = "What is the name of the server?";
+        let validate = |server: &str| validate_server(server, &self.config_path);
+        self.answer(&self.server, question, default, "--server", &validate)
+    }
+
+    fn registry(&self, default: Option<String>) -> Result<String> {
+        let question = "What is URL of the docker registry?";
+        self.answer(
+            &self.registry,
+            question,
+            default,
+            "--registry",
+            &validate_registry,
+        )
+    }
+
+    fn answer(
+        &self,
+        given: &Option<String>,
+        question: &str,
+        default: Option<String>,
+        flag: &str,
+        validate: &dyn Fn(&str) -> Result<()>,
+    ) -> Result<String> {
+        if let Some(given) = given {
+            validate(given).context(format!("Invalid {}", flag))?;
+            return Ok(given.clone());
+        }
+        if !self.non_interactive {
+            return ask(question, default, validate);
+        }
+        let default = default.context(format!("Pass {} with --non-interactive", flag))?;
+        validate(&default).context(format!("Pass {} with --non-interactive", flag))?;
+        Ok(default)
+    }
 }
 
 impl DepConfig {
-    pub fn create_interactive() -> Self {
+    /// Asks for the settings of a new project. When there is a
+    /// docker-compose.yaml, its name, a registry guessed from its images and
+    /// the files its services mount or read with `env_file` are the
+    /// defaults. The `answers` given on the command line are not asked for.
+    pub fn create_interactive(answers: &InitAnswers) -> Result<Self> {
         let current_directory_default: Option<String> = match std::env::current_dir() {
             Ok(pathbuf) => pathbuf
                 .file_name()
@@ -22,39 +987,688 @@ impl DepConfig {
                 .map(|s| s.to_string()),
             Err(_) => None,
         };
+        let compose = crate::compose::read(&[PathBuf::from(crate::DOCKER_COMPOSE_PATH)]).ok();
+        let compose_name = compose
+            .as_ref()
+            .and_then(|c| c.get("name")?.as_str().map(|s| s.to_string()));
+        let registry = compose.as_ref().and_then(import::guess_registry);
+        let additional_files = compose
+            .as_ref()
+            .map(import::used_paths)
+            .unwrap_or_default()
+            .into_iter()
+            .map(PathBuf::from)
+            .filter(|path| path.exists())
+            .collect();
 
-        Self {
-            name: ask("What the name of this program?", current_directory_default),
-            server: ask("What is the name of the server?", None),
-            registry: ask("What is URL of the docker registry?", None),
-            additional_files: Some(vec![]),
+        let name = answers.name(compose_name.or(current_directory_default))?;
+        let server = answers.server(None)?;
+        let (user, server) = match server.split_once('@') {
+            Some((user, server)) => (Some(user.to_string()), server.to_string()),
+            None => (None, server),
+        };
+        Ok(Self {
+            name,
+            server,
+            registry: answers.registry(registry)?,
+            ssh: SshConfig {
+                user,
+                ..Default::default()
+            },
+            additional_files: Some(additional_files),
             build: Some("".to_string()),
+            ..Default::default()
+        })
+    }
+
+    /// Asks for the settings of a new project from `template`. The deploys
+    /// wait for the healthchecks of the template, and roll back when they
+    /// fail.
+    pub fn create_templated(template: Template, answers: &InitAnswers) -> Result<Self> {
+        let directory = std::env::current_dir()?;
+        let directory = directory.file_name().and_then(|n| n.to_str());
+        let name = answers.name(directory.map(|d| d.to_string()))?;
+        let server = answers.server(None)?;
+        let (user, server) = match server.split_once('@') {
+            Some((user, server)) => (Some(user.to_string()), server.to_string()),
+            None => (None, server),
+        };
+        Ok(Self {
+            name,
+            server,
+            registry: answers.registry(None)?,
+            ssh: SshConfig {
+                user,
+                ..Default::default()
+            },
+            additional_files: Some(template.additional_files()),
+            build: Some("".to_string()),
+            healthcheck_timeout: Some(120),
+            rollback_on_failure: true,
+            ..Default::default()
+        })
+    }
+
+    /// Asks for the settings of a project imported with `dep init
+    /// --from-remote`, with the guesses from the server as defaults.
+    pub fn create_imported(project: &RemoteProject, answers: &InitAnswers) -> Result<Self> {
+        let name = answers.name(Some(project.name()))?;
+        let registry = answers.registry(project.registry())?;
+        let server = Some(project.server.clone());
+        let question = "What is the name of the server?";
+        let validate = |server: &str| validate_server(server, &answers.config_path);
+        let server = answers.answer(&server, question, None, "--from-remote", &validate)?;
+        let (user, server) = match server.split_once('@') {
+            Some((user, server)) => (Some(user.to_string()), server.to_string()),
+            None => (None, server),
+        };
+        // Projects are deployed to ~/<name> by default.
+        let path = project.path.strip_prefix("~/").unwrap_or(&project.path);
+        Ok(Self {
+            remote_path: (path != name).then(|| project.path.clone()),
+            name,
+            server,
+            registry,
+            ssh: SshConfig {
+                user,
+                ..Default::default()
+            },
+            additional_files: Some(project.files.iter().map(PathBuf::from).collect()),
+            build: Some("".to_string()),
+            ..Default::default()
+        })
+    }
+
+    /// Reads a deployment.yaml, with the environment variables in it
+    /// replaced.
+    pub fn read(path: &Path) -> Result<DepConfig> {
+        let text = std::fs::read_to_string(path)
+            .context(format!("Failed to open config file: {}", path.display()))?;
+        let parsed = serde_yaml::from_str(&text)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut value| match interpolate_config(&mut value)? {
+                // Parsing the text keeps the line numbers in errors.
+                false => parse(&text),
+                true => from_value(value),
+            });
+        let mut config =
+            parsed.context(format!("Failed to parse config file: {}", path.display()))?;
+        config.path = path.to_path_buf();
+        Ok(config)
+    }
+
+    /// The file the config was read from, or deployment.yaml for a config
+    /// that was not read from a file.
+    pub fn path(&self) -> &Path {
+        match self.path.as_os_str().is_empty() {
+            true => Path::new(crate::DEP_CONFIG_PATH),
+            false => &self.path,
+        }
+    }
+
+    /// Checks the settings that depend on each other, after environments and
+    /// command line options are applied. Settings that imply others are
+    /// filled in.
+    pub fn validate(&mut self) -> Result<()> {
+        // Commands that run on a single server use the first of the servers.
+        if self.server.is_empty() {
+            self.server = self
+                .servers
+                .first()
+                .context(format!(
+                    "{} must set server or servers",
+                    self.path().display()
+                ))?
+                .clone();
+        }
+        if let Some(project) = &self.compose_project {
+            let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_".contains(c);
+            if !project.starts_with(|c: char| c.is_ascii_alphanumeric())
+                || !project.chars().all(valid)
+            {
+                bail!(
+                    "Invalid compose_project {}. It must be lowercase letters, digits, dashes and underscores",
+                    project
+                );
+            }
+        }
+        if self.deploy_mode == DeployMode::DockerContext {
+            let unsupported = [
+                ("releases", self.releases),
+                ("strategy: blue-green", !self.strategy.is_recreate()),
+                ("secrets", !self.secrets.is_empty()),
+                (
+                    "additionalFiles",
+                    self.additional_files.iter().flatten().next().is_some(),
+                ),
+            ];
+            for (setting, used) in unsupported {
+                if used {
+                    bail!(
+                        "{} needs files on the server, which deploy_mode: docker-context does not push",
+                        setting
+                    );
+                }
+            }
+        }
+        let preflight = &self.preflight;
+        for (key, version) in [
+            ("min_docker_version", &preflight.min_docker_version),
+            ("min_compose_version", &preflight.min_compose_version),
+        ] {
+            if parse_version(version).is_none() {
+                bail!("Invalid preflight.{} {:?}, e.g. 2.20", key, version);
+            }
+        }
+        parse_size(&preflight.min_free_space).context("Invalid preflight.min_free_space")?;
+        if self.retry.attempts == 0 {
+            bail!("retry.attempts must be at least 1");
+        }
+        if !self.build_on.is_local() {
+            if !self.engine.is_docker() {
+                bail!("engine only applies to local builds, images are built with docker with build_on: remote");
+            }
+            // The images only exist on the server they are built on.
+            self.transport = Transport::Ssh;
+        }
+        Ok(())
+    }
+
+    /// The directory on the server that the project is deployed to.
+    pub fn remote_path(&self) -> &str {
+        let host = self.inventory_host().and_then(|h| h.remote_path.as_deref());
+        host.or(self.remote_path.as_deref()).unwrap_or(&self.name)
+    }
+
+    /// The ssh options for the server.
+    pub fn ssh(&self) -> &SshConfig {
+        let host = self.inventory_host().and_then(|h| h.ssh.as_ref());
+        host.unwrap_or(&self.ssh)
+    }
+
+    /// The inventory settings of the server, if it has any.
+    fn inventory_host(&self) -> Option<&InventoryHostAdvanced> {
+        let mut hosts = self.inventory.values().flatten();
+        hosts.find_map(|host| match host {
+            InventoryHost::Advanced(adv) if adv.host == self.server => Some(adv),
+            _ => None,
+        })
+    }
+
+    /// Adds the servers of all inventory groups to `servers`. When `groups`
+    /// is not empty, only the servers of those groups are deployed to.
+    pub fn select_groups(&mut self, groups: &[String]) -> Result<()> {
+        for group in groups {
+            if !self.inventory.contains_key(group) {
+                let available: Vec<_> = self.inventory.keys().cloned().collect();
+                bail!(
+                    "Unknown group {}. Available groups: [{}]",
+                    group,
+                    available.join(", ")
+                );
+            }
+        }
+        if !groups.is_empty() {
+            self.server.clear();
+            self.servers.clear();
+        }
+        let selected = self
+            .inventory
+            .iter()
+            .filter(|(name, _)| groups.is_empty() || groups.contains(name));
+        for host in selected.flat_map(|(_, hosts)| hosts) {
+            if !self.servers.iter().any(|s| s == host.host()) {
+                self.servers.push(host.host().to_string());
+            }
+        }
+        // The canary may be in a group that is not selected.
+        if self
+            .canary
+            .as_ref()
+            .is_some_and(|c| !self.servers.contains(c))
+        {
+            self.canary = None;
+        }
+        Ok(())
+    }
+
+    /// Overrides the top level settings with the ones from the given environment.
+    pub fn apply_environment(&mut self, environment: &str) -> Result<()> {
+        let Some(env) = self.environments.remove(environment) else {
+            let available: Vec<_> = self.environments.keys().cloned().collect();
+            bail!(
+                "Unknown environment {}. Available environments: [{}]",
+                environment,
+                available.join(", ")
+            );
+        };
+        if let Some(name) = env.name {
+            self.name = name;
+        }
+        // An environment with a single server doesn't deploy to the servers
+        // of the top level, and the other way around.
+        if let Some(servers) = env.servers {
+            self.server.clear();
+            self.servers = servers;
+        } else if env.server.is_some() {
+            self.servers.clear();
+        }
+        if let Some(server) = env.server {
+            self.server = server;
+        }
+        if let Some(canary) = env.canary {
+            self.canary = Some(canary);
+        }
+        if let Some(inventory) = env.inventory {
+            self.inventory = inventory;
+        }
+        if let Some(registry) = env.registry {
+            self.registry = registry;
+        }
+        if let Some(additional_files) = env.additional_files {
+            self.additional_files = Some(additional_files);
         }
+        if let Some(compose_files) = env.compose_files {
+            self.compose_files = compose_files;
+        }
+        if let Some(secrets) = env.secrets {
+            self.secrets = secrets;
+        }
+        if let Some(registry_auth) = env.registry_auth {
+            self.registry_auth = Some(registry_auth);
+        }
+        if let Some(ssh) = env.ssh {
+            self.ssh = ssh;
+        }
+        if let Some(remote_path) = env.remote_path {
+            self.remote_path = Some(remote_path);
+        }
+        if let Some(compose_project) = env.compose_project {
+            self.compose_project = Some(compose_project);
+        }
+        if let Some(protected) = env.protected {
+            self.protected = protected;
+        }
+        if let Some(require_clean) = env.require_clean {
+            self.require_clean = require_clean;
+        }
+        if let Some(allowed_branches) = env.allowed_branches {
+            self.allowed_branches = allowed_branches;
+        }
+        if let Some(require_tag) = env.require_tag {
+            self.require_tag = require_tag;
+        }
+        if let Some(tag_deploys) = env.tag_deploys {
+            self.tag_deploys = Some(tag_deploys);
+        }
+        if let Some(sentry) = env.sentry {
+            self.sentry = Some(sentry);
+        }
+        if let Some(notifications) = env.notifications {
+            self.notifications = notifications;
+        }
+        Ok(())
+    }
+}
+
+/// Parses deployment.yaml. `server` may also be a list of servers, which is
+/// the same as setting `servers`.
+pub fn parse(text: &str) -> Result<DepConfig> {
+    let mut value: serde_yaml::Value = serde_yaml::from_str(text)?;
+    // Parsing the text keeps the line numbers in errors.
+    match server_lists(&mut value) {
+        true => Ok(serde_yaml::from_value(value)?),
+        false => Ok(serde_yaml::from_str(text)?),
+    }
+}
+
+/// Parses deployment.yaml that was already read as YAML. Errors have the
+/// path of the wrong value, but no line numbers.
+pub fn from_value(mut value: serde_yaml::Value) -> Result<DepConfig> {
+    server_lists(&mut value);
+    Ok(serde_yaml::from_value(value)?)
+}
+
+/// Moves the lists in `server` to `servers`, also in the environments, and
+/// returns whether any were moved.
+fn server_lists(value: &mut serde_yaml::Value) -> bool {
+    let mut moved = server_list(value);
+    if let Some(environments) = value
+        .get_mut("environments")
+        .and_then(|e| e.as_mapping_mut())
+    {
+        for (_, environment) in environments.iter_mut() {
+            moved |= server_list(environment);
+        }
+    }
+    moved
+}
+
+/// Moves a list in `server` to `servers`, and returns whether it did.
+fn server_list(value: &mut serde_yaml::Value) -> bool {
+    let Some(mapping) = value.as_mapping_mut() else {
+        return false;
+    };
+    match mapping.remove("server") {
+        Some(servers) if servers.is_sequence() => {
+            mapping.insert("servers".into(), servers);
+            true
+        }
+        Some(server) => {
+            mapping.insert("server".into(), server);
+            false
+        }
+        None => false,
+    }
+}
+
+/// Top level keys whose values can use environment variables. Scripts and
+/// commands, such as `build` and the hooks, are passed to the shell untouched.
+pub const INTERPOLATED_KEYS: &[&str] = &[
+    "name",
+    "server",
+    "servers",
+    "canary",
+    "inventory",
+    "registry",
+    "remote_path",
+    "compose_project",
+    "docker_context",
+    "ssh",
+    "engine_binary",
+    "additionalFiles",
+    "compose_files",
+    "labels",
+    "registry_auth",
+    "notifications",
+    "sentry",
+];
+
+/// Replaces the environment variables in the values of the
+/// [INTERPOLATED_KEYS], at the top level and in the environments, and
+/// returns whether any were replaced.
+pub fn interpolate_config(value: &mut serde_yaml::Value) -> Result<bool> {
+    let mut changed = interpolate_keys(value, "")?;
+    if let Some(environments) = value
+        .get_mut("environments")
+        .and_then(|e| e.as_mapping_mut())
+    {
+        for (name, environment) in environments.iter_mut() {
+            let prefix = format!("environments.{}.", name.as_str().unwrap_or_default());
+            changed |= interpolate_keys(environment, &prefix)?;
+        }
+    }
+    Ok(changed)
+}
+
+fn interpolate_keys(value: &mut serde_yaml::Value, prefix: &str) -> Result<bool> {
+    let mut changed = false;
+    let Some(mapping) = value.as_mapping_mut() else {
+        return Ok(false);
+    };
+    for key in INTERPOLATED_KEYS {
+        if let Some(value) = mapping.get_mut(*key) {
+            changed |= interpolate_value(value, &format!("{}{}", prefix, key))?;
+        }
+    }
+    Ok(changed)
+}
+
+/// Replaces the environment variables in the strings in `value`, which is
+/// at `path` in deployment.yaml.
+fn interpolate_value(value: &mut serde_yaml::Value, path: &str) -> Result<bool> {
+    use serde_yaml::Value;
+    let mut changed = false;
+    match value {
+        Value::String(text) if text.contains('$') => {
+            let interpolated = interpolate(text).context(format!("Invalid {}", path))?;
+            // Numbers such as `ssh.port: ${SSH_PORT}` stay numbers.
+            *value = match interpolated.parse::<u64>() {
+                Ok(number) if path.ends_with(".port") => number.into(),
+                _ => Value::String(interpolated),
+            };
+            changed = true;
+        }
+        Value::Sequence(values) => {
+            for value in values.iter_mut() {
+                changed |= interpolate_value(value, path)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                let path = format!("{}.{}", path, key.as_str().unwrap_or_default());
+                changed |= interpolate_value(value, &path)?;
+            }
+        }
+        _ => {}
     }
+    Ok(changed)
 }
 
-fn ask(question: &str, default: Option<String>) -> String {
-    print!("{question} ");
-    if let Some(default) = &default {
-        print!("({}): ", default);
+/// Replaces `${VAR}` and `${VAR:-default}` with values from the environment,
+/// like docker compose does. `$$` is a literal `$`.
+pub fn interpolate(text: &str) -> Result<String> {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            output.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let Some(end) = after.find('}') else {
+                bail!("Unterminated ${{ in {:?}", text);
+            };
+            let expression = &after[..end];
+            let value = match expression.split_once(":-") {
+                Some((name, default)) => match std::env::var(name) {
+                    Ok(value) if !value.is_empty() => value,
+                    _ => default.to_string(),
+                },
+                None => match std::env::var(expression) {
+                    Ok(value) => value,
+                    Err(_) => bail!("Environment variable {} is not set", expression),
+                },
+            };
+            output.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = &rest[1..];
+        }
     }
+    output.push_str(rest);
+    Ok(output)
+}
 
-    std::io::stdout().flush().unwrap();
+/// Asks `question` until the answer, or the `default` for an empty answer,
+/// passes `validate`.
+fn ask(
+    question: &str,
+    default: Option<String>,
+    validate: &dyn Fn(&str) -> Result<()>,
+) -> Result<String> {
     let stdin = io::stdin();
-    let mut buf = String::new();
     loop {
-        match stdin.read_line(&mut buf) {
-            Err(e) => {
-                println!("\x1b[31merror\x1b[0m: {}", e);
-                continue;
+        print!("{question} ");
+        if let Some(default) = &default {
+            print!("({}): ", default);
+        }
+        std::io::stdout().flush()?;
+        let mut buf = String::new();
+        if stdin.read_line(&mut buf)? == 0 {
+            bail!("No answer to {:?}", question);
+        }
+        let answer = match (buf.trim(), &default) {
+            ("", Some(default)) => default.clone(),
+            ("", None) => continue,
+            (answer, _) => answer.to_string(),
+        };
+        match validate(&answer) {
+            Ok(()) => return Ok(answer),
+            Err(e) => println!("\x1b[31merror\x1b[0m: {:#}", e),
+        }
+    }
+}
+
+/// Checks that a project name can be used as a directory, in image names and
+/// as a compose project name.
+fn validate_name(name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    if !name.starts_with(valid)
+        || !name.ends_with(valid)
+        || !name.chars().all(|c| valid(c) || "._-".contains(c))
+    {
+        bail!(
+            "Invalid name {:?}. Names may contain lowercase letters, digits, ., _ and -, and must start and end with a letter or digit",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Checks that a server is `[user@]host`, as ssh takes it. The port is set
+/// in `config` instead.
+fn validate_server(server: &str, config: &Path) -> Result<()> {
+    if server.contains("://") {
+        bail!(
+            "Invalid server {:?}. Leave out the scheme, e.g. deploy@example.org",
+            server
+        );
+    }
+    let host = match server.split_once('@') {
+        Some((user, host)) => {
+            let valid = |c: char| c.is_ascii_alphanumeric() || "._-".contains(c);
+            if user.is_empty() || !user.chars().all(valid) {
+                bail!("Invalid user {:?} in server {:?}", user, server);
             }
-            Ok(_) => (),
+            host
         }
-        buf = buf.trim().to_string();
-        if !buf.is_empty() {
-            return buf;
-        } else if let Some(default) = default {
-            return default.clone();
+        None => server,
+    };
+    if host.matches(':').count() == 1 {
+        bail!(
+            "Invalid server {:?}. Set the port with ssh.port in {} instead",
+            server,
+            config.display()
+        );
+    }
+    // Host names, aliases from the ssh config and IPv6 addresses.
+    let valid = |c: char| c.is_ascii_alphanumeric() || ".-_:".contains(c);
+    if host.is_empty() || host.starts_with(['-', '.']) || !host.chars().all(valid) {
+        bail!(
+            "Invalid server {:?}. Use a host name or address, with an optional user, e.g. deploy@example.org",
+            server
+        );
+    }
+    Ok(())
+}
+
+/// Checks that a registry is `host[:port][/path]`, like
+/// `registry.example.org:5000/team`.
+fn validate_registry(registry: &str) -> Result<()> {
+    if registry.contains("://") {
+        bail!(
+            "Invalid registry {:?}. Leave out the scheme, e.g. registry.example.org",
+            registry
+        );
+    }
+    let (address, path) = match registry.split_once('/') {
+        Some((address, path)) => (address, Some(path)),
+        None => (registry, None),
+    };
+    let (host, port) = match address.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (address, None),
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || ".-".contains(c);
+    if host.is_empty() || host.starts_with(['-', '.']) || !host.chars().all(valid) {
+        bail!("Invalid host {:?} in registry {:?}", host, registry);
+    }
+    if let Some(port) = port {
+        if port.parse::<u16>().map_or(true, |p| p == 0) {
+            bail!("Invalid port {:?} in registry {:?}", port, registry);
         }
     }
+    for component in path.into_iter().flat_map(|p| p.split('/')) {
+        validate_name(component).context(format!("Invalid path in registry {:?}", registry))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_variables() {
+        std::env::set_var("DEP_TEST_HOST", "example.org");
+        assert_eq!(interpolate("${DEP_TEST_HOST}").unwrap(), "example.org");
+        assert_eq!(
+            interpolate("${DEP_TEST_UNSET:-fallback}").unwrap(),
+            "fallback"
+        );
+        assert_eq!(
+            interpolate("$${HOME} costs $5").unwrap(),
+            "${HOME} costs $5"
+        );
+        assert!(interpolate("${DEP_TEST_UNSET}").is_err());
+        assert!(interpolate("${DEP_TEST_HOST").is_err());
+    }
+
+    #[test]
+    fn interpolates_only_setting_values() {
+        std::env::set_var("DEP_TEST_SERVER", "deploy@example.org");
+        std::env::set_var("DEP_TEST_PORT", "2222");
+        let text = "# ${DEP_TEST_UNSET} in a comment
+name: shop
+server: ${DEP_TEST_SERVER}
+ssh:
+  port: ${DEP_TEST_PORT}
+pre_deploy: echo ${DEP_TEST_UNSET}
+environments:
+  staging:
+    server: staging-${DEP_TEST_SERVER}
+";
+        let mut value: serde_yaml::Value = serde_yaml::from_str(text).unwrap();
+        assert!(interpolate_config(&mut value).unwrap());
+        let config: DepConfig = serde_yaml::from_value(value.clone()).unwrap();
+        assert_eq!(config.server, "deploy@example.org");
+        assert_eq!(config.ssh.port, Some(2222));
+        assert_eq!(value["pre_deploy"], "echo ${DEP_TEST_UNSET}");
+        assert_eq!(
+            value["environments"]["staging"]["server"],
+            "staging-deploy@example.org"
+        );
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(validate_name("shop").is_ok());
+        assert!(validate_name("my-shop_2.0").is_ok());
+        assert!(validate_name("Shop").is_err());
+        assert!(validate_name("-shop").is_err());
+        assert!(validate_name("shop/web").is_err());
+    }
+
+    #[test]
+    fn validates_servers() {
+        assert!(validate_server("example.org", Path::new("deployment.yaml")).is_ok());
+        assert!(validate_server("deploy@10.0.0.1", Path::new("deployment.yaml")).is_ok());
+        assert!(validate_server("root@::1", Path::new("deployment.yaml")).is_ok());
+        assert!(validate_server("ssh://example.org", Path::new("deployment.yaml")).is_err());
+        assert!(validate_server("example.org:2222", Path::new("deployment.yaml")).is_err());
+        assert!(validate_server("@example.org", Path::new("deployment.yaml")).is_err());
+        assert!(validate_server("-oProxyCommand=x", Path::new("deployment.yaml")).is_err());
+    }
+
+    #[test]
+    fn validates_registries() {
+        assert!(validate_registry("registry.example.org").is_ok());
+        assert!(validate_registry("localhost:5000/team/shop").is_ok());
+        assert!(validate_registry("https://registry.example.org").is_err());
+        assert!(validate_registry("registry.example.org:0").is_err());
+        assert!(validate_registry("registry.example.org/Team").is_err());
+    }
 }
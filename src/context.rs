@@ -0,0 +1,4625 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+use serde_yaml::Value;
+use tracing::{error, info, warn};
+
+use crate::config::{
+    DepConfig, DeployMode, Engine, Hook, PreflightConfig, Secret, SentryConfig, SmokeTest,
+    Strategy, Transport,
+};
+use crate::dockerfile::DockerContainer;
+use crate::executor::{DryRun, Executor, System};
+use crate::exit::{self, Classify, Failure};
+use crate::history::{self, DeployRecord, HISTORY_DIR};
+use crate::lock::{self, DeployLock, LOCK_PATH};
+use crate::notify::DeployEvent;
+use crate::output;
+use crate::progress::{self, State};
+use crate::ps::{self, PsEntry};
+use crate::registry::{self, ImageRef};
+use crate::state::{BuildState, PushedImage};
+use crate::{buildhash, compose, depignore, git, interrupt, pin};
+use crate::{command_line, compose_files, header, header_elapsed, remote_shell_path, shell_quote};
+use crate::{
+    AUDIT_LOG_PATH, BUILD_DIR, COLOR_PATH, CONTEXT_DIR, CURRENT_RELEASE, DEPLOY_MANIFEST_PATH,
+    DOCKER_COMPOSE_PATH, RELEASES_DIR, REMOTE_STATE, ROLLING_HEALTHCHECK_TIMEOUT, VERSIONS_DIR,
+};
+
+/// Builds, pushes and deploys one version of a project.
+#[derive(Debug)]
+pub struct BuildContext {
+    registry: String,
+    /// The version that is built and deployed, used as the image tag.
+    pub version: String,
+    pub config: DepConfig,
+    /// Pull newer base images when building, and images when deploying.
+    pub pull: bool,
+    /// Number of images to build at the same time.
+    pub jobs: usize,
+    /// Only print the commands that would change something.
+    pub dry_run: bool,
+    containers: Vec<DockerContainer>,
+    /// Images to use for services that are not built in this run.
+    reused_images: BTreeMap<String, String>,
+    /// Build context hashes of the services that are built, saved to the
+    /// build state after pushing.
+    build_hashes: BTreeMap<String, String>,
+    /// Whether pushing reuses images whose build context is unchanged.
+    pub skip_unchanged: bool,
+    /// Tags that images get in addition to the version, such as `latest`.
+    pub extra_tags: Vec<String>,
+    /// Push images even if the registry already has the version.
+    pub force_push: bool,
+    /// Deploy even if the server is locked by another deploy.
+    pub force_unlock: bool,
+    /// Keep building the other services after a build fails.
+    pub keep_going: bool,
+    /// Show a dashboard with a pane per service while deploying.
+    pub ui: bool,
+    /// Images pinned to their registry digest, by service.
+    pinned_images: BTreeMap<String, String>,
+    /// The detected compose command of each server.
+    compose_commands: Mutex<BTreeMap<String, String>>,
+    /// How the last action went on each of the `servers`, for the audit log.
+    server_outcomes: BTreeMap<String, &'static str>,
+    /// Runs the commands, or prints them during dry runs.
+    executor: Arc<dyn Executor>,
+}
+
+impl BuildContext {
+    /// Creates a context for building and deploying `containers` at
+    /// `version`. `jobs` defaults to the `jobs` setting.
+    pub fn new(
+        version: String,
+        config: DepConfig,
+        pull: bool,
+        jobs: Option<usize>,
+        dry_run: bool,
+        containers: Vec<DockerContainer>,
+    ) -> Self {
+        let jobs = jobs.or(config.jobs).unwrap_or(1).max(1);
+        let timeouts = config.timeouts.clone();
+        BuildContext {
+            registry: config.registry.clone(),
+            version,
+            config,
+            pull,
+            jobs,
+            dry_run,
+            containers,
+            reused_images: BTreeMap::new(),
+            build_hashes: BTreeMap::new(),
+            skip_unchanged: false,
+            extra_tags: vec![],
+            force_push: false,
+            force_unlock: false,
+            keep_going: false,
+            ui: false,
+            pinned_images: BTreeMap::new(),
+            compose_commands: Mutex::new(BTreeMap::new()),
+            server_outcomes: BTreeMap::new(),
+            executor: match dry_run {
+                true => Arc::new(DryRun::new(timeouts)),
+                false => Arc::new(System::new(timeouts)),
+            },
+        }
+    }
+
+    /// Restricts building and pushing to the given services. When
+    /// `keep_deployed` is set, the other services keep the image that is
+    /// currently deployed on the server.
+    pub fn select_services(&mut self, services: &[String], keep_deployed: bool) -> Result<()> {
+        for service in services {
+            if !self.containers.iter().any(|c| &c.name == service) {
+                let names: Vec<_> = self.containers.iter().map(|c| c.name.as_str()).collect();
+                bail!(
+                    "Unknown service {}. Services with a build section: [{}]",
+                    service,
+                    names.join(", ")
+                );
+            }
+        }
+        let (selected, other): (Vec<_>, Vec<_>) = std::mem::take(&mut self.containers)
+            .into_iter()
+            .partition(|c| services.contains(&c.name));
+        self.containers = selected;
+        if keep_deployed && !other.is_empty() {
+            let deployed = self.deployed_images()?;
+            for container in other {
+                let image = deployed.get(&container.name).context(format!(
+                    "Service {} is not deployed to {} yet and must be included",
+                    container.name, self.config.server
+                ))?;
+                self.reused_images.insert(container.name, image.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Reuses the previously pushed image of services whose build context
+    /// hash is unchanged, instead of building them again.
+    fn reuse_unchanged(&mut self) -> Result<()> {
+        let state = BuildState::read()?;
+        for container in std::mem::take(&mut self.containers) {
+            let hash = self.build_hash(&container)?;
+            match state.images.get(&self.repository(&container)) {
+                Some(pushed) if pushed.hash == hash => {
+                    progress::set_state(&container.name, State::Unchanged);
+                    info!(
+                        "Skipping {}, unchanged since {}",
+                        container.name, pushed.image
+                    );
+                    self.reused_images
+                        .insert(container.name, pushed.image.clone());
+                }
+                _ => {
+                    self.build_hashes.insert(container.name.clone(), hash);
+                    self.containers.push(container);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hashes the build context and the settings that affect the image.
+    fn build_hash(&self, container: &DockerContainer) -> Result<String> {
+        let context = Path::new(&container.build_dir);
+        let dockerfile = match &container.dockerfile {
+            Some(file) => PathBuf::from(file),
+            None => context.join("Dockerfile"),
+        };
+        let settings = [
+            container.target.clone().unwrap_or_default(),
+            self.platforms(container).join(","),
+            build_args_hash(&container.args),
+        ];
+        buildhash::hash_build(context, &dockerfile, &settings).context(format!(
+            "Failed to hash the build context of {}",
+            container.name
+        ))
+    }
+
+    /// Remembers the build context hashes of the pushed images.
+    fn save_build_state(&self) -> Result<()> {
+        if self.build_hashes.is_empty() || self.dry_run {
+            return Ok(());
+        }
+        let mut state = BuildState::read()?;
+        for container in self.containers.iter() {
+            if let Some(hash) = self.build_hashes.get(&container.name) {
+                let pushed = PushedImage {
+                    hash: hash.clone(),
+                    image: self.image(container),
+                };
+                state.images.insert(self.repository(container), pushed);
+            }
+        }
+        state.write()
+    }
+
+    /// Reads the image of each service from the docker-compose.yaml that is
+    /// currently deployed on the server.
+    fn deployed_images(&self) -> Result<BTreeMap<String, String>> {
+        let text = match self.config.deploy_mode {
+            DeployMode::Ssh => self.ssh_output(&format!(
+                "cat {} 2>/dev/null; true",
+                self.remote_compose_path()
+            ))?,
+            DeployMode::DockerContext => {
+                std::fs::read_to_string(self.context_dir().join(DOCKER_COMPOSE_PATH))
+                    .unwrap_or_default()
+            }
+        };
+        let mut images = BTreeMap::new();
+        if text.trim().is_empty() {
+            return Ok(images);
+        }
+        let compose: Value = serde_yaml::from_str(&text)
+            .context(format!("Failed to parse deployed {}", DOCKER_COMPOSE_PATH))?;
+        if let Some(services) = compose.get("services").and_then(|s| s.as_mapping()) {
+            for (name, service) in services {
+                let name = name.as_str();
+                let image = service.get("image").and_then(|i| i.as_str());
+                if let (Some(name), Some(image)) = (name, image) {
+                    images.insert(name.to_string(), image.to_string());
+                }
+            }
+        }
+        Ok(images)
+    }
+
+    /// Runs commands through another [Executor], such as [Capture] for
+    /// tests.
+    ///
+    /// [Capture]: crate::executor::Capture
+    pub fn set_executor(&mut self, executor: Arc<dyn Executor>) {
+        self.executor = executor;
+    }
+
+    /// Runs a command, or only prints it when doing a dry run.
+    fn run(&self, command: &mut Command) -> Result<ExitStatus> {
+        self.executor.status(command)
+    }
+
+    /// Runs a command like [Self::run], and fails with `error` if it does
+    /// not succeed.
+    pub(crate) fn check(&self, command: &mut Command, error: impl Display) -> Result<()> {
+        let status = self.run(command)?;
+        if !status.success() {
+            return Err(failed(command, status, error));
+        }
+        Ok(())
+    }
+
+    /// Runs a command like [Self::check], with a progress line labelled
+    /// `label` instead of its output on a terminal.
+    fn check_progress(
+        &self,
+        command: &mut Command,
+        label: &str,
+        error: impl Display,
+    ) -> Result<()> {
+        let status = self.executor.status_progress(command, label)?;
+        if !status.success() {
+            return Err(failed(command, status, error));
+        }
+        Ok(())
+    }
+
+    /// Runs a command like [Self::check_progress] with a `label`, or like
+    /// [Self::check] without one, and retries it with `retry` when it fails
+    /// on the network or with a 5xx error of the registry, waiting longer
+    /// after every attempt.
+    fn check_retry(
+        &self,
+        command: &mut Command,
+        label: Option<&str>,
+        error: impl Display,
+    ) -> Result<()> {
+        let retry = &self.config.retry;
+        let mut delay = retry.delay;
+        let mut attempt = 1;
+        loop {
+            let (status, tail) = self.executor.status_tail(command, label)?;
+            if status.success() {
+                return Ok(());
+            }
+            if attempt >= retry.attempts || !is_transient(command, status, &tail) {
+                return Err(failed(command, status, error));
+            }
+            attempt += 1;
+            warn!(
+                "{}, retrying in {}s (attempt {}/{})",
+                error, delay, attempt, retry.attempts
+            );
+            interrupt::sleep(Duration::from_secs(delay))?;
+            delay = (delay * 2).min(retry.max_delay);
+        }
+    }
+
+    /// Label of the progress line of a service, with its position among the
+    /// services, e.g. `[2/5] web`. On the dashboard, the position is left
+    /// out, so that the progress is shown in the pane of the service.
+    fn progress_label(&self, container: &DockerContainer) -> String {
+        if progress::dashboard() {
+            return container.name.clone();
+        }
+        let position = self
+            .containers
+            .iter()
+            .position(|c| c.name == container.name)
+            .unwrap_or(0);
+        format!(
+            "[{}/{}] {}",
+            position + 1,
+            self.containers.len(),
+            container.name
+        )
+    }
+
+    /// Generates the docker-compose.yaml that is deployed, with the images
+    /// of the built services instead of their build settings.
+    pub fn transform_docker_compose(&self) -> Result<String> {
+        let mut input = compose::read(&compose_files(&self.config))?;
+        let services = input
+            .get_mut("services")
+            .and_then(|k| k.as_mapping_mut())
+            .context("No services in docker-compose")?;
+
+        for (service_name, service) in services.iter_mut() {
+            let build = service.get("build");
+            if build.is_some() {
+                if let Value::String(service_name) = service_name {
+                    let service = service.as_mapping_mut().context("service is not a map")?;
+                    let container = self.containers.iter().find(|c| &c.name == service_name);
+                    let image = match container {
+                        Some(container) => match self.pinned_images.get(service_name) {
+                            Some(pinned) => pinned.clone(),
+                            None => self.image(container),
+                        },
+                        None => self
+                            .reused_images
+                            .get(service_name)
+                            .context(format!("No image for service {}", service_name))?
+                            .clone(),
+                    };
+                    service.insert(Value::String("image".to_string()), Value::String(image));
+                    service.remove(Value::String("build".into()));
+                }
+            }
+        }
+
+        let output = serde_yaml::to_string(&input)?;
+
+        Ok(output)
+    }
+
+    fn run_build_script(&self) -> Result<()> {
+        if let Some(build_script) = &self.config.build {
+            header("Running build script");
+            self.run_script(build_script, false)
+                .context("Failed to execute build script")
+                .failure(Failure::Build)?;
+        }
+        Ok(())
+    }
+
+    fn run_hook(&self, name: &str, hook: &Option<Hook>) -> Result<()> {
+        if let Some(hook) = hook {
+            header(&format!("Running {} hook", name));
+            self.run_script(hook.script(), hook.is_remote())
+                .context(format!("Failed to run {} hook", name))?;
+        }
+        Ok(())
+    }
+
+    /// Runs a bash script locally, or in the remote project directory when
+    /// `remote` is set. The script can read the version from `$DEP_VERSION`
+    /// and the server from `$DEP_SERVER`.
+    fn run_script(&self, script: &str, remote: bool) -> Result<()> {
+        let prefix = r"
+set -o errexit
+set -o nounset
+set -o pipefail";
+        let script = format!(
+            "{}\nexport DEP_VERSION={}\nexport DEP_SERVER={}\n{}",
+            prefix,
+            shell_quote(&self.version),
+            shell_quote(&self.config.server),
+            script
+        );
+        let location = match remote {
+            true => format!(" on {}", self.config.server),
+            false => String::new(),
+        };
+        info!(
+            "Executing{}\x1b[48;2;10;10;10m\n{}\x1b[0m",
+            location, script
+        );
+        if self.dry_run {
+            return Ok(());
+        }
+        let mut command = match remote {
+            true => self.ssh("bash -s"),
+            false => Command::new("bash"),
+        };
+        let input = format!("{}\n", script);
+        let status = self
+            .executor
+            .status_with_input(&mut command, input.as_bytes())?;
+        if !status.success() {
+            bail!("Script exited with an error");
+        }
+        Ok(())
+    }
+
+    /// Builds all containers. Multi-platform images can't be loaded into
+    /// the local image store, so with `push` they are pushed by buildx
+    /// directly.
+    pub fn build_all(&mut self, push: bool) -> Result<()> {
+        self.run_build_script()?;
+        // Hashed after the build script, which may generate files in the
+        // build contexts. Only pushed images can be reused.
+        if push && self.skip_unchanged {
+            self.reuse_unchanged()?;
+        }
+        if !self.config.build_on.is_local() {
+            self.push_build_contexts()?;
+        }
+        let start = Instant::now();
+        if !push && self.containers.iter().any(|c| self.platforms(c).len() > 1) {
+            warn!("multi-platform images are only kept in the build cache");
+        }
+        if self.jobs > 1 && self.containers.len() > 1 && !self.dry_run {
+            self.build_parallel(push)?;
+        } else if self.keep_going {
+            let mut results = vec![];
+            for container in self.containers.iter() {
+                let start = Instant::now();
+                let result = self.build(container, push);
+                info!("");
+                results.push((container.name.clone(), result.map(|_| start.elapsed())));
+                if interrupt::interrupted() {
+                    break;
+                }
+            }
+            self.build_summary(&results)?;
+        } else {
+            for container in self.containers.iter() {
+                self.build(container, push)?;
+                info!("");
+            }
+        }
+        header_elapsed("Built all containers", &start);
+        Ok(())
+    }
+
+    /// Builds the containers using `self.jobs` worker threads. No new builds
+    /// are started after the first failure, unless `keep_going`.
+    fn build_parallel(&self, push: bool) -> Result<()> {
+        let queue = Mutex::new(self.containers.iter());
+        let failed = Mutex::new(vec![]);
+        let results = Mutex::new(vec![]);
+        let abort = AtomicBool::new(false);
+        let width = self.containers.iter().map(|c| c.name.len()).max();
+        let width = width.unwrap_or(0);
+
+        header(&format!(
+            "Building {} containers with {} jobs",
+            self.containers.len(),
+            self.jobs
+        ));
+        std::thread::scope(|scope| {
+            for _ in 0..self.jobs.min(self.containers.len()) {
+                scope.spawn(|| loop {
+                    if abort.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Some(container) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let start = Instant::now();
+                    let result = self.build_prefixed(container, width, push);
+                    if let Err(e) = &result {
+                        error!("{:#}", e);
+                        failed.lock().unwrap().push(container.name.clone());
+                        if !self.keep_going || interrupt::interrupted() {
+                            abort.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    let result = result.map(|_| start.elapsed());
+                    results
+                        .lock()
+                        .unwrap()
+                        .push((container.name.clone(), result));
+                });
+            }
+        });
+
+        if self.keep_going {
+            return self.build_summary(&results.into_inner().unwrap());
+        }
+        let failed = failed.into_inner().unwrap();
+        if !failed.is_empty() {
+            let error = anyhow!("Failed to build {}", failed.join(", "));
+            return Err(exit::mark(Failure::Build, error));
+        }
+        Ok(())
+    }
+
+    /// Prints how the build of each service went with `--keep-going`, and
+    /// fails if any of them failed.
+    fn build_summary(&self, results: &[(String, Result<Duration>)]) -> Result<()> {
+        header("Summary");
+        let width = self.containers.iter().map(|c| c.name.len()).max();
+        let mut failed = vec![];
+        for container in self.containers.iter() {
+            let status = match results.iter().find(|(name, _)| *name == container.name) {
+                Some((_, Ok(elapsed))) => {
+                    format!("\x1b[32mok\x1b[0m in {:.2} seconds", elapsed.as_secs_f64())
+                }
+                Some((name, Err(e))) => {
+                    failed.push(name.as_str());
+                    format!("\x1b[31mfailed\x1b[0m: {:#}", e)
+                }
+                None => "\x1b[33mskipped\x1b[0m".to_string(),
+            };
+            info!(
+                "{:width$}  {}",
+                container.name,
+                status,
+                width = width.unwrap_or(0)
+            );
+        }
+        if !failed.is_empty() {
+            let error = anyhow!(
+                "Failed to build {} of {} services: {}",
+                failed.len(),
+                self.containers.len(),
+                failed.join(", ")
+            );
+            return Err(exit::mark(Failure::Build, error));
+        }
+        Ok(())
+    }
+
+    /// Runs a single docker build with its output prefixed by the service
+    /// name, so that concurrent builds can be told apart.
+    fn build_prefixed(&self, container: &DockerContainer, width: usize, push: bool) -> Result<()> {
+        let prefix = format!("\x1b[1m{:width$} |\x1b[0m", container.name);
+        info!("{} Building {}", prefix, self.image(container));
+        let start = Instant::now();
+        progress::set_state(&container.name, State::Building);
+        let mut builder = self.build_command(container, push)?;
+        builder.env("BUILDKIT_PROGRESS", "plain");
+        let status = match progress::dashboard() {
+            true => self
+                .executor
+                .status_progress(&mut builder, &self.progress_label(container))?,
+            false => self.executor.status_prefixed(&mut builder, &prefix)?,
+        };
+        if !status.success() {
+            let error = anyhow!("Failed to execute docker build for {}", container.name);
+            return Err(exit::mark(Failure::Build, error));
+        }
+        progress::set_state(&container.name, State::Built);
+        info!("{} Built {}", prefix, self.image(container));
+        self.image_built(container, &start);
+        Ok(())
+    }
+
+    /// Builds and pushes the images and files, and deploys them to the
+    /// server, or to each of the `servers`.
+    pub fn deploy(&mut self) -> Result<()> {
+        let start = Instant::now();
+        if self.ui {
+            let services: Vec<String> = self.containers.iter().map(|c| c.name.clone()).collect();
+            progress::start_dashboard(&services);
+        }
+        let servers = match self.config.servers.is_empty() {
+            true => vec![self.config.server.clone()],
+            false => self.config.servers.clone(),
+        };
+        // Failing to read the changes, to create the Sentry release, to tag
+        // the deploy or to notify is reported, but does not fail the deploy.
+        // The history of the server has this deploy afterwards.
+        let changes = self.notified_changes();
+        self.server_outcomes.clear();
+        // Errors of the new version belong to its release from the start.
+        self.sentry_release();
+        let result = self
+            .preflight()
+            .and_then(|_| match self.config.servers.is_empty() {
+                true => self.with_lock(|ctx| ctx.push_and_deploy()),
+                false => self.deploy_servers(),
+            });
+        progress::finish_dashboard(result.is_ok());
+        let primary = self.config.server.clone();
+        for server in servers.iter() {
+            self.config.server = server.clone();
+            // A server that was not reached did not fail itself.
+            let outcome = match self.server_outcomes.get(server) {
+                Some(outcome) => outcome,
+                None => outcome(&result),
+            };
+            self.audit_line(&format!("deploy {}", self.version), outcome);
+        }
+        self.config.server = primary;
+        if result.is_ok() {
+            self.tag_deploy();
+            self.sentry_deployed(start.elapsed());
+        }
+        output::event(
+            "deploy_finished",
+            json!({
+                "version": self.version,
+                "servers": servers,
+                "ok": result.is_ok(),
+                "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+                "seconds": start.elapsed().as_secs_f64(),
+            }),
+        );
+        self.notify(&result, start.elapsed(), changes);
+        result
+    }
+
+    /// Tags the deployed commit with `tag_deploys` and pushes the tag.
+    fn tag_deploy(&self) {
+        let Some(template) = &self.config.tag_deploys else {
+            return;
+        };
+        let result = git::render(&template.replace("{version}", &self.version)).and_then(|tag| {
+            if self.dry_run {
+                info!("Would tag the commit with {} and push the tag", tag);
+                return Ok(());
+            }
+            header(&format!("Tagging the commit with {}", tag));
+            git::push_tag(&tag)
+        });
+        if let Err(e) = result {
+            warn!("Failed to tag the deploy: {:#}", e);
+        }
+    }
+
+    /// Runs `action`, such as `down`, and records it in the audit log on the
+    /// server.
+    pub fn audited<T>(
+        &mut self,
+        action: &str,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let result = f(self);
+        self.audit(action, &result);
+        result
+    }
+
+    /// Appends a line about `action` to [AUDIT_LOG_PATH] on the server, such
+    /// as `2024-06-01T12:00:00Z jane@laptop ok deploy 2024-06-01-1a2b3c4`.
+    fn audit<T>(&self, action: &str, result: &Result<T>) {
+        self.audit_line(action, outcome(result));
+    }
+
+    fn audit_line(&self, action: &str, outcome: &str) {
+        let line = format!(
+            "{} {} {} {}\n",
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            history::deployer(),
+            outcome,
+            action
+        );
+        // With a docker context, the deployed files are kept locally.
+        if self.config.deploy_mode == DeployMode::DockerContext {
+            if let Err(e) = self.write_context_file(AUDIT_LOG_PATH, &line, true) {
+                warn!("{:#}", e);
+            }
+            return;
+        }
+        let mut command = self.ssh(&format!("cat >> {}", AUDIT_LOG_PATH));
+        // An interrupted deploy is recorded too.
+        let status = interrupt::cleanup(|| {
+            self.executor
+                .status_with_input(&mut command, line.as_bytes())
+        });
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(_) => warn!(
+                "Failed to write {} on {}",
+                AUDIT_LOG_PATH, self.config.server
+            ),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+
+    /// Creates the Sentry release of the version and associates the commits
+    /// with it.
+    fn sentry_release(&self) {
+        let Some(sentry) = &self.config.sentry else {
+            return;
+        };
+        header(&format!("Creating the Sentry release {}", self.version));
+        let result = self
+            .sentry_cli(sentry, &["releases", "new", &self.version])
+            .and_then(|_| {
+                self.sentry_cli(
+                    sentry,
+                    &["releases", "set-commits", &self.version, "--auto"],
+                )
+            });
+        if let Err(e) = result {
+            warn!("{:#}", e);
+        }
+    }
+
+    /// Finalizes the Sentry release and marks the deploy to the Sentry
+    /// environment.
+    fn sentry_deployed(&self, duration: Duration) {
+        let Some(sentry) = &self.config.sentry else {
+            return;
+        };
+        header(&format!("Marking the deploy of {} in Sentry", self.version));
+        let seconds = duration.as_secs().to_string();
+        let result = self
+            .sentry_cli(sentry, &["releases", "finalize", &self.version])
+            .and_then(|_| {
+                self.sentry_cli(
+                    sentry,
+                    &[
+                        "releases",
+                        "deploys",
+                        &self.version,
+                        "new",
+                        "--env",
+                        &sentry.environment,
+                        "--time",
+                        &seconds,
+                    ],
+                )
+            });
+        if let Err(e) = result {
+            warn!("{:#}", e);
+        }
+    }
+
+    fn sentry_cli(&self, sentry: &SentryConfig, args: &[&str]) -> Result<()> {
+        let mut command = Command::new("sentry-cli");
+        command
+            .args(args)
+            .env("SENTRY_ORG", &sentry.org)
+            .env("SENTRY_PROJECT", &sentry.project);
+        if let Some(url) = &sentry.url {
+            command.env("SENTRY_URL", url);
+        }
+        self.check(
+            &mut command,
+            format!("Failed to run sentry-cli {}", args[..2].join(" ")),
+        )
+    }
+
+    /// Builds and pushes the images once, and deploys to each of the
+    /// `servers`, starting with the `canary`.
+    fn deploy_servers(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let mut servers = self.config.servers.clone();
+        if let Some(canary) = &self.config.canary {
+            let index = servers
+                .iter()
+                .position(|s| s == canary)
+                .context(format!("The canary {} is not one of the servers", canary))?;
+            let canary = servers.remove(index);
+            servers.insert(0, canary);
+        }
+
+        self.build_images()?;
+        self.run_hook("pre_push", &self.config.pre_push)?;
+        if self.config.transport.is_registry() {
+            self.push_images()?;
+        }
+        let rolling = self.config.rolling.is_some();
+        self.for_each_server("Deploying to", &servers, rolling, |ctx| {
+            ctx.with_lock(|ctx| {
+                if !ctx.config.transport.is_registry() {
+                    ctx.push_containers()?;
+                }
+                ctx.push_files()?;
+                ctx.deploy_pushed()
+            })
+        })?;
+        if !self.config.transport.is_registry() {
+            self.save_build_state()?;
+        }
+        header_elapsed(&format!("Deployed to {} servers", servers.len()), &start);
+        Ok(())
+    }
+
+    /// Runs `f` for each of the servers in turn, with `config.server` set to
+    /// it, and prints how it went on each server. A server that fails does
+    /// not stop the others, except for the canary or in a `rolling` deploy.
+    fn for_each_server(
+        &mut self,
+        action: &str,
+        servers: &[String],
+        rolling: bool,
+        mut f: impl FnMut(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        let primary = self.config.server.clone();
+        let mut results = vec![];
+        for (i, server) in servers.iter().enumerate() {
+            // The delay only fails when dep is interrupted.
+            if i > 0 && rolling && self.rolling_delay().is_err() {
+                break;
+            }
+            header(&format!("{} {}", action, server));
+            self.config.server = server.clone();
+            let start = Instant::now();
+            let result = f(self);
+            output::event(
+                "server_finished",
+                json!({
+                    "action": action,
+                    "server": server,
+                    "ok": result.is_ok(),
+                    "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+                    "seconds": start.elapsed().as_secs_f64(),
+                }),
+            );
+            let stop = result.is_err() && (rolling || self.is_canary() || interrupt::interrupted());
+            self.server_outcomes
+                .insert(server.clone(), outcome(&result));
+            results.push((server, result, start.elapsed()));
+            if stop {
+                break;
+            }
+        }
+        self.config.server = primary;
+
+        header("Summary");
+        let mut failed = 0;
+        for server in servers {
+            let status = match results.iter().find(|(s, ..)| *s == server) {
+                Some((_, Ok(()), elapsed)) => {
+                    format!("\x1b[32mok\x1b[0m in {:.2} seconds", elapsed.as_secs_f64())
+                }
+                Some((_, Err(e), _)) => {
+                    failed += 1;
+                    format!("\x1b[31mfailed\x1b[0m: {:#}", e)
+                }
+                None => {
+                    self.server_outcomes.insert(server.clone(), "skipped");
+                    "\x1b[33mskipped\x1b[0m".to_string()
+                }
+            };
+            info!("{:<30}  {}", server, status);
+        }
+        if let Some((server, ..)) = results.last().filter(|_| results.len() < servers.len()) {
+            bail!("{} failed, so the other servers were skipped", server);
+        }
+        if failed > 0 {
+            bail!("{} of {} servers failed", failed, servers.len());
+        }
+        Ok(())
+    }
+
+    /// Waits for the `rolling.delay` before deploying to the next server.
+    fn rolling_delay(&self) -> Result<()> {
+        let delay = self.config.rolling.as_ref().map_or(0, |r| r.delay);
+        if delay == 0 {
+            return Ok(());
+        }
+        match self.dry_run {
+            true => info!("Would wait {} seconds before the next server", delay),
+            false => {
+                info!("Waiting {} seconds before the next server", delay);
+                interrupt::sleep(Duration::from_secs(delay))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the server is the canary of the `servers`.
+    fn is_canary(&self) -> bool {
+        !self.config.servers.is_empty()
+            && self.config.canary.as_deref() == Some(self.config.server.as_str())
+    }
+
+    /// Runs `f` while holding the deploy lock on the server, so two deploys
+    /// to the same project can't run at the same time.
+    pub fn with_lock<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let lock = DeployLock {
+            owner: history::deployer(),
+            pid: std::process::id(),
+            version: self.version.clone(),
+            date: chrono::Utc::now(),
+        };
+        let remote_path = remote_shell_path(self.config.remote_path());
+        let mut command = self.ssh_command();
+        command.arg(format!(
+            "mkdir -p {path} && cd {path} && {script}",
+            path = remote_path,
+            script = lock.acquire_script(self.force_unlock)?
+        ));
+        let output = self.executor.output(command.stderr(Stdio::inherit()))?;
+        if output.status.code() == Some(lock::LOCKED_EXIT_CODE) {
+            let holder: DeployLock = serde_yaml::from_slice(&output.stdout)
+                .context(format!("Failed to parse {}", LOCK_PATH))?;
+            bail!(
+                "{} is being deployed by {} (pid {}, version {}) since {}. Use --force-unlock if that deploy is no longer running",
+                self.config.server,
+                holder.owner,
+                holder.pid,
+                holder.version,
+                holder.date.format("%Y-%m-%d %H:%M:%S UTC")
+            );
+        }
+        if !output.status.success() {
+            let error = format!("Failed to lock {} on {}", LOCK_PATH, self.config.server);
+            return Err(failed(&command, output.status, error));
+        }
+
+        let result = f(self);
+        // The lock is also released when dep is interrupted.
+        let status = interrupt::cleanup(|| self.run(&mut self.ssh(&lock.release_script()?)))?;
+        let value = result?;
+        if !status.success() {
+            bail!("Failed to remove {} on {}", LOCK_PATH, self.config.server);
+        }
+        Ok(value)
+    }
+
+    /// The commits since the last deploy, for the notifications.
+    fn notified_changes(&self) -> Vec<String> {
+        if self.config.notifications.is_empty() {
+            return vec![];
+        }
+        match self.changes() {
+            Ok(changes) => changes
+                .map(|(_, commits)| commits.iter().map(|c| c.line()).collect())
+                .unwrap_or_default(),
+            Err(e) => {
+                warn!("{:#}", e);
+                vec![]
+            }
+        }
+    }
+
+    /// Sends the result of a deploy, with the commits in it, to the
+    /// configured webhooks.
+    fn notify(&self, result: &Result<()>, duration: Duration, changes: Vec<String>) {
+        if self.config.notifications.is_empty() {
+            return;
+        }
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        let server = match self.config.servers.is_empty() {
+            true => self.config.server.clone(),
+            false => self.config.servers.join(", "),
+        };
+        let mut event =
+            DeployEvent::new(&self.config.name, &self.version, &server, duration, error);
+        event.changes = changes;
+        for notification in self.config.notifications.iter() {
+            let payload = event.payload(notification.kind);
+            let result = match &notification.token_env {
+                Some(name) => std::env::var(name)
+                    .context(format!("Set {} to notify {}", name, notification.url))
+                    .map(Some),
+                None => Ok(None),
+            };
+            let result = result
+                .and_then(|token| self.post_json(&notification.url, &payload, token.as_deref()));
+            if let Err(e) = result {
+                warn!("{:#}", e);
+            }
+        }
+    }
+
+    /// Posts `body` to `url`, with `token` as a bearer token. They are passed
+    /// on stdin, so the token does not show up in the process list.
+    fn post_json(&self, url: &str, body: &serde_json::Value, token: Option<&str>) -> Result<()> {
+        let mut config = vec![format!(
+            "data-binary = {}",
+            registry::curl_quote(&body.to_string())
+        )];
+        if let Some(token) = token {
+            let header = format!("Authorization: Bearer {}", token);
+            config.push(format!("header = {}", registry::curl_quote(&header)));
+        }
+        let mut command = Command::new("curl");
+        command
+            .arg("--fail")
+            .arg("--silent")
+            .arg("--show-error")
+            .arg("--header")
+            .arg("Content-Type: application/json")
+            .arg("--config")
+            .arg("-")
+            .arg(url)
+            .stdout(Stdio::null());
+        let status = self
+            .executor
+            .status_with_input(&mut command, config.join("\n").as_bytes())?;
+        if self.dry_run {
+            info!("\x1b[48;2;10;10;10m{}\x1b[0m", body);
+        }
+        if !status.success() {
+            bail!("Failed to post to {}", url);
+        }
+        Ok(())
+    }
+
+    fn push_and_deploy(&mut self) -> Result<()> {
+        let start = Instant::now();
+        self.push_all(true)?;
+        self.deploy_pushed()?;
+        header_elapsed("Deployed", &start);
+        Ok(())
+    }
+
+    /// Deploys the version that has been pushed to the server.
+    fn deploy_pushed(&self) -> Result<()> {
+        let previous = self.deployed_version()?;
+        if self.config.releases {
+            self.switch_release(&self.version)?;
+        }
+        if let Err(e) = self.prepare_deploy() {
+            // The old version keeps running, so point back at its files.
+            if let (true, Some(previous)) = (self.config.releases, &previous) {
+                self.switch_release(previous)?;
+            }
+            return Err(e);
+        }
+        let started = Instant::now();
+        let result = match self.config.strategy {
+            Strategy::Recreate => self
+                .compose_up()
+                .and_then(|_| self.wait_healthy(&self.compose()?)),
+            Strategy::BlueGreen => self.blue_green(),
+        };
+        let result = result.and_then(|_| self.smoke_test());
+        let result = result.and_then(|_| self.watch(&started));
+        let result = result.and_then(|_| match self.is_canary() {
+            true => self.run_hook("canary_check", &self.config.canary_check),
+            false => Ok(()),
+        });
+        if let Err(e) = result {
+            match previous {
+                Some(previous) if self.config.rollback_on_failure => {
+                    error!("{:#}", e);
+                    self.rollback(Some(previous))?;
+                    bail!("Deploy of {} failed and was rolled back", self.version);
+                }
+                _ => return Err(e),
+            }
+        }
+        self.mark_deployed(&self.version)?;
+        self.record_deploy()?;
+        progress::set_states(State::Deployed);
+        self.run_hook("post_deploy", &self.config.post_deploy)?;
+        if let Some(retention) = self.config.retention {
+            if let Err(e) = self.clean_remote(retention) {
+                warn!("{:#}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the `pre_deploy` hook, pulls the images and runs the migrations
+    /// of the new version, while the old version is still running.
+    fn prepare_deploy(&self) -> Result<()> {
+        self.run_hook("pre_deploy", &self.config.pre_deploy)?;
+        header("Deploying");
+        progress::set_states(State::Deploying);
+        if self.pull {
+            // Images copied over ssh only exist on the server itself.
+            let pull = match self.config.transport.is_registry() {
+                true => "pull",
+                false => "pull --ignore-pull-failures",
+            };
+            self.check_retry(
+                &mut self.compose_script(&format!("{} {}", self.compose()?, pull)),
+                None,
+                format!("Failed to {} pull", self.compose()?),
+            )?;
+        }
+        self.run_migrations()
+    }
+
+    /// Runs the `migrations` in one-off containers of the new version, before
+    /// it is started.
+    fn run_migrations(&self) -> Result<()> {
+        for migration in self.config.migrations.iter() {
+            header(&format!(
+                "Running {} in {}",
+                migration.command, migration.service
+            ));
+            self.check(
+                &mut self.compose_script(&format!(
+                    "{} run --rm -T {} {}",
+                    self.compose()?,
+                    shell_quote(&migration.service),
+                    migration.command
+                )),
+                format!(
+                    "Migration `{}` in {} failed",
+                    migration.command, migration.service
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Runs the `smoke_tests`, retrying each until it passes or times out.
+    fn smoke_test(&self) -> Result<()> {
+        if self.config.smoke_tests.is_empty() {
+            return Ok(());
+        }
+        header("Running smoke tests");
+        for test in self.config.smoke_tests.iter() {
+            if self.dry_run {
+                info!("Would check {}", test);
+                continue;
+            }
+            let start = Instant::now();
+            loop {
+                match self.probe(test) {
+                    Ok(()) => break,
+                    Err(_) if start.elapsed() < Duration::from_secs(test.timeout()) => {
+                        interrupt::sleep(Duration::from_secs(2))?;
+                    }
+                    Err(e) => {
+                        return Err(e.context(format!("Smoke test {} failed", test)))
+                            .failure(Failure::Healthcheck)
+                    }
+                }
+            }
+            info!("\x1b[32mok\x1b[0m {}", test);
+        }
+        Ok(())
+    }
+
+    /// Shows the logs of the services for `watch` seconds after they were
+    /// started, and fails if a container keeps restarting or exits with an
+    /// error in that time.
+    fn watch(&self, started: &Instant) -> Result<()> {
+        let Some(seconds) = self.config.watch else {
+            return Ok(());
+        };
+        if self.dry_run {
+            info!("Would watch the services for {} seconds", seconds);
+            return Ok(());
+        }
+        header(&format!("Watching the services for {} seconds", seconds));
+        // Relative to the server's clock, with some slack for the ssh calls.
+        let since = started.elapsed().as_secs() + 5;
+        let logs = self.executor.spawn(&mut self.compose_script(&format!(
+            "{} logs --follow --since {}s",
+            self.compose()?,
+            since
+        )))?;
+        let result = self.watch_containers(seconds);
+        if let Some(mut logs) = logs {
+            logs.kill()?;
+            logs.wait()?;
+        }
+        result
+    }
+
+    fn watch_containers(&self, seconds: u64) -> Result<()> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(seconds) {
+            interrupt::sleep(Duration::from_secs(2))?;
+            let text =
+                self.compose_output(&format!("{} ps --all --format json", self.compose()?))?;
+            let containers = PsEntry::parse_all(&text)?;
+            let crashed: Vec<_> = containers
+                .iter()
+                .filter(|c| c.is_crashed())
+                .map(|c| c.service.as_str())
+                .collect();
+            if !crashed.is_empty() {
+                let error = anyhow!("Crashing services: {}", crashed.join(", "));
+                return Err(exit::mark(Failure::Healthcheck, error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a smoke test once.
+    fn probe(&self, test: &SmokeTest) -> Result<()> {
+        match test {
+            SmokeTest::Http { url, status, .. } => {
+                let output = self.executor.query(
+                    Command::new("curl")
+                        .arg("--silent")
+                        .arg("--output")
+                        .arg("/dev/null")
+                        .arg("--write-out")
+                        .arg("%{http_code}")
+                        .arg("--max-time")
+                        .arg("10")
+                        .arg(url),
+                )?;
+                let expected = status.unwrap_or(200).to_string();
+                let actual = String::from_utf8_lossy(&output.stdout);
+                if actual.trim() != expected {
+                    bail!("Expected status {}, got {}", expected, actual.trim());
+                }
+            }
+            SmokeTest::Command { command, .. } => {
+                let output = self
+                    .executor
+                    .query(self.ssh(command).stderr(Stdio::piped()))?;
+                if !output.status.success() {
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    bail!("{}: {}", output.status, stderr.trim());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The version that was deployed most recently, if any.
+    fn deployed_version(&self) -> Result<Option<String>> {
+        Ok(self.deployed_versions()?.pop())
+    }
+
+    /// The versions in the order they were deployed, with a line per deploy.
+    fn deployed_versions(&self) -> Result<Vec<String>> {
+        let path = format!("{}/deployed", VERSIONS_DIR);
+        let deployed = match self.config.deploy_mode {
+            DeployMode::Ssh => self.ssh_output(&format!("cat {} 2>/dev/null; true", path))?,
+            DeployMode::DockerContext => {
+                std::fs::read_to_string(self.context_dir().join(path)).unwrap_or_default()
+            }
+        };
+        let deployed = deployed.lines().map(str::trim).filter(|v| !v.is_empty());
+        Ok(deployed.map(str::to_string).collect())
+    }
+
+    /// Waits until all services with a healthcheck are healthy, for at most
+    /// `healthcheck_timeout` seconds. `compose` is the docker compose command
+    /// of the project to check.
+    fn wait_healthy(&self, compose: &str) -> Result<()> {
+        // Rolling deploys only move on to the next server once it is healthy.
+        let rolling = self
+            .config
+            .rolling
+            .as_ref()
+            .map(|_| ROLLING_HEALTHCHECK_TIMEOUT);
+        let Some(timeout) = self.config.healthcheck_timeout.or(rolling) else {
+            return Ok(());
+        };
+        if self.dry_run {
+            info!(
+                "Would wait up to {} seconds for services to be healthy",
+                timeout
+            );
+            return Ok(());
+        }
+        header("Waiting for services to be healthy");
+        let start = Instant::now();
+        loop {
+            let text = self.compose_output(&format!("{} ps --format json", compose))?;
+            let containers = PsEntry::parse_all(&text)?;
+            let unhealthy: Vec<_> = containers
+                .iter()
+                .filter(|c| c.health == "unhealthy")
+                .map(|c| c.service.as_str())
+                .collect();
+            if !unhealthy.is_empty() {
+                let error = anyhow!("Unhealthy services: {}", unhealthy.join(", "));
+                return Err(exit::mark(Failure::Healthcheck, error));
+            }
+            let waiting: Vec<_> = containers
+                .iter()
+                .filter(|c| !c.health.is_empty() && c.health != "healthy")
+                .map(|c| c.service.as_str())
+                .collect();
+            if waiting.is_empty() {
+                header_elapsed("All services are healthy", &start);
+                return Ok(());
+            }
+            if start.elapsed() > Duration::from_secs(timeout) {
+                let error = anyhow!(
+                    "Timed out after {} seconds waiting for {} to be healthy",
+                    timeout,
+                    waiting.join(", ")
+                );
+                return Err(exit::mark(Failure::Healthcheck, error));
+            }
+            info!("Waiting for {}", waiting.join(", "));
+            interrupt::sleep(Duration::from_secs(2))?;
+        }
+    }
+
+    /// Writes a record of the current deployment to the history directory
+    /// on the server.
+    fn record_deploy(&self) -> Result<()> {
+        let record = self.deploy_record()?;
+        let path = format!("{}/{}", HISTORY_DIR, record.file_name());
+        self.write_remote_file(&path, &serde_yaml::to_string(&record)?)
+    }
+
+    /// Describes the version with the digest of each image, for the history
+    /// and [DEPLOY_MANIFEST_PATH].
+    fn deploy_record(&self) -> Result<DeployRecord> {
+        let mut images = BTreeMap::new();
+        for container in self.containers.iter() {
+            let image = self.image(container);
+            let digest = self.image_digest(&image).unwrap_or(None);
+            images.insert(container.name.clone(), digest.unwrap_or(image));
+        }
+        for (name, image) in self.reused_images.iter() {
+            images.insert(name.clone(), image.clone());
+        }
+        Ok(DeployRecord {
+            version: self.version.clone(),
+            git_sha: git::sha()?,
+            date: chrono::Utc::now(),
+            user: history::deployer(),
+            images,
+        })
+    }
+
+    /// Shows the logs of the services, or of all services when none are
+    /// given.
+    pub fn logs(&self, services: &[String], follow: bool, since: Option<String>) -> Result<()> {
+        let mut script = format!("{} logs", self.compose()?);
+        if follow {
+            script.push_str(" --follow");
+        }
+        if let Some(since) = since {
+            script.push_str(&format!(" --since {}", shell_quote(&since)));
+        }
+        for service in services {
+            script.push_str(&format!(" {}", shell_quote(service)));
+        }
+        self.check(
+            &mut self.compose_script(&script),
+            format!("Failed to run {} logs", self.compose()?),
+        )?;
+        Ok(())
+    }
+
+    /// Runs `docker compose <command>` on the server for the services, or for
+    /// all services when none are given.
+    pub fn compose_command(&self, command: &str, services: &[String]) -> Result<()> {
+        let mut script = format!("{} {}", self.compose()?, command);
+        for service in services {
+            script.push_str(&format!(" {}", shell_quote(service)));
+        }
+        self.check(
+            &mut self.compose_script(&script),
+            format!("Failed to run {} {}", self.compose()?, command),
+        )?;
+        Ok(())
+    }
+
+    /// Runs `command` in a container of `service` on the server with
+    /// `docker compose <compose>`, such as `exec` or `run --rm`, and returns
+    /// its exit code.
+    pub fn exec(&self, compose: &str, service: &str, command: &[String], tty: bool) -> Result<i32> {
+        let mut script = format!("{} {}", self.compose()?, compose);
+        if !tty {
+            script.push_str(" -T");
+        }
+        script.push_str(&format!(" {}", shell_quote(service)));
+        for arg in command {
+            script.push_str(&format!(" {}", shell_quote(arg)));
+        }
+        let name = compose.split(' ').next().unwrap_or(compose);
+        let action = format!("{} {} -- {}", name, service, command.join(" "));
+        // It is recorded before it runs too, since it may run for a long time.
+        self.audit_line(&action, "started");
+        let mut command = match tty {
+            true => self.compose_script_tty(&script),
+            false => self.compose_script(&script),
+        };
+        let code = self.run(&mut command).and_then(|status| {
+            status.code().context(format!(
+                "{} {} in {} was killed",
+                self.compose()?,
+                compose,
+                service
+            ))
+        });
+        match &code {
+            Ok(0) => self.audit_line(&action, "ok"),
+            Ok(code) => self.audit_line(&format!("{} (exit code {})", action, code), "failed"),
+            Err(_) => self.audit(&action, &code),
+        }
+        code
+    }
+
+    /// Opens an interactive shell in the remote project directory, and
+    /// returns its exit code.
+    pub fn shell(&self) -> Result<i32> {
+        let status = self.run(&mut self.ssh_tty(r#"exec "$SHELL" -l"#))?;
+        status.code().context("The ssh session was killed")
+    }
+
+    /// Lists the containers of the project on the server, as a table or as
+    /// JSON.
+    pub fn ps(&self, json: bool) -> Result<()> {
+        let text = self.compose_output(&format!("{} ps --all --format json", self.compose()?))?;
+        let containers = PsEntry::parse_all(&text)?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&containers)?);
+            return Ok(());
+        }
+        println!(
+            "{:<20}  {:<10}  {:<10}  {:<30}  STATUS",
+            "SERVICE", "STATE", "HEALTH", "TAG"
+        );
+        for c in containers.iter() {
+            let health = match c.health.as_str() {
+                "" => "-",
+                health => health,
+            };
+            println!(
+                "{:<20}  {:<10}  {:<10}  {:<30}  {}",
+                c.service,
+                c.state,
+                health,
+                ps::image_tag(&c.image),
+                c.status
+            );
+        }
+        Ok(())
+    }
+
+    /// Compares the images running on the server with the images a deploy
+    /// would use.
+    pub fn status(&self) -> Result<()> {
+        let text = self.compose_output(&format!("{} ps --all --format json", self.compose()?))?;
+        let running = PsEntry::parse_all(&text)?;
+        let services = self.service_images()?;
+
+        println!("Local version: {}", self.version);
+        if let Some(manifest) = self.deploy_manifest()? {
+            let sha: String = manifest.git_sha.chars().take(10).collect();
+            println!(
+                "Pushed version: {} ({}) by {} at {}",
+                manifest.version,
+                sha,
+                manifest.user,
+                manifest.date.format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+        println!(
+            "{:<20}  {:<12}  {:<30}  LOCAL",
+            "SERVICE", "STATE", "DEPLOYED"
+        );
+        let mut behind = 0;
+        for (name, local) in services.iter() {
+            let container = running.iter().find(|c| c.service == *name);
+            let (state, deployed) = match container {
+                Some(c) => (c.state.as_str(), c.image.as_str()),
+                None => ("missing", ""),
+            };
+            let deployed_tag = match deployed {
+                "" => "-",
+                image => ps::image_tag(image),
+            };
+            let marker = if deployed == *local {
+                ""
+            } else {
+                behind += 1;
+                " *"
+            };
+            println!(
+                "{:<20}  {:<12}  {:<30}  {}{}",
+                name,
+                state,
+                deployed_tag,
+                ps::image_tag(local),
+                marker
+            );
+        }
+        if behind == 0 {
+            println!("\n{} is up to date", self.config.server);
+        } else {
+            println!(
+                "\n{} service(s) on {} differ from the local version",
+                behind, self.config.server
+            );
+        }
+        Ok(())
+    }
+
+    /// The image of each service in the generated compose file, or an empty
+    /// string for services without one.
+    fn service_images(&self) -> Result<Vec<(String, String)>> {
+        let compose: Value = serde_yaml::from_str(&self.transform_docker_compose()?)?;
+        let services = compose
+            .get("services")
+            .and_then(|s| s.as_mapping())
+            .context("No services in docker-compose")?;
+        Ok(services
+            .iter()
+            .map(|(name, service)| {
+                let name = name.as_str().unwrap_or_default();
+                let image = service.get("image").and_then(|i| i.as_str());
+                (name.to_string(), image.unwrap_or_default().to_string())
+            })
+            .collect())
+    }
+
+    /// Reads [DEPLOY_MANIFEST_PATH] next to the compose file on the server.
+    fn deploy_manifest(&self) -> Result<Option<DeployRecord>> {
+        // The compose file of a docker context is not on the server.
+        if self.config.deploy_mode == DeployMode::DockerContext {
+            return Ok(None);
+        }
+        let path = match self.config.releases {
+            true => format!("{}/{}", CURRENT_RELEASE, DEPLOY_MANIFEST_PATH),
+            false => DEPLOY_MANIFEST_PATH.to_string(),
+        };
+        let text = self.ssh_output(&format!("cat {} 2>/dev/null; true", path))?;
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+        let manifest = serde_json::from_str(&text).context(format!("Failed to parse {}", path))?;
+        Ok(Some(manifest))
+    }
+
+    /// Compares the servers with what a deploy of the local version leaves
+    /// there: the checksums of the pushed files, and the images of the
+    /// containers. Fails when any server has drifted, e.g. because a file
+    /// was edited by hand or a container still runs an old image.
+    pub fn verify(&mut self) -> Result<()> {
+        if self.config.pin_digests && self.config.transport.is_registry() {
+            self.pin_digests()?;
+        }
+        if self.config.servers.is_empty() {
+            return self.verify_server();
+        }
+        let servers = self.config.servers.clone();
+        self.for_each_server("Verifying", &servers, false, |ctx| ctx.verify_server())
+    }
+
+    fn verify_server(&mut self) -> Result<()> {
+        let server = self.config.server.clone();
+        let differences = self.verify_files()? + self.verify_containers()?;
+        if differences > 0 {
+            bail!(
+                "{} difference(s) between {} and version {}",
+                differences,
+                server,
+                self.version
+            );
+        }
+        header(&format!("{} runs version {}", server, self.version));
+        Ok(())
+    }
+
+    /// Prints the pushed files that are missing, changed or extra on the
+    /// server, compared by checksum, and returns how many there are.
+    fn verify_files(&self) -> Result<usize> {
+        header(&format!("Files on {}", self.config.server));
+        let compose_txt = self.transform_docker_compose()?;
+        if self.config.deploy_mode == DeployMode::DockerContext {
+            let path = self.context_dir().join(DOCKER_COMPOSE_PATH);
+            let pushed = std::fs::read_to_string(&path).unwrap_or_default();
+            if pushed == compose_txt {
+                info!("All files match");
+                return Ok(0);
+            }
+            info!("\x1b[33mchanged\x1b[0m {}", path.display());
+            return Ok(1);
+        }
+        let tmp_dir = tempfile::tempdir()?;
+        let changes = self.file_changes(tmp_dir.path(), &compose_txt, &["--checksum"])?;
+        // The manifest records when the files were pushed, so it always
+        // differs.
+        let changes: Vec<_> = changes
+            .into_iter()
+            .filter(|(_, path)| path != DEPLOY_MANIFEST_PATH)
+            .collect();
+        if changes.is_empty() {
+            info!("All files match");
+        }
+        for (change, path) in changes.iter() {
+            match change {
+                Change::Create => info!("\x1b[31mmissing\x1b[0m {}", path),
+                Change::Update => info!("\x1b[33mchanged\x1b[0m {}", path),
+                Change::Delete => info!("\x1b[33mextra\x1b[0m {}", path),
+            }
+        }
+        Ok(changes.len())
+    }
+
+    /// Prints the services whose containers are missing, run another image,
+    /// or run an older image than the one the tag points to on the server,
+    /// and returns how many there are.
+    fn verify_containers(&self) -> Result<usize> {
+        header(&format!("Containers on {}", self.config.server));
+        let expected = self.service_images()?;
+
+        let text = self.compose_output(&format!(
+            "for c in $({} ps --all --quiet); do docker inspect --format '{{{{index .Config.Labels \"com.docker.compose.service\"}}}} {{{{.Config.Image}}}} {{{{.Image}}}}' \"$c\"; done",
+            self.compose()?
+        ))?;
+        let running: Vec<Vec<&str>> = text
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .filter(|fields| fields.len() == 3)
+            .collect();
+        let images: Vec<String> = expected
+            .iter()
+            .filter(|(_, image)| !image.is_empty())
+            .map(|(_, image)| shell_quote(image))
+            .collect();
+        let text = self.compose_output(&format!(
+            "for i in {}; do echo \"$i $(docker image inspect --format '{{{{.Id}}}}' \"$i\" 2>/dev/null)\"; done",
+            images.join(" ")
+        ))?;
+        let image_ids: BTreeMap<&str, &str> = text
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .collect();
+
+        let width = expected.iter().map(|(name, _)| name.len()).max();
+        let width = width.unwrap_or(0);
+        let mut differences = 0;
+        for (name, image) in expected.iter() {
+            let containers: Vec<_> = running.iter().filter(|c| c[0] == *name).collect();
+            let problem = if containers.is_empty() {
+                Some("has no container".to_string())
+            } else if image.is_empty() {
+                None
+            } else if let Some(c) = containers.iter().find(|c| c[1] != *image) {
+                Some(format!(
+                    "runs {} instead of {}",
+                    ps::image_tag(c[1]),
+                    ps::image_tag(image)
+                ))
+            } else {
+                match image_ids.get(image.as_str()).map(|id| id.trim()) {
+                    Some(id) if !id.is_empty() && containers.iter().any(|c| c[2] != id) => {
+                        Some(format!("runs an older image than {}", ps::image_tag(image)))
+                    }
+                    _ => None,
+                }
+            };
+            match problem {
+                Some(problem) => {
+                    differences += 1;
+                    info!("{:width$}  \x1b[33m{}\x1b[0m", name, problem);
+                }
+                None => info!(
+                    "{:width$}  \x1b[32mok\x1b[0m {}",
+                    name,
+                    ps::image_tag(image)
+                ),
+            }
+        }
+        Ok(differences)
+    }
+
+    /// Prints the deploys recorded on the server.
+    pub fn history(&self) -> Result<()> {
+        let text = match self.config.deploy_mode {
+            DeployMode::Ssh => self.ssh_output(&format!(
+                "for f in {}/*.yaml; do [ -f \"$f\" ] && echo --- && cat \"$f\"; done; true",
+                HISTORY_DIR
+            ))?,
+            DeployMode::DockerContext => self.context_history()?,
+        };
+        let records = DeployRecord::parse_all(&text)?;
+        if records.is_empty() {
+            println!("No deployments recorded on {}", self.config.server);
+        } else {
+            history::print_history(&records);
+        }
+        Ok(())
+    }
+
+    /// The most recent deploy record on the server.
+    fn last_deploy(&self) -> Result<Option<DeployRecord>> {
+        let text = match self.config.deploy_mode {
+            DeployMode::Ssh => self.ssh_output(&format!(
+                "f=$(ls -1 {}/*.yaml 2>/dev/null | tail -n 1); [ -n \"$f\" ] && cat \"$f\"; true",
+                HISTORY_DIR
+            ))?,
+            DeployMode::DockerContext => self.context_history()?,
+        };
+        Ok(DeployRecord::parse_all(&text)?.pop())
+    }
+
+    /// Services whose build context or Dockerfile changed since the commit
+    /// that was last deployed. All services are affected when nothing has
+    /// been deployed yet.
+    pub fn affected_services(&self) -> Result<Vec<String>> {
+        let Some(record) = self.last_deploy()? else {
+            info!(
+                "Nothing is deployed to {} yet, so all services are affected",
+                self.config.server
+            );
+            return Ok(self.containers.iter().map(|c| c.name.clone()).collect());
+        };
+        let changed = git::changed_files(&record.git_sha)?;
+        self.services_changed(&changed)
+    }
+
+    /// The services whose build context or Dockerfile is one of `files`.
+    fn services_changed(&self, files: &[PathBuf]) -> Result<Vec<String>> {
+        let mut changed = vec![];
+        for container in self.containers.iter() {
+            let context = std::fs::canonicalize(&container.build_dir).context(format!(
+                "Failed to find build context {}",
+                container.build_dir
+            ))?;
+            let dockerfile = match &container.dockerfile {
+                Some(file) => std::fs::canonicalize(file).ok(),
+                None => None,
+            };
+            let is_changed = files
+                .iter()
+                .any(|f| f.starts_with(&context) || Some(f) == dockerfile.as_ref());
+            if is_changed {
+                changed.push(container.name.clone());
+            }
+        }
+        Ok(changed)
+    }
+
+    /// What was deployed last, such as `2024-06-01-1a2b3c4 (1a2b3c4)`, and
+    /// the commits since, from the history on the server or else from the
+    /// newest `tag_deploys` tag. None when nothing was deployed yet.
+    fn changes(&self) -> Result<Option<(String, Vec<git::Commit>)>> {
+        if let Some(record) = self.last_deploy()? {
+            let short = record.git_sha.get(..7).unwrap_or(&record.git_sha);
+            let deployed = format!("{} ({})", record.version, short);
+            return Ok(Some((deployed, git::log(&record.git_sha)?)));
+        }
+        let Some(template) = &self.config.tag_deploys else {
+            return Ok(None);
+        };
+        // Every placeholder matches any text in the tag.
+        let mut pattern = String::new();
+        let mut placeholder = false;
+        for c in template.chars() {
+            match c {
+                '{' => placeholder = true,
+                '}' if placeholder => {
+                    placeholder = false;
+                    pattern.push('*');
+                }
+                _ if placeholder => {}
+                c => pattern.push(c),
+            }
+        }
+        match git::newest_tag(&pattern)? {
+            Some(tag) => {
+                let commits = git::log(&tag)?;
+                Ok(Some((tag, commits)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The changelog since the last deploy, with the commits grouped by the
+    /// services whose build context they change.
+    fn changelog_lines(&self) -> Result<Vec<String>> {
+        let Some((deployed, commits)) = self.changes()? else {
+            return Ok(vec![format!(
+                "Nothing is deployed to {} yet",
+                self.config.server
+            )]);
+        };
+        if commits.is_empty() {
+            return Ok(vec![format!("No changes since {}", deployed)]);
+        }
+        let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut other = vec![];
+        for commit in commits.iter() {
+            let services = self.services_changed(&commit.files)?;
+            if services.is_empty() {
+                other.push(commit.line());
+            }
+            for service in services {
+                groups.entry(service).or_default().push(commit.line());
+            }
+        }
+        let mut lines = vec![format!("{} commit(s) since {}", commits.len(), deployed)];
+        let other = (!other.is_empty()).then(|| ("other".to_string(), other));
+        for (group, commits) in groups.into_iter().chain(other) {
+            lines.push(format!("{}:", group));
+            lines.extend(commits.iter().map(|c| format!("  {}", c)));
+        }
+        Ok(lines)
+    }
+
+    /// Prints the commits since the last deploy, grouped by service.
+    pub fn changelog(&self) -> Result<()> {
+        for line in self.changelog_lines()? {
+            println!("{}", line);
+        }
+        Ok(())
+    }
+
+    /// Returns the registry digest of a pushed image, such as
+    /// `registry/web@sha256:...`.
+    fn image_digest(&self, image: &str) -> Result<Option<String>> {
+        let output = self.executor.query(
+            self.engine()
+                .arg("image")
+                .arg("inspect")
+                .arg("--format")
+                .arg("{{join .RepoDigests \"\\n\"}}")
+                .arg(image)
+                .stderr(Stdio::null()),
+        )?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let repository = image.rsplit_once(':').map_or(image, |(repo, _)| repo);
+        let digests = String::from_utf8(output.stdout)?;
+        let digest = digests
+            .lines()
+            .find(|d| d.starts_with(&format!("{}@", repository)));
+        Ok(digest.map(|d| d.to_string()))
+    }
+
+    /// Resolves the registry digest of every pushed image, so the server
+    /// runs `registry/service:version@sha256:...` instead of a mutable tag.
+    fn pin_digests(&mut self) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        for container in self.containers.iter() {
+            let image = self.image(container);
+            // Images that were pushed by buildx, or not pushed because the
+            // registry had them already, have no local repo digest.
+            let local = match self.pushed_by_buildx(container) {
+                true => None,
+                false => self
+                    .image_digest(&image)?
+                    .and_then(|d| d.split_once('@').map(|(_, digest)| digest.to_string())),
+            };
+            let digest = match local {
+                Some(digest) => digest,
+                None => self
+                    .manifest_digest(&image)?
+                    .context(format!("Failed to find the digest of {}", image))?,
+            };
+            self.pinned_images
+                .insert(container.name.clone(), format!("{}@{}", image, digest));
+        }
+        Ok(())
+    }
+
+    /// Pins the images of services that are not built to their current
+    /// digest, either in the compose files or in a separate override file.
+    pub fn pin(&self, output: Option<PathBuf>) -> Result<()> {
+        let files = compose_files(&self.config);
+        let images = pin::third_party_images(&compose::read(&files)?);
+        let mut pinned = BTreeMap::new();
+        for (service, image) in images.iter() {
+            let tag = pin::strip_digest(image);
+            let digest = self
+                .manifest_digest(tag)?
+                .context(format!("Failed to find the digest of {}", tag))?;
+            let new = format!("{}@{}", tag, digest);
+            match &new == image {
+                true => println!("{} {} is up to date", service, image),
+                false => println!("{} {}", service, new),
+            }
+            pinned.insert(service.clone(), (image.clone(), new));
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+        match output {
+            Some(output) => {
+                let mut services = serde_yaml::Mapping::new();
+                for (service, (_, new)) in pinned {
+                    let mut image = serde_yaml::Mapping::new();
+                    image.insert("image".into(), new.into());
+                    services.insert(service.into(), image.into());
+                }
+                let mut compose = serde_yaml::Mapping::new();
+                compose.insert("services".into(), services.into());
+                std::fs::write(&output, serde_yaml::to_string(&compose)?)
+                    .context(format!("Failed to write {}", output.display()))?;
+            }
+            None => {
+                for file in files {
+                    let text = std::fs::read_to_string(&file)?;
+                    let mut updated = text.clone();
+                    for (old, new) in pinned.values() {
+                        updated = pin::replace_image(&updated, old, new);
+                    }
+                    if updated != text {
+                        std::fs::write(&file, updated)
+                            .context(format!("Failed to write {}", file.display()))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports third-party images that have a newer version in their
+    /// registry, or whose pinned digest is no longer the digest of the tag.
+    pub fn outdated(&self) -> Result<()> {
+        let images = pin::third_party_images(&compose::read(&compose_files(&self.config))?);
+        println!("{:<20}  {:<40}  STATUS", "SERVICE", "IMAGE");
+        let mut outdated = 0;
+        for (service, image) in images.iter() {
+            let status = match self.image_updates(image) {
+                Ok(updates) if updates.is_empty() => "up to date".to_string(),
+                Ok(updates) => {
+                    outdated += 1;
+                    format!("\x1b[33m{}\x1b[0m", updates.join(", "))
+                }
+                Err(e) => format!("\x1b[31m{:#}\x1b[0m", e),
+            };
+            println!(
+                "{:<20}  {:<40}  {}",
+                service,
+                pin::strip_digest(image),
+                output::styled(&status)
+            );
+        }
+        if outdated > 0 {
+            println!("\n{} image(s) can be updated", outdated);
+        }
+        Ok(())
+    }
+
+    /// Checks that the tools dep runs are installed and working, and that the
+    /// servers and the registry can be reached, with a fix for every problem.
+    pub fn doctor(&mut self) -> Result<()> {
+        let mut failed = 0;
+        let mut check = |name: &str, result: Result<String>, fix: &str, required: bool| match result
+        {
+            Ok(detail) => info!("\x1b[32mok\x1b[0m       {:<22}  {}", name, detail),
+            Err(e) if !required => {
+                info!("\x1b[33mwarning\x1b[0m  {:<22}  {:#}", name, e);
+                info!("         {:<22}  {}", "", fix);
+            }
+            Err(e) => {
+                failed += 1;
+                info!("\x1b[31mfailed\x1b[0m   {:<22}  {:#}", name, e);
+                info!("         {:<22}  {}", "", fix);
+            }
+        };
+
+        header("Local tools");
+        check(
+            "git",
+            self.tool_version(Command::new("git").arg("--version")),
+            "Install git, e.g. `sudo apt install git`",
+            true,
+        );
+        check(
+            "git repository",
+            self.tool_version(Command::new("git").arg("rev-parse").arg("--show-toplevel")),
+            "Run dep in a git repository, which the version is computed from, or use --tag",
+            true,
+        );
+        let engine = self.engine_name().to_string();
+        check(
+            &engine,
+            self.tool_version(self.engine().arg("--version")),
+            match self.config.engine {
+                Engine::Docker => "Install docker: https://docs.docker.com/engine/install/",
+                Engine::Podman => "Install podman: https://podman.io/docs/installation",
+            },
+            true,
+        );
+        check(
+            &format!("{} daemon", engine),
+            self.tool_version(self.engine().arg("info").arg("--format").arg("{{json .ID}}"))
+                .map(|_| "running".to_string()),
+            "Start the docker daemon, and add your user to the docker group with `sudo usermod -aG docker $USER`",
+            true,
+        );
+        if self.config.engine == Engine::Docker {
+            // Builds only use buildx for multiple platforms and caches.
+            let buildx = !self.config.cache_from.is_empty()
+                || !self.config.cache_to.is_empty()
+                || self
+                    .containers
+                    .iter()
+                    .any(|c| !self.platforms(c).is_empty());
+            check(
+                "docker buildx",
+                self.tool_version(self.engine().arg("buildx").arg("version")),
+                "Install the buildx plugin: https://docs.docker.com/build/install-buildx/",
+                buildx,
+            );
+        }
+        check(
+            "ssh",
+            self.tool_version(Command::new("ssh").arg("-V")),
+            "Install OpenSSH, e.g. `sudo apt install openssh-client`",
+            true,
+        );
+        if self.config.deploy_mode == DeployMode::Ssh {
+            check(
+                "rsync",
+                self.tool_version(Command::new("rsync").arg("--version")),
+                "Install rsync, e.g. `sudo apt install rsync`",
+                true,
+            );
+        }
+
+        if self.config.sentry.is_some() {
+            check(
+                "sentry-cli",
+                self.tool_version(Command::new("sentry-cli").arg("--version")),
+                "Install sentry-cli: https://docs.sentry.io/cli/installation/",
+                false,
+            );
+        }
+
+        header("Servers");
+        let servers = match self.config.servers.is_empty() {
+            true => vec![self.config.server.clone()],
+            false => self.config.servers.clone(),
+        };
+        let primary = self.config.server.clone();
+        for server in servers {
+            self.config.server = server.clone();
+            let result = self.remote_docker();
+            let fix = match &result {
+                Err(RemoteError::Ssh(_)) => format!(
+                    "Check that `ssh {}` logs in without a password prompt, e.g. with `ssh-copy-id {}`",
+                    server, server
+                ),
+                _ => format!(
+                    "Install docker on {}, and add the user to the docker group with `sudo usermod -aG docker $USER`",
+                    server
+                ),
+            };
+            check(&server, result.map_err(anyhow::Error::from), &fix, true);
+        }
+        self.config.server = primary;
+
+        if self.config.transport.is_registry() && !self.registry.is_empty() {
+            header("Registry");
+            let host = self.registry_host().to_string();
+            check(
+                &host,
+                self.registry_login_state(),
+                &format!(
+                    "Run `{} login {}`, or set registry_auth in {}",
+                    engine,
+                    host,
+                    self.config.path().display()
+                ),
+                true,
+            );
+        }
+
+        if failed > 0 {
+            bail!("{} check(s) failed", failed);
+        }
+        info!("Everything looks good");
+        Ok(())
+    }
+
+    /// The first line of the output of a command that prints its version.
+    fn tool_version(&self, command: &mut Command) -> Result<String> {
+        let program = command.get_program().to_string_lossy().to_string();
+        let output = self
+            .executor
+            .query(command)
+            .map_err(|_| anyhow::anyhow!("{} is not installed", program))?;
+        // ssh prints its version to stderr.
+        let text = match output.stdout.is_empty() {
+            true => String::from_utf8_lossy(&output.stderr),
+            false => String::from_utf8_lossy(&output.stdout),
+        };
+        let line = text.lines().next().unwrap_or_default().trim().to_string();
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let error = stderr.lines().last().unwrap_or_default().trim();
+            bail!("{} failed: {}", command_line(command), error);
+        }
+        Ok(line)
+    }
+
+    /// The docker version on the server, checked without prompting for a
+    /// password.
+    fn remote_docker(&self) -> Result<String, RemoteError> {
+        let mut command = Command::new("ssh");
+        command
+            .args(self.config.ssh().args())
+            .arg("-o")
+            .arg("BatchMode=yes")
+            .arg("-o")
+            .arg("ConnectTimeout=10")
+            .arg(&self.config.server)
+            .arg("docker version --format 'docker {{.Server.Version}}'")
+            .stderr(Stdio::piped());
+        let output = self
+            .executor
+            .query(&mut command)
+            .map_err(|e| RemoteError::Ssh(format!("{:#}", e)))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let error = stderr.lines().last().unwrap_or_default().trim().to_string();
+        match output.status.code() {
+            Some(0) => Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+            // ssh exits with 255 when it fails to connect or log in.
+            Some(255) => Err(RemoteError::Ssh(error)),
+            _ => Err(RemoteError::Docker(error)),
+        }
+    }
+
+    /// Who is logged in to the registry, from `registry_auth` or the docker
+    /// credentials.
+    fn registry_login_state(&self) -> Result<String> {
+        if let Some((username, _)) = self.registry_credentials()? {
+            return Ok(format!("registry_auth as {}", username));
+        }
+        let host = self.registry_host();
+        if self.config.engine == Engine::Podman {
+            let mut command = self.engine();
+            command.arg("login").arg("--get-login").arg(host);
+            let login = self.tool_version(&mut command);
+            return login
+                .map(|user| format!("logged in as {}", user))
+                .map_err(|_| anyhow::anyhow!("Not logged in to {}", host));
+        }
+        let path = match std::env::var_os("DOCKER_CONFIG") {
+            Some(dir) => PathBuf::from(dir).join("config.json"),
+            None => PathBuf::from(std::env::var_os("HOME").unwrap_or_default())
+                .join(".docker/config.json"),
+        };
+        let config: serde_json::Value = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .context(format!("Failed to parse {}", path.display()))?,
+            Err(_) => json!({}),
+        };
+        if config["auths"].get(host).is_some()
+            || config["auths"].get(format!("https://{}", host)).is_some()
+        {
+            return Ok(format!("logged in, in {}", path.display()));
+        }
+        let helper = config["credHelpers"][host]
+            .as_str()
+            .or(config["credsStore"].as_str());
+        if let Some(helper) = helper {
+            if let Ok((username, _)) = credential_helper_get(helper, host) {
+                return Ok(format!(
+                    "logged in as {} with docker-credential-{}",
+                    username, helper
+                ));
+            }
+        }
+        bail!("Not logged in to {}", host)
+    }
+
+    /// Describes the available updates of an image.
+    fn image_updates(&self, image: &str) -> Result<Vec<String>> {
+        let mut updates = vec![];
+        let tag = pin::strip_digest(image);
+        if let Some((_, pinned)) = image.split_once('@') {
+            let digest = self
+                .manifest_digest(tag)?
+                .context(format!("Failed to find the digest of {}", tag))?;
+            if digest != pinned {
+                updates.push(format!("new digest {}", digest));
+            }
+        }
+        let reference = ImageRef::parse(image);
+        let tags = registry::Client::new(&reference, None, "pull")?.tags()?;
+        if let Some(newer) = registry::newer_tag(&reference.tag, &tags) {
+            updates.push(format!("newer tag {}", newer));
+        }
+        Ok(updates)
+    }
+
+    /// Reads the digest of an image, or the manifest list of a
+    /// multi-platform image, from the registry.
+    fn manifest_digest(&self, image: &str) -> Result<Option<String>> {
+        #[derive(serde::Deserialize)]
+        struct Descriptor {
+            digest: String,
+        }
+
+        if self.config.engine == Engine::Podman {
+            // Podman has no imagetools, but comes with skopeo.
+            let output = self.executor.query(
+                Command::new("skopeo")
+                    .arg("inspect")
+                    .arg("--format")
+                    .arg("{{.Digest}}")
+                    .arg(format!("docker://{}", image))
+                    .stderr(Stdio::null()),
+            )?;
+            if !output.status.success() {
+                return Ok(None);
+            }
+            return Ok(Some(String::from_utf8(output.stdout)?.trim().to_string()));
+        }
+        let output = self.executor.query(
+            Command::new("docker")
+                .arg("buildx")
+                .arg("imagetools")
+                .arg("inspect")
+                .arg("--format")
+                .arg("{{json .Manifest}}")
+                .arg(image)
+                .stderr(Stdio::null()),
+        )?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let descriptor: Descriptor = serde_json::from_slice(&output.stdout)
+            .context(format!("Failed to parse the manifest of {}", image))?;
+        Ok(Some(descriptor.digest))
+    }
+
+    /// Writes `contents` to `path`, relative to the remote project directory.
+    fn write_remote_file(&self, path: &str, contents: &str) -> Result<()> {
+        if self.config.deploy_mode == DeployMode::DockerContext {
+            return self.write_context_file(path, contents, false);
+        }
+        let mut command = self.ssh(&format!(
+            "mkdir -p \"$(dirname {path})\" && cat > {path}",
+            path = shell_quote(path)
+        ));
+        let status = self
+            .executor
+            .status_with_input(&mut command, contents.as_bytes())?;
+        if self.dry_run {
+            info!("\x1b[48;2;10;10;10m{}\x1b[0m", contents.trim_end());
+        }
+        if !status.success() {
+            bail!("Failed to write {} on {}", path, self.config.server);
+        }
+        Ok(())
+    }
+
+    fn compose_up(&self) -> Result<()> {
+        self.check_retry(
+            &mut self.compose_script(&format!("{} {}", self.compose()?, self.up())),
+            None,
+            format!("Failed to run {} up -d", self.compose()?),
+        )
+    }
+
+    /// The compose command on the server, which is `compose_command`, or
+    /// `docker compose` or the standalone `docker-compose` when it is unset.
+    fn compose(&self) -> Result<String> {
+        if let Some(command) = &self.config.compose_command {
+            return Ok(command.clone());
+        }
+        if self.config.deploy_mode == DeployMode::DockerContext {
+            // Relative paths resolve against the local project directory,
+            // not the generated file.
+            return Ok("docker compose --project-directory .".to_string());
+        }
+        let mut commands = self.compose_commands.lock().unwrap();
+        if let Some(command) = commands.get(&self.config.server) {
+            return Ok(command.clone());
+        }
+        let mut command = self.ssh_command();
+        command
+            .arg("if docker compose version >/dev/null 2>&1 || ! command -v docker-compose >/dev/null; then echo docker compose; else echo docker-compose; fi")
+            .stderr(Stdio::inherit());
+        let output = self.executor.query(&mut command)?;
+        if !output.status.success() {
+            let error = format!(
+                "Failed to detect the compose command on {}",
+                self.config.server
+            );
+            return Err(failed(&command, output.status, error));
+        }
+        let command = String::from_utf8(output.stdout)?.trim().to_string();
+        commands.insert(self.config.server.clone(), command.clone());
+        Ok(command)
+    }
+
+    /// The `up -d` command with the `compose_up` options.
+    fn up(&self) -> String {
+        let mut up = "up -d".to_string();
+        for arg in self.config.compose_up.args() {
+            up.push_str(&format!(" {}", shell_quote(&arg)));
+        }
+        up
+    }
+
+    /// Points the `current` symlink at the release of `version`. The link is
+    /// replaced with a rename, so it always points at a complete release.
+    fn switch_release(&self, version: &str) -> Result<()> {
+        let release = format!("{}/{}", RELEASES_DIR, version);
+        self.check(
+            &mut self.ssh(&format!(
+                "ln -sfn {} {current}.tmp && mv -T {current}.tmp {current}",
+                shell_quote(&release),
+                current = CURRENT_RELEASE
+            )),
+            format!("Failed to switch {} to {}", CURRENT_RELEASE, release),
+        )?;
+        Ok(())
+    }
+
+    /// Starts the new version as the colour that is not receiving traffic,
+    /// switches traffic to it with the `switch_traffic` hook, and stops the
+    /// old colour. If the new colour fails, it is stopped again and the old
+    /// one keeps running.
+    fn blue_green(&self) -> Result<()> {
+        let (active, color) = self.colors()?;
+        let active = active.as_str();
+        let project = format!("{}-{}", self.project_name(), color);
+        let compose = format!("COMPOSE_PROJECT_NAME={} {}", project, self.compose()?);
+
+        header(&format!("Starting {}", project));
+        let result = self
+            .run(&mut self.ssh(&format!("{} {}", compose, self.up())))
+            .and_then(|status| match status.success() {
+                true => Ok(()),
+                false => bail!("Failed to start {}", project),
+            })
+            .and_then(|_| self.wait_healthy(&compose))
+            .and_then(|_| self.switch_traffic(color, &project));
+        if let Err(e) = result {
+            header(&format!("Stopping {}", project));
+            self.run(&mut self.ssh(&format!("{} down --remove-orphans", compose)))?;
+            return Err(e);
+        }
+
+        self.check(
+            &mut self.ssh(&format!(
+                "mkdir -p {} && echo {} > {}",
+                VERSIONS_DIR, color, COLOR_PATH
+            )),
+            format!("Failed to record {} as the active colour", color),
+        )?;
+
+        // The project ran without a colour before the first blue-green deploy.
+        let old = match active.is_empty() {
+            true => self.project_name(),
+            false => format!("{}-{}", self.project_name(), active),
+        };
+        header(&format!("Stopping {}", old));
+        self.check(
+            &mut self.ssh(&format!(
+                "COMPOSE_PROJECT_NAME={} {} down --remove-orphans",
+                old,
+                self.compose()?
+            )),
+            format!("Failed to stop {}", old),
+        )?;
+        Ok(())
+    }
+
+    /// The colour that is receiving traffic, which is empty before the first
+    /// blue-green deploy, and the colour the next version starts as.
+    fn colors(&self) -> Result<(String, &'static str)> {
+        let active = self.ssh_output(&format!("cat {} 2>/dev/null; true", COLOR_PATH))?;
+        let active = active.trim().to_string();
+        let color = match active.as_str() {
+            "blue" => "green",
+            _ => "blue",
+        };
+        Ok((active, color))
+    }
+
+    /// Runs the `switch_traffic` hook for the new colour.
+    fn switch_traffic(&self, color: &str, project: &str) -> Result<()> {
+        let Some(hook) = &self.config.switch_traffic else {
+            return Ok(());
+        };
+        header(&format!("Switching traffic to {}", project));
+        let script = format!(
+            "export DEP_COLOR={} DEP_PROJECT={}\n{}",
+            color,
+            project,
+            hook.script()
+        );
+        self.run_script(&script, hook.is_remote())
+            .context("Failed to run switch_traffic hook")
+    }
+
+    /// Records `version` as the currently deployed version.
+    fn mark_deployed(&self, version: &str) -> Result<()> {
+        if self.config.deploy_mode == DeployMode::DockerContext {
+            let path = format!("{}/deployed", VERSIONS_DIR);
+            return self.write_context_file(&path, &format!("{}\n", version), true);
+        }
+        self.check(
+            &mut self.ssh(&format!(
+                "mkdir -p {dir} && echo {} >> {dir}/deployed",
+                shell_quote(version),
+                dir = VERSIONS_DIR
+            )),
+            format!("Failed to record {} as deployed", version),
+        )?;
+        Ok(())
+    }
+
+    /// Removes the images and stored compose files of all but the
+    /// `retention` most recently deployed versions from the server, and
+    /// prunes dangling images.
+    fn clean_remote(&self, retention: usize) -> Result<()> {
+        header("Removing old images");
+        let services = self
+            .containers
+            .iter()
+            .map(|c| &c.name)
+            .chain(self.reused_images.keys());
+        let repositories: Vec<_> = services.map(|s| shell_quote(&self.image_name(s))).collect();
+        let keep_tags: Vec<_> = self.extra_tags.iter().map(|t| shell_quote(t)).collect();
+        let script = format!(
+            r#"keep=$(printf '%s\n' {keep_tags}; tac {versions}/deployed | awk '!seen[$0]++' | head -n {retention})
+for repository in {repositories}; do
+  docker image ls --format '{{{{.Repository}}}}:{{{{.Tag}}}}' "$repository" | while read -r image; do
+    echo "$keep" | grep -qxF "${{image##*:}}" || docker image rm "$image" || true
+  done
+done
+for dir in {pushed}/*/; do
+  echo "$keep" | grep -qxF "$(basename "$dir")" || rm -rf "$dir"
+done
+docker image prune --force"#,
+            keep_tags = keep_tags.join(" "),
+            versions = VERSIONS_DIR,
+            pushed = self.pushed_versions_dir(),
+            retention = retention,
+            repositories = repositories.join(" "),
+        );
+        // With a docker context, the versions are kept locally, and docker
+        // talks to the daemon of the server.
+        let script = match self.config.deploy_mode {
+            DeployMode::Ssh => script,
+            DeployMode::DockerContext => format!(
+                "cd {} && {}",
+                shell_quote(&self.context_dir().to_string_lossy()),
+                script
+            ),
+        };
+        self.check(
+            &mut self.compose_script(&script),
+            format!("Failed to remove old images from {}", self.config.server),
+        )?;
+        Ok(())
+    }
+
+    /// Removes all but the `keep` newest locally built images of each
+    /// service, and the dangling images and build cache.
+    pub fn clean(&self, keep: usize) -> Result<()> {
+        for container in self.containers.iter() {
+            let output = self.executor.query(
+                self.engine()
+                    .arg("image")
+                    .arg("ls")
+                    .arg("--format")
+                    .arg("{{.Repository}}:{{.Tag}}")
+                    .arg(self.image_name(&container.name)),
+            )?;
+            if !output.status.success() {
+                bail!("Failed to list the images of {}", container.name);
+            }
+            // docker image ls lists the newest images first.
+            let images = String::from_utf8(output.stdout)?;
+            let old = images
+                .lines()
+                .filter(|i| {
+                    let tag = ps::image_tag(i);
+                    tag != "<none>" && !self.extra_tags.iter().any(|t| t == tag)
+                })
+                .skip(keep);
+            for image in old {
+                self.check(
+                    self.engine().arg("image").arg("rm").arg(image),
+                    format!("Failed to remove {}", image),
+                )?;
+            }
+        }
+        if self.config.engine == Engine::Podman {
+            // Podman has no builder command, the build cache is pruned with
+            // the images.
+            let mut prune = self.engine();
+            prune.args(["image", "prune", "--force", "--build-cache"]);
+            if !self.run(&mut prune)?.success() {
+                bail!("Failed to run podman image prune");
+            }
+            return Ok(());
+        }
+        for prune in ["image", "builder"] {
+            self.check(
+                Command::new("docker")
+                    .arg(prune)
+                    .arg("prune")
+                    .arg("--force"),
+                format!("Failed to run docker {} prune", prune),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Redeploys a version that has previously been pushed to the server.
+    /// Without a version, the version deployed before the current one is used.
+    pub fn rollback(&self, version: Option<String>) -> Result<()> {
+        let start = Instant::now();
+        let pushed = match self.config.deploy_mode {
+            DeployMode::Ssh => self.ssh_output(&format!(
+                "for d in {}/*/; do basename \"$d\"; done",
+                self.pushed_versions_dir()
+            ))?,
+            DeployMode::DockerContext => self.context_versions()?.join("\n"),
+        };
+        let pushed: Vec<&str> = pushed.lines().collect();
+        let version = match version {
+            Some(version) => version,
+            None => {
+                let deployed = self.deployed_versions()?;
+                let current = deployed.last().context("Nothing has been deployed yet")?;
+                let previous = deployed.iter().rev().find(|v| v != &current);
+                previous
+                    .context(format!("No version deployed before {}", current))?
+                    .to_string()
+            }
+        };
+        if !pushed.contains(&version.as_str()) {
+            bail!(
+                "Version {} has not been pushed to {}. Available versions: [{}]",
+                version,
+                self.config.server,
+                pushed.join(", ")
+            );
+        }
+
+        header(&format!("Rolling back to {}", version));
+        if self.config.releases {
+            self.switch_release(&version)?;
+        } else if self.config.deploy_mode == DeployMode::DockerContext {
+            let dir = self.context_dir();
+            let path = dir
+                .join(VERSIONS_DIR)
+                .join(&version)
+                .join(DOCKER_COMPOSE_PATH);
+            match self.dry_run {
+                true => info!("Would copy {} to {}", path.display(), DOCKER_COMPOSE_PATH),
+                false => {
+                    std::fs::copy(&path, dir.join(DOCKER_COMPOSE_PATH))
+                        .context(format!("Failed to restore {}", path.display()))?;
+                }
+            }
+        } else {
+            self.check(
+                &mut self.ssh(&format!(
+                    "cp {}/{}/{} {}",
+                    VERSIONS_DIR,
+                    shell_quote(&version),
+                    DOCKER_COMPOSE_PATH,
+                    DOCKER_COMPOSE_PATH
+                )),
+                format!("Failed to restore {} for {}", DOCKER_COMPOSE_PATH, version),
+            )?;
+        }
+        match self.config.strategy {
+            Strategy::Recreate => self.compose_up()?,
+            Strategy::BlueGreen => self.blue_green()?,
+        }
+        self.mark_deployed(&version)?;
+        header_elapsed(&format!("Rolled back to {}", version), &start);
+        Ok(())
+    }
+
+    /// Creates a command that runs a docker compose `script` for the project.
+    /// With `deploy_mode: docker-context`, the script runs locally against
+    /// the docker daemon of the server, otherwise it runs on the server.
+    fn compose_script(&self, script: &str) -> Command {
+        match self.config.deploy_mode {
+            DeployMode::Ssh => self.ssh(script),
+            DeployMode::DockerContext => self.docker_context_script(script),
+        }
+    }
+
+    /// Like [Self::compose_script], but with a terminal for interactive
+    /// commands.
+    fn compose_script_tty(&self, script: &str) -> Command {
+        match self.config.deploy_mode {
+            DeployMode::Ssh => self.ssh_tty(script),
+            DeployMode::DockerContext => self.docker_context_script(script),
+        }
+    }
+
+    /// Runs a docker compose `script` and returns its output. Like
+    /// [Self::ssh_output], this also runs during dry runs.
+    fn compose_output(&self, script: &str) -> Result<String> {
+        let mut command = self.compose_script(script);
+        let output = self.executor.query(command.stderr(Stdio::inherit()))?;
+        if !output.status.success() {
+            let error = format!("Failed to run `{}` for {}", script, self.config.server);
+            return Err(failed(&command, output.status, error));
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    /// Creates a local command that runs `script` with docker pointed at the
+    /// daemon of the server, and docker compose at the generated compose
+    /// file of the server.
+    fn docker_context_script(&self, script: &str) -> Command {
+        let mut command = Command::new("bash");
+        command
+            .arg("-c")
+            .arg(script)
+            .env("COMPOSE_FILE", self.context_dir().join(DOCKER_COMPOSE_PATH))
+            .env("COMPOSE_PROJECT_NAME", self.project_name());
+        match &self.config.docker_context {
+            Some(context) => command.env("DOCKER_CONTEXT", context),
+            None => command.env("DOCKER_HOST", self.docker_host()),
+        };
+        command
+    }
+
+    /// The `ssh://` url of the docker daemon on the server.
+    fn docker_host(&self) -> String {
+        let ssh = self.config.ssh();
+        let mut host = "ssh://".to_string();
+        if let Some(user) = &ssh.user {
+            host += &format!("{}@", user);
+        }
+        host += &self.config.server;
+        if let Some(port) = ssh.port {
+            host += &format!(":{}", port);
+        }
+        host
+    }
+
+    /// The local directory with the generated compose files of the server,
+    /// with `deploy_mode: docker-context`.
+    fn context_dir(&self) -> PathBuf {
+        Path::new(CONTEXT_DIR)
+            .join(&self.config.server)
+            .join(self.project_name())
+    }
+
+    /// Writes `contents` to `path` in [Self::context_dir], or appends them
+    /// with `append`. With `deploy_mode: docker-context`, the state that is
+    /// otherwise kept on the server is kept there.
+    fn write_context_file(&self, path: &str, contents: &str, append: bool) -> Result<()> {
+        let path = self.context_dir().join(path);
+        if self.dry_run {
+            info!("Would write {}: {}", path.display(), contents.trim_end());
+            return Ok(());
+        }
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(append)
+                    .truncate(!append)
+                    .open(&path)?
+                    .write_all(contents.as_bytes())
+            });
+        written.context(format!("Failed to write {}", path.display()))
+    }
+
+    /// The deploy records in [Self::context_dir], oldest first, separated
+    /// like [Self::history] reads them from the server.
+    fn context_history(&self) -> Result<String> {
+        let dir = self.context_dir().join(HISTORY_DIR);
+        let mut paths = vec![];
+        if dir.exists() {
+            for entry in
+                std::fs::read_dir(&dir).context(format!("Failed to read {}", dir.display()))?
+            {
+                paths.push(entry?.path());
+            }
+        }
+        paths.retain(|p| p.extension().is_some_and(|e| e == "yaml"));
+        paths.sort();
+        let mut text = String::new();
+        for path in paths {
+            text += "---\n";
+            text += &std::fs::read_to_string(&path)
+                .context(format!("Failed to read {}", path.display()))?;
+        }
+        Ok(text)
+    }
+
+    /// The versions that have been pushed to the server, oldest first, with
+    /// `deploy_mode: docker-context`.
+    fn context_versions(&self) -> Result<Vec<String>> {
+        let dir = self.context_dir().join(VERSIONS_DIR);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut versions = vec![];
+        for entry in std::fs::read_dir(&dir).context(format!("Failed to read {}", dir.display()))? {
+            versions.push(entry?.file_name().to_string_lossy().to_string());
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    /// Creates an ssh command that runs `script` in the remote project directory.
+    fn ssh(&self, script: &str) -> Command {
+        let mut command = self.ssh_command();
+        command.arg(self.remote_script(script));
+        command
+    }
+
+    /// Like [Self::ssh], but with a terminal for interactive commands.
+    fn ssh_tty(&self, script: &str) -> Command {
+        let mut command = Command::new("ssh");
+        command
+            .args(self.config.ssh().args())
+            .arg("-t")
+            .arg(&self.config.server)
+            .arg(self.remote_script(script));
+        command
+    }
+
+    /// Prefixes `script` with changing into the remote project directory.
+    /// With `releases`, docker compose is pointed at the current release, and
+    /// with `strategy: blue-green` at the colour that receives traffic.
+    fn remote_script(&self, script: &str) -> String {
+        let mut prefix = format!("cd {}", remote_shell_path(self.config.remote_path()));
+        if self.config.releases {
+            prefix += &format!(
+                " && export COMPOSE_FILE={}/{}",
+                CURRENT_RELEASE, DOCKER_COMPOSE_PATH
+            );
+        }
+        match self.config.strategy {
+            Strategy::Recreate if self.config.releases || self.config.compose_project.is_some() => {
+                prefix += &format!(" && export COMPOSE_PROJECT_NAME={}", self.project_name());
+            }
+            Strategy::Recreate => {}
+            // The project of the colour that receives traffic.
+            Strategy::BlueGreen => {
+                prefix += &format!(
+                    " && export COMPOSE_PROJECT_NAME={}$(sed 's/^/-/' {} 2>/dev/null)",
+                    self.project_name(),
+                    COLOR_PATH
+                );
+            }
+        }
+        format!("{} && {}", prefix, script)
+    }
+
+    /// The compose project name. Defaults to the name of the remote project
+    /// directory, like docker compose picks when run in it.
+    fn project_name(&self) -> String {
+        if let Some(project) = &self.config.compose_project {
+            return project.clone();
+        }
+        let path = Path::new(self.config.remote_path());
+        let name = path.file_name().unwrap_or(path.as_os_str());
+        name.to_string_lossy()
+            .to_lowercase()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .collect()
+    }
+
+    /// The directory on the server with a subdirectory per pushed version.
+    fn pushed_versions_dir(&self) -> &'static str {
+        match self.config.releases {
+            true => RELEASES_DIR,
+            false => VERSIONS_DIR,
+        }
+    }
+
+    /// The path of the deployed docker-compose.yaml in the remote project
+    /// directory.
+    fn remote_compose_path(&self) -> String {
+        match self.config.releases {
+            true => format!("{}/{}", CURRENT_RELEASE, DOCKER_COMPOSE_PATH),
+            false => DOCKER_COMPOSE_PATH.to_string(),
+        }
+    }
+
+    /// Creates an ssh command to the server without a remote command.
+    fn ssh_command(&self) -> Command {
+        let mut command = Command::new("ssh");
+        command
+            .args(self.config.ssh().args())
+            .arg(&self.config.server);
+        command
+    }
+
+    /// Runs `script` in the remote project directory and returns its output.
+    /// This also runs during dry runs, so it must only be used for queries.
+    pub(crate) fn ssh_output(&self, script: &str) -> Result<String> {
+        let mut command = self.ssh(script);
+        let output = self.executor.query(command.stderr(Stdio::inherit()))?;
+        if !output.status.success() {
+            let error = format!("Failed to run `{}` on {}", script, self.config.server);
+            return Err(failed(&command, output.status, error));
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    /// Checks the servers, builds and pushes the images, unless `images` is
+    /// false, and pushes the files to the server.
+    pub fn push(&mut self, images: bool) -> Result<()> {
+        self.preflight()?;
+        self.push_all(images)
+    }
+
+    /// Prints what a deploy would do without changing anything: the commits
+    /// since the last deploy, the services that are built or reuse an image,
+    /// the images that are pushed, the files that rsync copies and the
+    /// commands that run on each server.
+    pub fn plan(&mut self) -> Result<()> {
+        header("Changes");
+        match self.changelog_lines() {
+            Ok(lines) => lines.iter().for_each(|line| info!("{}", line)),
+            Err(e) => warn!("{:#}", e),
+        }
+        let built = self.plan_builds()?;
+        self.plan_pushes(&built)?;
+        let servers = match self.config.servers.is_empty() {
+            true => vec![self.config.server.clone()],
+            false => self.config.servers.clone(),
+        };
+        let primary = self.config.server.clone();
+        let result = servers.iter().try_for_each(|server| {
+            self.config.server = server.clone();
+            self.plan_files()?;
+            self.plan_commands()
+        });
+        self.config.server = primary;
+        result
+    }
+
+    /// Prints whether each service is built or reuses an image, and returns
+    /// the services that are built.
+    fn plan_builds(&self) -> Result<Vec<String>> {
+        header("Build");
+        if self.config.build.is_some() {
+            info!("Run the build script");
+        }
+        let names = self.containers.iter().map(|c| &c.name);
+        let width = names
+            .chain(self.reused_images.keys())
+            .map(|n| n.len())
+            .max();
+        let width = width.unwrap_or(0);
+        let state = BuildState::read()?;
+        let mut built = vec![];
+        for container in self.containers.iter() {
+            let hash = self.build_hash(container)?;
+            let reason = match state.images.get(&self.repository(container)) {
+                Some(pushed) if pushed.hash == hash && self.skip_unchanged => {
+                    info!(
+                        "{:width$}  \x1b[32mreuse\x1b[0m {}, unchanged",
+                        container.name, pushed.image
+                    );
+                    continue;
+                }
+                Some(_) if !self.config.skip_unchanged => "skip_unchanged is off",
+                Some(pushed) if pushed.hash == hash => "unchanged, but --rebuild or --pull is set",
+                Some(_) => "changed since the last push",
+                None => "not pushed before",
+            };
+            let location = match self.config.build_on.is_local() {
+                true => "",
+                false => " on the server",
+            };
+            info!(
+                "{:width$}  \x1b[33mbuild\x1b[0m{}, {}",
+                container.name, location, reason
+            );
+            built.push(container.name.clone());
+        }
+        for (name, image) in self.reused_images.iter() {
+            info!("{:width$}  keep {}, not selected", name, image);
+        }
+        Ok(built)
+    }
+
+    /// Prints which of the `built` images are pushed.
+    fn plan_pushes(&self, built: &[String]) -> Result<()> {
+        header("Push");
+        let containers = self.containers.iter().filter(|c| built.contains(&c.name));
+        let containers: Vec<_> = containers.collect();
+        if containers.is_empty() || !self.config.build_on.is_local() {
+            info!("Nothing, the images are on the servers already");
+            return Ok(());
+        }
+        for container in containers {
+            let images = self.images(container);
+            if !self.config.transport.is_registry() {
+                info!("\x1b[33mcopy\x1b[0m {} to the servers over ssh", images[0]);
+            } else if self.pushed_by_buildx(container) {
+                for image in images {
+                    info!("\x1b[33mpush\x1b[0m {} while building", image);
+                }
+            } else {
+                let mut images = images;
+                if self.skip_push(container)? {
+                    info!("{} is in the registry already", self.image(container));
+                    images.retain(|image| *image != self.image(container));
+                }
+                for image in images {
+                    info!("\x1b[33mpush\x1b[0m {}", image);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints the files that rsync would copy to or delete from the server.
+    fn plan_files(&self) -> Result<()> {
+        header(&format!("Files on {}", self.config.server));
+        let compose_txt = self.transform_docker_compose()?;
+        if self.config.deploy_mode == DeployMode::DockerContext {
+            info!(
+                "Write {} to {}",
+                DOCKER_COMPOSE_PATH,
+                self.context_dir().display()
+            );
+            return Ok(());
+        }
+        let tmp_dir = tempfile::tempdir()?;
+        let changes = self.file_changes(tmp_dir.path(), &compose_txt, &[])?;
+        if changes.is_empty() {
+            info!("No changes");
+        }
+        for (change, path) in changes {
+            match change {
+                Change::Create => info!("\x1b[32mcreate\x1b[0m {}", path),
+                Change::Update => info!("\x1b[33mupdate\x1b[0m {}", path),
+                Change::Delete => info!("\x1b[31mdelete\x1b[0m {}", path),
+            }
+        }
+        Ok(())
+    }
+
+    /// The changes a push of the files would make on the server, from a dry
+    /// run of rsync with `options`. The generated files are written to `dir`.
+    fn file_changes(
+        &self,
+        dir: &Path,
+        compose_txt: &str,
+        options: &[&str],
+    ) -> Result<Vec<(Change, String)>> {
+        let mut options = options.to_vec();
+        options.extend(["--dry-run", "--itemize-changes"]);
+        let mut rsync = self.rsync_files(dir, compose_txt, &options)?;
+        let output = self.executor.query(rsync.stderr(Stdio::piped()))?;
+        if !output.status.success() {
+            let error = format!(
+                "Failed to compare the files with {}: {}",
+                self.config.server,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return Err(failed(&rsync, output.status, error));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let changes = stdout.lines().filter_map(rsync_change);
+        Ok(changes
+            .map(|(change, path)| (change, path.to_string()))
+            .collect())
+    }
+
+    /// Prints the image tags that a deploy would change, and a unified diff
+    /// of the files on each server against the files a deploy would push.
+    pub fn diff(&mut self) -> Result<()> {
+        let servers = match self.config.servers.is_empty() {
+            true => vec![self.config.server.clone()],
+            false => self.config.servers.clone(),
+        };
+        let primary = self.config.server.clone();
+        let result = servers.iter().try_for_each(|server| {
+            self.config.server = server.clone();
+            self.diff_images()?;
+            self.diff_files()
+        });
+        self.config.server = primary;
+        result
+    }
+
+    /// Prints the services whose image would change, with the tag on the
+    /// server and the tag a deploy would use.
+    fn diff_images(&self) -> Result<()> {
+        header(&format!("Images on {}", self.config.server));
+        let text = self.compose_output(&format!("{} ps --all --format json", self.compose()?))?;
+        let running = PsEntry::parse_all(&text)?;
+        let services = self.service_images()?;
+        let width = services.iter().map(|(name, _)| name.len()).max();
+        let width = width.unwrap_or(0);
+        let mut changed = 0;
+        for (name, image) in services.iter() {
+            match running.iter().find(|c| c.service == *name) {
+                Some(c) if c.image == *image => continue,
+                Some(c) => info!(
+                    "{:width$}  {} -> \x1b[33m{}\x1b[0m",
+                    name,
+                    ps::image_tag(&c.image),
+                    ps::image_tag(image)
+                ),
+                None => info!(
+                    "{:width$}  \x1b[32mnew\x1b[0m {}",
+                    name,
+                    ps::image_tag(image)
+                ),
+            }
+            changed += 1;
+        }
+        if changed == 0 {
+            info!("No changes");
+        }
+        Ok(())
+    }
+
+    /// Prints a unified diff of each file that a push would change on the
+    /// server. Secrets are only listed.
+    fn diff_files(&self) -> Result<()> {
+        header(&format!("Files on {}", self.config.server));
+        let compose_txt = self.transform_docker_compose()?;
+        if self.config.deploy_mode == DeployMode::DockerContext {
+            let path = self.context_dir().join(DOCKER_COMPOSE_PATH);
+            let pushed = std::fs::read(&path).ok();
+            let generated = Some(compose_txt.into_bytes());
+            match pushed == generated {
+                true => info!("No changes"),
+                false => self.print_diff(&path.display().to_string(), pushed, generated)?,
+            }
+            return Ok(());
+        }
+        let tmp_dir = tempfile::tempdir()?;
+        let changes = self.file_changes(tmp_dir.path(), &compose_txt, &["--checksum"])?;
+        let secrets = self.config.secrets.iter().map(|s| s.output_name());
+        let secrets = secrets.collect::<Result<Vec<_>>>()?;
+        // A new release is compared with the current one.
+        let base = match self.config.releases {
+            true => format!("{}/", CURRENT_RELEASE),
+            false => String::new(),
+        };
+        let mut changed = 0;
+        for (change, path) in changes {
+            // The manifest and the copy of the compose file in VERSIONS_DIR
+            // always change with the version.
+            if path.ends_with('/') || path == DEPLOY_MANIFEST_PATH || path.starts_with(VERSIONS_DIR)
+            {
+                continue;
+            }
+            let remote = self.remote_file(&format!("{}{}", base, path))?;
+            let local = match change {
+                Change::Delete => None,
+                _ => match self.local_file(tmp_dir.path(), &path)? {
+                    Some(local) => Some(std::fs::read(local)?),
+                    None => None,
+                },
+            };
+            if remote == local {
+                continue;
+            }
+            changed += 1;
+            if secrets.contains(&path) {
+                info!("Secret {} differs", path);
+                continue;
+            }
+            self.print_diff(&path, remote, local)?;
+        }
+        if changed == 0 {
+            info!("No changes");
+        }
+        Ok(())
+    }
+
+    /// The contents of `path` in the project directory on the server, or
+    /// `None` if there is no such file.
+    fn remote_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let mut command = self.ssh(&format!(
+            "if [ -f {path} ]; then echo file; cat {path}; fi",
+            path = shell_quote(path)
+        ));
+        let output = self.executor.query(command.stderr(Stdio::inherit()))?;
+        if !output.status.success() {
+            let error = format!("Failed to read {} on {}", path, self.config.server);
+            return Err(failed(&command, output.status, error));
+        }
+        Ok(output.stdout.strip_prefix(b"file\n").map(|c| c.to_vec()))
+    }
+
+    /// The local file that rsync pushes to `path` in the project directory:
+    /// a file generated in `dir`, or one of the `additionalFiles`.
+    fn local_file(&self, dir: &Path, path: &str) -> Result<Option<PathBuf>> {
+        if dir.join(path).is_file() {
+            return Ok(Some(dir.join(path)));
+        }
+        for add in self.additional_files()? {
+            // rsync pushes the part after `/./` of every source.
+            let (base, relative) = match add.split_once("/./") {
+                Some(anchored) => anchored,
+                None => (".", add.trim_start_matches("./")),
+            };
+            if !Path::new(path).starts_with(relative) {
+                continue;
+            }
+            let local = Path::new(base).join(path);
+            if local.is_file() {
+                return Ok(Some(local));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Prints a unified diff from the `remote` to the `local` contents of
+    /// `path`, where `None` is a missing file.
+    fn print_diff(
+        &self,
+        path: &str,
+        remote: Option<Vec<u8>>,
+        local: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let is_text =
+            |c: &Option<Vec<u8>>| c.as_ref().is_none_or(|c| std::str::from_utf8(c).is_ok());
+        if !is_text(&remote) || !is_text(&local) {
+            info!("Binary file {} differs", path);
+            return Ok(());
+        }
+        let tmp_dir = tempfile::tempdir()?;
+        let mut diff = Command::new("diff");
+        diff.arg("-u");
+        for (name, contents) in [("remote", remote), ("local", local)] {
+            match contents {
+                Some(contents) => {
+                    let file = tmp_dir.path().join(name);
+                    std::fs::write(&file, contents)?;
+                    diff.arg(format!("--label={}/{}", name, path)).arg(file);
+                }
+                None => {
+                    diff.arg("--label=/dev/null").arg("/dev/null");
+                }
+            }
+        }
+        let output = self.executor.query(diff.stderr(Stdio::inherit()))?;
+        // diff exits with 1 when the files differ.
+        if output.status.code().is_none_or(|code| code > 1) {
+            return Err(failed(
+                &diff,
+                output.status,
+                format!("Failed to diff {}", path),
+            ));
+        }
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            match line.chars().next() {
+                _ if line.starts_with("+++") || line.starts_with("---") => {
+                    info!("\x1b[1m{}\x1b[0m", line)
+                }
+                Some('+') => info!("\x1b[32m{}\x1b[0m", line),
+                Some('-') => info!("\x1b[31m{}\x1b[0m", line),
+                Some('@') => info!("\x1b[36m{}\x1b[0m", line),
+                _ => info!("{}", line),
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints the commands that deploy the version on the server, in the
+    /// order they run.
+    fn plan_commands(&self) -> Result<()> {
+        header(&format!("Commands on {}", self.config.server));
+        let hook = |name: &str, hook: &Option<Hook>| {
+            hook.as_ref().map(|hook| match hook.is_remote() {
+                true => format!("The {} hook", name),
+                false => format!("The {} hook, locally", name),
+            })
+        };
+        let compose = self.compose()?;
+        let mut commands = vec![];
+        if self.config.releases {
+            commands.push(format!(
+                "ln -sfn {}/{} {}",
+                RELEASES_DIR, self.version, CURRENT_RELEASE
+            ));
+        }
+        commands.extend(hook("pre_deploy", &self.config.pre_deploy));
+        if self.pull {
+            commands.push(format!("{} pull", compose));
+        }
+        for migration in self.config.migrations.iter() {
+            commands.push(format!(
+                "{} run --rm -T {} {}",
+                compose,
+                shell_quote(&migration.service),
+                migration.command
+            ));
+        }
+        match self.config.strategy {
+            Strategy::Recreate => commands.push(format!("{} {}", compose, self.up())),
+            Strategy::BlueGreen => {
+                let (active, color) = self.colors()?;
+                let old = match active.is_empty() {
+                    true => self.project_name(),
+                    false => format!("{}-{}", self.project_name(), active),
+                };
+                commands.push(format!(
+                    "COMPOSE_PROJECT_NAME={}-{} {} {}",
+                    self.project_name(),
+                    color,
+                    compose,
+                    self.up()
+                ));
+                commands.extend(hook("switch_traffic", &self.config.switch_traffic));
+                commands.push(format!(
+                    "COMPOSE_PROJECT_NAME={} {} down --remove-orphans",
+                    old, compose
+                ));
+            }
+        }
+        if self.is_canary() {
+            commands.extend(hook("canary_check", &self.config.canary_check));
+        }
+        commands.extend(hook("post_deploy", &self.config.post_deploy));
+        for command in commands {
+            info!("{}", command);
+        }
+        Ok(())
+    }
+
+    /// Checks that each server has docker and compose of the minimum
+    /// versions, the remote directory and enough free space, before
+    /// anything is built or pushed. The remote directory is created when
+    /// `preflight.create_remote_path` is set.
+    pub fn preflight(&mut self) -> Result<()> {
+        let preflight = self.config.preflight.clone();
+        // docker compose runs locally with deploy_mode: docker-context.
+        if !preflight.enabled || self.config.deploy_mode == DeployMode::DockerContext {
+            return Ok(());
+        }
+        let start = Instant::now();
+        header("Checking the servers");
+        let servers = match self.config.servers.is_empty() {
+            true => vec![self.config.server.clone()],
+            false => self.config.servers.clone(),
+        };
+        let primary = self.config.server.clone();
+        let result = servers.iter().try_for_each(|server| {
+            self.config.server = server.clone();
+            self.preflight_server(&preflight)
+        });
+        self.config.server = primary;
+        result?;
+        header_elapsed("Checked the servers", &start);
+        Ok(())
+    }
+
+    fn preflight_server(&self, preflight: &PreflightConfig) -> Result<()> {
+        let server = &self.config.server;
+        let compose = self.compose()?;
+        let path = remote_shell_path(self.config.remote_path());
+        // The free space is that of the closest directory that exists.
+        let script = format!(
+            r#"echo "docker=$(docker version --format '{{{{.Server.Version}}}}' 2>/dev/null)"
+echo "compose=$({compose} version --short 2>/dev/null)"
+echo "directory=$(test -d {path} && echo yes)"
+dir={path}; while [ ! -d "$dir" ]; do dir=$(dirname "$dir"); done
+echo "free=$(df -Pk "$dir" 2>/dev/null | awk 'NR == 2 {{ print $4 }}')""#
+        );
+        let output = self
+            .executor
+            .query(self.ssh_command().arg(script).stderr(Stdio::inherit()))?;
+        if !output.status.success() {
+            bail!(
+                "Failed to connect to {}. Check it with `dep doctor`",
+                server
+            );
+        }
+        let stdout = String::from_utf8(output.stdout)?;
+        let value = |key: &str| {
+            let prefix = format!("{}=", key);
+            let line = stdout.lines().find_map(|line| line.strip_prefix(&prefix));
+            line.unwrap_or_default().trim().to_string()
+        };
+
+        // Other compose commands, such as podman-compose, may not use docker.
+        if compose.starts_with("docker") {
+            let docker = value("docker");
+            if docker.is_empty() {
+                bail!(
+                    "docker is not running on {}, or the user can't use it. Install docker, and add the user to the docker group",
+                    server
+                );
+            }
+            check_version(server, "docker", &docker, &preflight.min_docker_version)?;
+            let version = value("compose");
+            if version.is_empty() {
+                bail!(
+                    "{} is not installed on {}. Install the docker compose plugin",
+                    compose,
+                    server
+                );
+            }
+            check_version(server, &compose, &version, &preflight.min_compose_version)?;
+            info!("{}: docker {}, {} {}", server, docker, compose, version);
+        }
+
+        if value("directory").is_empty() {
+            if !preflight.create_remote_path {
+                bail!(
+                    "{} does not exist on {}. Create it, or set preflight.create_remote_path",
+                    self.config.remote_path(),
+                    server
+                );
+            }
+            let mut mkdir = self.ssh_command();
+            self.check(
+                mkdir.arg(format!("mkdir -p {}", path)),
+                format!(
+                    "Failed to create {} on {}",
+                    self.config.remote_path(),
+                    server
+                ),
+            )?;
+        }
+
+        let minimum = crate::config::parse_size(&preflight.min_free_space)?;
+        if let Ok(free) = value("free").parse::<u64>() {
+            let free = free * 1024;
+            if free < minimum {
+                bail!(
+                    "Only {} free on {} in {}, which is less than preflight.min_free_space {}. Free some space, e.g. with `docker system prune`",
+                    human_size(free),
+                    server,
+                    self.config.remote_path(),
+                    preflight.min_free_space
+                );
+            }
+            info!("{}: {} free", server, human_size(free));
+        }
+        Ok(())
+    }
+
+    /// Builds and pushes the images, unless `images` is false, and pushes
+    /// the files to the server.
+    fn push_all(&mut self, images: bool) -> Result<()> {
+        let start = Instant::now();
+        // Images that are built on the server are built on every server.
+        let build_local = self.config.build_on.is_local();
+        if images && (build_local || self.config.servers.is_empty()) {
+            self.build_images()?;
+        }
+        if images && !build_local && self.skip_unchanged && !self.config.servers.is_empty() {
+            bail!("skip_unchanged does not work with build_on: remote and several servers");
+        }
+        self.run_hook("pre_push", &self.config.pre_push)?;
+        if self.config.servers.is_empty() {
+            if images {
+                self.push_images().failure(Failure::Push)?;
+            }
+            self.push_files().failure(Failure::Push)?;
+        } else {
+            let registry = self.config.transport.is_registry();
+            if images && registry {
+                self.push_images().failure(Failure::Push)?;
+            }
+            let servers = self.config.servers.clone();
+            self.for_each_server("Pushing to", &servers, false, |ctx| {
+                if images && !build_local {
+                    ctx.build_images()?;
+                }
+                if images && !registry {
+                    ctx.push_containers().failure(Failure::Push)?;
+                }
+                ctx.push_files().failure(Failure::Push)
+            })?;
+            if images && !registry {
+                self.save_build_state()?;
+            }
+        }
+        header_elapsed("Pushed everything", &start);
+        Ok(())
+    }
+
+    /// Logs in to the registry and builds the images that are pushed.
+    fn build_images(&mut self) -> Result<()> {
+        if self.config.transport.is_registry() {
+            self.registry_login()?;
+        } else if let Some(c) = self.containers.iter().find(|c| self.platforms(c).len() > 1) {
+            bail!(
+                "{} is built for several platforms, which needs transport: registry",
+                c.name
+            );
+        }
+        self.build_all(true)
+    }
+
+    /// Pushes the built images and records what was pushed.
+    fn push_images(&mut self) -> Result<()> {
+        self.push_containers()?;
+        self.save_build_state()?;
+        if self.config.pin_digests && self.config.transport.is_registry() {
+            self.pin_digests()?;
+        }
+        Ok(())
+    }
+
+    fn push_containers(&self) -> Result<()> {
+        // Images built on the server are there already.
+        if !self.config.build_on.is_local() {
+            return Ok(());
+        }
+        if !self.config.transport.is_registry() {
+            return self.transfer_containers();
+        }
+        for container in self.containers.iter() {
+            if self.pushed_by_buildx(container) {
+                progress::set_state(&container.name, State::Pushed);
+                continue;
+            }
+            let mut images = self.images(container);
+            if self.skip_push(container)? {
+                info!(
+                    "{} is already in the registry, not pushing it again",
+                    self.image(container)
+                );
+                // The extra tags, such as latest, still move to this version.
+                images.retain(|image| *image != self.image(container));
+            }
+            if images.is_empty() {
+                progress::set_state(&container.name, State::Pushed);
+                continue;
+            }
+            header(&format!("Pushing {}", images[0]));
+            progress::set_state(&container.name, State::Pushing);
+            for image in images {
+                let mut push = self.engine();
+                if self.builds_manifest_list(container) {
+                    // The platforms are in a local manifest list named after
+                    // the image, which is pushed to every tag.
+                    push.arg("manifest")
+                        .arg("push")
+                        .arg("--all")
+                        .arg(self.image(container))
+                        .arg(format!("docker://{}", image));
+                } else {
+                    push.arg("push").arg(&image);
+                }
+                self.check_retry(
+                    &mut push,
+                    Some(&self.progress_label(container)),
+                    format!("Failed to push container {}", container.name),
+                )?;
+                self.image_pushed(container, &image)?;
+            }
+            progress::set_state(&container.name, State::Pushed);
+        }
+
+        Ok(())
+    }
+
+    /// Whether pushing the version tag of the image is skipped, because the
+    /// registry has it already. Dirty versions are always pushed, since the
+    /// same version can have different contents.
+    fn skip_push(&self, container: &DockerContainer) -> Result<bool> {
+        if self.force_push || self.version.ends_with("-dirty") {
+            return Ok(false);
+        }
+        self.is_pushed(container)
+    }
+
+    /// Whether the registry already has the version of the image.
+    fn is_pushed(&self, container: &DockerContainer) -> Result<bool> {
+        let output = self.executor.query(
+            self.engine()
+                .arg("manifest")
+                .arg("inspect")
+                .arg(self.image(container))
+                .stderr(Stdio::null()),
+        )?;
+        Ok(output.status.success())
+    }
+
+    /// Copies the images directly to the server over ssh, without a registry.
+    fn transfer_containers(&self) -> Result<()> {
+        for container in self.containers.iter() {
+            let image = self.image(container);
+            header(&format!("Transferring {} to {}", image, self.config.server));
+            progress::set_state(&container.name, State::Pushing);
+            let mut save = self.engine();
+            save.arg("save");
+            if self.config.engine == Engine::Podman {
+                // Podman only saves more than one image with --multi-image-archive.
+                save.arg("--multi-image-archive");
+            }
+            let save = command_line(save.args(self.images(container)));
+            let pipeline = match self.config.transport {
+                Transport::SshZstd => format!(
+                    "{} | zstd --stdout | {}",
+                    save,
+                    command_line(
+                        self.ssh_command()
+                            .arg("zstd --decompress --stdout | docker load")
+                    )
+                ),
+                _ => format!(
+                    "{} | {}",
+                    save,
+                    command_line(self.ssh_command().arg("docker load"))
+                ),
+            };
+            self.check_retry(
+                Command::new("bash")
+                    .arg("-o")
+                    .arg("pipefail")
+                    .arg("-c")
+                    .arg(pipeline),
+                Some(&self.progress_label(container)),
+                format!("Failed to transfer container {}", container.name),
+            )?;
+            self.image_pushed(container, &image)?;
+            progress::set_state(&container.name, State::Pushed);
+        }
+        Ok(())
+    }
+
+    /// The host part of the registry, e.g. `registry.example.org` for
+    /// `registry.example.org/team`.
+    fn registry_host(&self) -> &str {
+        self.registry.split('/').next().unwrap_or(&self.registry)
+    }
+
+    /// Logs in to the registry when `registry_auth` is configured.
+    fn registry_login(&self) -> Result<()> {
+        let Some((username, password)) = self.registry_credentials()? else {
+            return Ok(());
+        };
+        let host = self.registry_host();
+
+        header(&format!("Logging in to {} as {}", host, username));
+        let mut command = self.engine();
+        command
+            .arg("login")
+            .arg("--username")
+            .arg(&username)
+            .arg("--password-stdin")
+            .arg(host);
+        let status = self
+            .executor
+            .status_with_input(&mut command, password.as_bytes())?;
+        if !status.success() {
+            bail!(
+                "Failed to log in to {} as {}. Check the registry_auth credentials",
+                host,
+                username
+            );
+        }
+        Ok(())
+    }
+
+    /// The username and password from `registry_auth`, if configured.
+    fn registry_credentials(&self) -> Result<Option<(String, String)>> {
+        let Some(auth) = &self.config.registry_auth else {
+            return Ok(None);
+        };
+        let host = self.registry_host();
+        let credentials = match (&auth.credential_helper, &auth.password_env) {
+            (Some(helper), _) => credential_helper_get(helper, host)?,
+            (None, Some(env)) => {
+                let username = auth
+                    .username
+                    .clone()
+                    .context("registry_auth.username is required with password_env")?;
+                let password = std::env::var(env)
+                    .context(format!("Environment variable {} is not set", env))?;
+                (username, password)
+            }
+            (None, None) => bail!("registry_auth needs either password_env or credential_helper"),
+        };
+        Ok(Some(credentials))
+    }
+
+    /// Deletes all but the `keep` newest tags of each service from the
+    /// registry. Tags in `extra_tags`, and tags that share an image with a
+    /// kept tag, are kept.
+    pub fn prune_registry(&mut self, keep: usize) -> Result<()> {
+        if !self.config.transport.is_registry() || self.registry.is_empty() {
+            bail!("dep registry prune needs a registry");
+        }
+        let in_use = self.versions_in_use()?;
+        let credentials = self.registry_credentials()?;
+        for container in self.containers.iter() {
+            let name = self.image_name(&container.name);
+            header(&format!("Pruning {}", name));
+            let reference = ImageRef::parse(&self.image(container));
+            let client = registry::Client::new(&reference, credentials.clone(), "pull,delete")?;
+            let mut tags = vec![];
+            for tag in client.tags()? {
+                let (digest, created) = client.created(&tag)?;
+                tags.push((tag, digest, created));
+            }
+            // Newest first. Images without a creation date sort last.
+            tags.sort_by_key(|(_, _, created)| std::cmp::Reverse(*created));
+            let (kept, old): (Vec<_>, Vec<_>) = tags
+                .into_iter()
+                .partition(|(tag, _, _)| self.extra_tags.contains(tag) || in_use.contains(tag));
+            let mut kept: Vec<_> = kept.into_iter().map(|(_, digest, _)| digest).collect();
+            let mut deleted = vec![];
+            for (i, (tag, digest, _)) in old.into_iter().enumerate() {
+                if i < keep || kept.contains(&digest) {
+                    kept.push(digest);
+                    continue;
+                }
+                if deleted.contains(&digest) {
+                    continue;
+                }
+                match self.dry_run {
+                    true => info!("Would delete {}:{}", name, tag),
+                    false => {
+                        info!("Deleting {}:{}", name, tag);
+                        client.delete(&digest)?;
+                    }
+                }
+                deleted.push(digest);
+            }
+            if !self.dry_run {
+                info!("Deleted {} image(s) of {}", deleted.len(), container.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// The versions that the servers can run without pulling anything new:
+    /// the deployed version and, with `releases`, the pushed releases.
+    fn versions_in_use(&mut self) -> Result<Vec<String>> {
+        let servers = match self.config.servers.is_empty() {
+            true => vec![self.config.server.clone()],
+            false => self.config.servers.clone(),
+        };
+        let primary = self.config.server.clone();
+        let mut versions = vec![];
+        let result = servers.iter().try_for_each(|server| {
+            self.config.server = server.clone();
+            versions.extend(self.deployed_version()?);
+            if self.config.releases {
+                let releases =
+                    self.ssh_output(&format!("ls {} 2>/dev/null; true", RELEASES_DIR))?;
+                versions.extend(releases.lines().map(str::to_string));
+            }
+            Ok(())
+        });
+        self.config.server = primary;
+        result.map(|_| versions)
+    }
+
+    /// Creates the releases directory on the server, as rsync only creates
+    /// the last directory of the destination.
+    fn create_releases_dir(&self) -> Result<()> {
+        let mut ssh = self.ssh_command();
+        ssh.arg(format!(
+            "mkdir -p {}/{}",
+            remote_shell_path(self.config.remote_path()),
+            RELEASES_DIR
+        ));
+        if !self.run(&mut ssh)?.success() {
+            bail!(
+                "Failed to create {} on {}",
+                RELEASES_DIR,
+                self.config.server
+            );
+        }
+        Ok(())
+    }
+
+    fn remote_dir(&self) -> String {
+        format!("{}:{}", self.config.server, self.config.remote_path())
+    }
+
+    fn push_files(&self) -> Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let compose_txt = self.transform_docker_compose()?;
+        if self.dry_run {
+            header(&format!("Generated {}", DOCKER_COMPOSE_PATH));
+            info!("{}", compose_txt);
+        }
+        if self.config.deploy_mode == DeployMode::DockerContext {
+            return self.write_context_files(&compose_txt);
+        }
+        header(&format!("Copying files to {}", self.config.server));
+        if self.config.releases {
+            self.create_releases_dir()?;
+        }
+        let mut proc = self.rsync_files(
+            tmp_dir.path(),
+            &compose_txt,
+            &["--verbose", "-h", "--progress"],
+        )?;
+        self.check_retry(&mut proc, Some("rsync"), "Failed to push rsync")
+    }
+
+    /// Writes the generated compose file, [DEPLOY_MANIFEST_PATH], the
+    /// secrets and the copy of the version to `dir`, and creates the rsync
+    /// command that copies them and the `additional_files` to the server,
+    /// with `options`.
+    fn rsync_files(&self, dir: &Path, compose_txt: &str, options: &[&str]) -> Result<Command> {
+        let mut tmp_file_path = dir.to_owned();
+        tmp_file_path.push(DOCKER_COMPOSE_PATH);
+        std::fs::write(tmp_file_path, compose_txt)?;
+        let manifest = serde_json::to_string_pretty(&self.deploy_record()?)?;
+        std::fs::write(dir.join(DEPLOY_MANIFEST_PATH), manifest)?;
+
+        for secret in self.config.secrets.iter() {
+            self.decrypt_secret(secret, dir)?;
+        }
+
+        // Keep a copy of every pushed version around for rollbacks. Releases
+        // are kept around as they are.
+        if !self.config.releases {
+            let version_dir = dir.join(VERSIONS_DIR).join(&self.version);
+            std::fs::create_dir_all(&version_dir)?;
+            std::fs::write(version_dir.join(DOCKER_COMPOSE_PATH), compose_txt)?;
+        }
+
+        // Every source is anchored with `/./`, so that --relative keeps the
+        // directories of glob matches and pushes the rest by their name.
+        let tmp_dir_path = format!("{}/./", dir.display());
+        let mut all_paths: Vec<String> = vec![tmp_dir_path];
+        all_paths.extend(self.additional_files()?);
+
+        let mut proc = self.rsync();
+        proc.args(options).arg("--archive").arg("--relative");
+        if Path::new(depignore::DEPIGNORE_PATH).exists() {
+            let text = std::fs::read_to_string(depignore::DEPIGNORE_PATH)
+                .context(format!("Failed to read {}", depignore::DEPIGNORE_PATH))?;
+            for rule in depignore::rsync_filters(&text) {
+                proc.arg(format!("--filter={}", rule));
+            }
+        }
+        let rsync = &self.config.rsync;
+        for exclude in rsync.exclude.iter() {
+            proc.arg(format!("--exclude={}", exclude));
+        }
+        if rsync.delete {
+            proc.arg("--delete");
+            for state in REMOTE_STATE.iter() {
+                proc.arg(format!("--filter=P /{}", state));
+            }
+            for protect in rsync.protect.iter() {
+                proc.arg(format!("--filter=P {}", protect));
+            }
+        }
+        if let Some(bwlimit) = &rsync.bwlimit {
+            proc.arg(format!("--bwlimit={}", bwlimit));
+        }
+        if rsync.compress {
+            proc.arg("--compress");
+        }
+        let mut destination = self.remote_dir();
+        if self.config.releases {
+            // Files that did not change are hard linked from the current
+            // release instead of being copied again.
+            proc.arg(format!("--link-dest=../../{}/", CURRENT_RELEASE));
+            destination = format!("{}/{}/{}/", destination, RELEASES_DIR, self.version);
+        }
+        proc.args(all_paths).arg(destination);
+        Ok(proc)
+    }
+
+    /// Creates an rsync command that connects with the `ssh` options.
+    pub(crate) fn rsync(&self) -> Command {
+        let mut proc = Command::new("rsync");
+        let ssh_args = self.config.ssh().args();
+        if !ssh_args.is_empty() {
+            let mut ssh = Command::new("ssh");
+            ssh.args(ssh_args);
+            proc.arg("-e").arg(command_line(&ssh));
+        }
+        proc
+    }
+
+    /// Copies the build contexts and Dockerfiles to [BUILD_DIR] on the
+    /// server, with the same paths as in the project, so that the build
+    /// command can run there unchanged.
+    fn push_build_contexts(&self) -> Result<()> {
+        header(&format!("Copying build contexts to {}", self.config.server));
+        let mut mkdir = self.ssh_command();
+        mkdir.arg(format!(
+            "mkdir -p {}/{}",
+            remote_shell_path(self.config.remote_path()),
+            BUILD_DIR
+        ));
+        if !self.run(&mut mkdir)?.success() {
+            bail!("Failed to create {} on {}", BUILD_DIR, self.config.server);
+        }
+        let mut proc = self.rsync();
+        proc.arg("--archive")
+            .arg("--relative")
+            .arg("--delete")
+            .arg("--compress")
+            .arg("-h")
+            .arg("--exclude=.git");
+        for container in self.containers.iter() {
+            let paths = std::iter::once(&container.build_dir).chain(container.dockerfile.iter());
+            for path in paths {
+                let inside = Path::new(path)
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+                if !inside {
+                    bail!(
+                        "{} of {} must be inside the project to build on the server",
+                        path,
+                        container.name
+                    );
+                }
+                proc.arg(path);
+            }
+        }
+        proc.arg(format!("{}/{}/", self.remote_dir(), BUILD_DIR));
+        self.check_retry(
+            &mut proc,
+            Some("rsync"),
+            format!(
+                "Failed to copy the build contexts to {}",
+                self.config.server
+            ),
+        )
+    }
+
+    /// Writes the generated compose file to the local directory of the server
+    /// instead of pushing it, with a copy per version for rollbacks.
+    fn write_context_files(&self, compose_txt: &str) -> Result<()> {
+        let dir = self.context_dir();
+        let version_dir = dir.join(VERSIONS_DIR).join(&self.version);
+        if self.dry_run {
+            info!("Would write {}", dir.join(DOCKER_COMPOSE_PATH).display());
+            return Ok(());
+        }
+        std::fs::create_dir_all(&version_dir)
+            .context(format!("Failed to create {}", version_dir.display()))?;
+        std::fs::write(version_dir.join(DOCKER_COMPOSE_PATH), compose_txt)?;
+        std::fs::write(dir.join(DOCKER_COMPOSE_PATH), compose_txt)?;
+        Ok(())
+    }
+
+    /// The additional files to push, as sources for `rsync --relative`, with
+    /// glob patterns such as `config/*.toml` expanded. A pattern that matches
+    /// nothing is an error.
+    fn additional_files(&self) -> Result<Vec<String>> {
+        let mut files = vec![];
+        for add in self.config.additional_files.iter().flatten() {
+            let pattern = add.display().to_string();
+            if !pattern.contains(['*', '?', '[']) {
+                files.push(anchor_file(&pattern));
+                continue;
+            }
+            let mut matches = glob::glob(&pattern)
+                .context(format!("Invalid pattern in additionalFiles: {}", pattern))?
+                .collect::<Result<Vec<_>, _>>()?;
+            if matches.is_empty() {
+                bail!("No files match {} in additionalFiles", pattern);
+            }
+            matches.sort();
+            let base = glob_base(&pattern);
+            for path in matches {
+                let path = path.display().to_string();
+                let relative = path.strip_prefix(&base).unwrap_or(&path);
+                let relative = relative.trim_start_matches('/');
+                match base.as_str() {
+                    "." => files.push(format!("./{}", relative)),
+                    _ => files.push(format!("{}/./{}", base, relative)),
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Decrypts a secret into `dir`, readable only by the owner.
+    fn decrypt_secret(&self, secret: &Secret, dir: &Path) -> Result<()> {
+        let mut command = secret.decrypt_command()?;
+        let output = self.executor.output(command.stderr(Stdio::inherit()))?;
+        if !output.status.success() {
+            bail!("Failed to decrypt {}", secret.path().display());
+        }
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let path = dir.join(secret.output_name()?);
+        let mut file = options
+            .open(&path)
+            .context(format!("Failed to create {}", path.display()))?;
+        file.write_all(&output.stdout)?;
+        Ok(())
+    }
+
+    fn build(&self, container: &DockerContainer, push: bool) -> Result<()> {
+        header(&format!("Building {}", self.image(container)));
+        progress::set_state(&container.name, State::Building);
+        let start = Instant::now();
+        self.check_progress(
+            &mut self.build_command(container, push)?,
+            &self.progress_label(container),
+            "Failed to execute docker build",
+        )
+        .failure(Failure::Build)?;
+        progress::set_state(&container.name, State::Built);
+        self.image_built(container, &start);
+        Ok(())
+    }
+
+    fn image_built(&self, container: &DockerContainer, start: &Instant) {
+        output::event(
+            "image_built",
+            json!({
+                "service": container.name,
+                "image": self.image(container),
+                "seconds": start.elapsed().as_secs_f64(),
+            }),
+        );
+    }
+
+    /// Reports a pushed image with its size and digest, which are only
+    /// looked up if events are wanted.
+    fn image_pushed(&self, container: &DockerContainer, image: &str) -> Result<()> {
+        if !output::wants_events() {
+            return Ok(());
+        }
+        let size = self.executor.query(
+            self.engine()
+                .arg("image")
+                .arg("inspect")
+                .arg("--format")
+                .arg("{{.Size}}")
+                .arg(image)
+                .stderr(Stdio::null()),
+        )?;
+        let bytes: Option<u64> = String::from_utf8_lossy(&size.stdout).trim().parse().ok();
+        let digest = self.image_digest(image)?;
+        let digest = digest.as_deref().and_then(|d| d.split_once('@'));
+        output::event(
+            "image_pushed",
+            json!({
+                "service": container.name,
+                "image": image,
+                "bytes": bytes,
+                "digest": digest.map(|(_, digest)| digest),
+            }),
+        );
+        Ok(())
+    }
+
+    /// Platforms to build for. Service level platforms from docker-compose
+    /// take precedence over the global `platforms` setting.
+    fn platforms<'a>(&'a self, container: &'a DockerContainer) -> &'a [String] {
+        match container.platforms.is_empty() {
+            true => &self.config.platforms,
+            false => &container.platforms,
+        }
+    }
+
+    /// Cache settings for a service, falling back to the global settings
+    /// with `{registry}` and `{service}` filled in.
+    fn cache_settings(
+        &self,
+        service: &[String],
+        global: &[String],
+        container: &DockerContainer,
+    ) -> Vec<String> {
+        if !service.is_empty() {
+            return service.to_vec();
+        }
+        global
+            .iter()
+            .map(|c| {
+                c.replace("{registry}", &self.registry)
+                    .replace("{service}", &container.name)
+            })
+            .collect()
+    }
+
+    /// OCI labels that trace an image back to its commit, followed by
+    /// the labels from the config.
+    fn labels(&self) -> Vec<(String, String)> {
+        let mut labels = vec![];
+        if let Ok(sha) = git::sha() {
+            labels.push(("org.opencontainers.image.revision".to_string(), sha));
+        }
+        labels.push((
+            "org.opencontainers.image.created".to_string(),
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        ));
+        labels.push((
+            "org.opencontainers.image.version".to_string(),
+            self.version.clone(),
+        ));
+        for (key, value) in self.config.labels.iter() {
+            labels.push((key.clone(), value.clone()));
+        }
+        labels
+    }
+
+    /// Whether the image is pushed by `docker buildx build --push` already.
+    fn pushed_by_buildx(&self, container: &DockerContainer) -> bool {
+        self.config.engine == Engine::Docker
+            && !self.platforms(container).is_empty()
+            && self.config.transport.is_registry()
+    }
+
+    /// Whether podman builds the platforms into a local manifest list, which
+    /// is pushed with `podman manifest push`.
+    fn builds_manifest_list(&self, container: &DockerContainer) -> bool {
+        self.config.engine == Engine::Podman && self.platforms(container).len() > 1
+    }
+
+    /// A command that runs the configured container engine locally.
+    fn engine(&self) -> Command {
+        Command::new(self.engine_name())
+    }
+
+    fn engine_name(&self) -> &str {
+        match &self.config.engine_binary {
+            Some(binary) => binary,
+            None => self.config.engine.binary(),
+        }
+    }
+
+    fn build_command(&self, container: &DockerContainer, push: bool) -> Result<Command> {
+        let mut builder = self.engine();
+        let platforms = self.platforms(container);
+        if self.config.engine == Engine::Podman {
+            // Podman builds OCI images by default, which drop HEALTHCHECK.
+            builder.arg("build").arg("--format").arg("docker");
+            if !platforms.is_empty() {
+                builder.arg("--platform").arg(platforms.join(","));
+            }
+        } else if platforms.is_empty() {
+            builder.arg("build");
+        } else {
+            builder
+                .arg("buildx")
+                .arg("build")
+                .arg("--platform")
+                .arg(platforms.join(","));
+            if push && self.config.transport.is_registry() {
+                builder.arg("--push");
+            } else if platforms.len() == 1 {
+                builder.arg("--load");
+            }
+        }
+        // VERSION comes last, so that it matches the tag of the image.
+        for arg in container.args.iter() {
+            builder.arg("--build-arg").arg(arg);
+        }
+        builder
+            .arg("--build-arg")
+            .arg(format!("VERSION={}", &self.version));
+        if self.pull {
+            builder.arg("--pull");
+        }
+        for secret in container.secrets.iter() {
+            builder.arg("--secret").arg(secret);
+        }
+        for (id, secret) in self.config.build_secrets.iter() {
+            builder.arg("--secret").arg(secret.arg(id)?);
+        }
+        for ssh in container.ssh.iter().chain(self.config.build_ssh.iter()) {
+            builder.arg("--ssh").arg(ssh);
+        }
+        builder.arg(&container.build_dir);
+        if let Some(file) = &container.dockerfile {
+            builder.arg("-f").arg(file);
+        }
+        if let Some(target) = &container.target {
+            builder.arg("--target").arg(target);
+        }
+        for cache in self.cache_settings(&container.cache_from, &self.config.cache_from, container)
+        {
+            builder.arg("--cache-from").arg(cache);
+        }
+        for cache in self.cache_settings(&container.cache_to, &self.config.cache_to, container) {
+            builder.arg("--cache-to").arg(cache);
+        }
+        for (key, value) in self.labels() {
+            builder.arg("--label").arg(format!("{}={}", key, value));
+        }
+        if self.builds_manifest_list(container) {
+            builder.arg("--manifest").arg(self.image(container));
+        } else {
+            for image in self.images(container) {
+                builder.arg("-t").arg(image);
+            }
+        }
+        if !self.config.build_on.is_local() {
+            let uses_local = !container.secrets.is_empty()
+                || !container.ssh.is_empty()
+                || !self.config.build_secrets.is_empty()
+                || !self.config.build_ssh.is_empty();
+            if uses_local {
+                bail!(
+                    "{} uses build secrets or ssh, which are not available when building on the server",
+                    container.name
+                );
+            }
+            let args: Vec<_> = builder
+                .get_args()
+                .map(|a| shell_quote(&a.to_string_lossy()))
+                .collect();
+            return Ok(self.ssh(&format!("cd {} && docker {}", BUILD_DIR, args.join(" "))));
+        }
+        Ok(builder)
+    }
+
+    fn image(&self, c: &DockerContainer) -> String {
+        format!("{}:{}", self.image_name(&c.name), self.version)
+    }
+
+    /// The image with the version and each of the `extra_tags`.
+    fn images(&self, c: &DockerContainer) -> Vec<String> {
+        let mut images = vec![self.image(c)];
+        for tag in self.extra_tags.iter() {
+            images.push(format!("{}:{}", self.image_name(&c.name), tag));
+        }
+        images
+    }
+
+    fn image_name(&self, service: &str) -> String {
+        match self.registry.is_empty() {
+            true => format!("{}/{}", self.config.name, service),
+            false => format!("{}/{}", self.registry, service),
+        }
+    }
+
+    /// Identifies where the image of a service is pushed to, independent of
+    /// the version. Images copied over ssh only exist on that server.
+    fn repository(&self, c: &DockerContainer) -> String {
+        match self.config.transport.is_registry() {
+            true => self.image_name(&c.name),
+            false => format!("ssh://{}/{}", self.config.server, self.image_name(&c.name)),
+        }
+    }
+}
+
+/// The error of a command that failed, marked as an ssh failure when ssh
+/// could not connect to the server, for which ssh, and rsync through it,
+/// exit with 255.
+/// How an action ended, for the audit log.
+fn outcome<T>(result: &Result<T>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(_) if interrupt::interrupted() => "interrupted",
+        Err(_) => "failed",
+    }
+}
+
+fn failed(command: &Command, status: ExitStatus, error: impl Display) -> anyhow::Error {
+    let error = anyhow!("{}", error);
+    let program = command.get_program();
+    match status.code() == Some(255) && (program == "ssh" || program == "rsync") {
+        true => exit::mark(Failure::Ssh, error),
+        false => error,
+    }
+}
+
+/// Whether a command failed on the network or with a 5xx error of the
+/// registry, and may succeed when it is tried again. Errors such as a
+/// denied login or a missing image are not transient.
+fn is_transient(command: &Command, status: ExitStatus, output: &str) -> bool {
+    let output = output.to_lowercase();
+    let permanent = ["unauthorized", "denied", "not found", "manifest unknown"];
+    if permanent.iter().any(|p| output.contains(p)) {
+        return false;
+    }
+    // ssh exits with 255 when the connection fails, also in the pipelines
+    // of bash and as the shell of rsync. rsync has its own codes for socket
+    // and protocol errors and timeouts.
+    let codes: &[i32] = match command.get_program().to_str() {
+        Some("ssh") | Some("bash") => &[255],
+        Some("rsync") => &[10, 12, 30, 35, 255],
+        _ => &[],
+    };
+    if status.code().is_some_and(|code| codes.contains(&code)) {
+        return true;
+    }
+    let transient = [
+        "500 internal server error",
+        "502 bad gateway",
+        "503 service unavailable",
+        "504 gateway timeout",
+        "connection reset",
+        "connection refused",
+        "connection timed out",
+        "broken pipe",
+        "i/o timeout",
+        "tls handshake timeout",
+        "unexpected eof",
+        "temporary failure in name resolution",
+        "no route to host",
+        "network is unreachable",
+    ];
+    transient.iter().any(|t| output.contains(t))
+}
+
+/// The build arguments with the values that docker build reads from the
+/// environment for arguments without a value, so that changing them changes
+/// the build hash.
+fn build_args_hash(args: &[String]) -> String {
+    let resolved = args.iter().map(|arg| match arg.contains('=') {
+        true => arg.clone(),
+        false => match std::env::var(arg) {
+            Ok(value) => format!("{}={}", arg, value),
+            Err(_) => arg.clone(),
+        },
+    });
+    resolved.collect::<Vec<_>>().join("\n")
+}
+
+/// Anchors an `additionalFiles` entry for `rsync --relative`, so that it is
+/// pushed by its name: `config/app.env` as `config/./app.env`, and the
+/// contents of `public/` as `public/./`.
+fn anchor_file(path: &str) -> String {
+    if path.ends_with('/') {
+        return format!("{}./", path);
+    }
+    match path.rsplit_once('/') {
+        Some((dir, name)) => format!("{}/./{}", dir, name),
+        None => format!("./{}", path),
+    }
+}
+
+/// The directory that the matches of a glob `pattern` keep their path
+/// below: `.` for relative patterns, so that `config/**/*.env` keeps its
+/// directories, and the directory before the first wildcard for absolute
+/// patterns.
+fn glob_base(pattern: &str) -> String {
+    if !pattern.starts_with('/') {
+        return ".".to_string();
+    }
+    let wildcard = pattern.find(['*', '?', '[']).unwrap_or(pattern.len());
+    let end = pattern[..wildcard].rfind('/').unwrap_or(0);
+    pattern[..end].to_string()
+}
+
+/// Parses a line of `rsync --itemize-changes`, such as `>f.st......
+/// app.py`, into `(Change::Update, "app.py")`. Changes of only the
+/// attributes are left out.
+fn rsync_change(line: &str) -> Option<(Change, &str)> {
+    let (flags, path) = line.split_once(' ')?;
+    let path = path.trim_start();
+    if flags == "*deleting" {
+        return Some((Change::Delete, path));
+    }
+    match flags.chars().next()? {
+        '<' | '>' | 'c' | 'h' if flags.chars().skip(2).all(|c| c == '+') => {
+            Some((Change::Create, path))
+        }
+        '<' | '>' | 'c' | 'h' => Some((Change::Update, path)),
+        _ => None,
+    }
+}
+
+/// What rsync would do with a file on the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    Create,
+    Update,
+    Delete,
+}
+
+/// Fails when `version` of `tool` on the server is older than `minimum`.
+fn check_version(server: &str, tool: &str, version: &str, minimum: &str) -> Result<()> {
+    let (Some(parsed), Some(minimum_parsed)) = (
+        crate::config::parse_version(version),
+        crate::config::parse_version(minimum),
+    ) else {
+        return Ok(());
+    };
+    if parsed < minimum_parsed {
+        bail!(
+            "{} on {} is version {}, which is older than {}. Upgrade it, or lower the minimum in preflight",
+            tool,
+            server,
+            version,
+            minimum
+        );
+    }
+    Ok(())
+}
+
+/// Formats bytes like `1.5 GB`.
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+/// Why a command on the server failed.
+#[derive(Debug)]
+enum RemoteError {
+    /// ssh failed to connect or log in.
+    Ssh(String),
+    /// The command failed on the server.
+    Docker(String),
+}
+
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteError::Ssh(e) => write!(f, "Failed to connect: {}", e),
+            RemoteError::Docker(e) => write!(f, "docker failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+/// Reads the username and password for `host` from a docker credential helper.
+fn credential_helper_get(helper: &str, host: &str) -> Result<(String, String)> {
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Credentials {
+        username: String,
+        secret: String,
+    }
+
+    let program = format!("docker-credential-{}", helper);
+    let mut process = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to run {}", program))?;
+    let stdin = process.stdin.as_mut().context("No stdin")?;
+    stdin.write_all(host.as_bytes())?;
+    let output = process.wait_with_output()?;
+    if !output.status.success() {
+        bail!("Failed to get credentials for {} from {}", host, program);
+    }
+    let credentials: Credentials = serde_json::from_slice(&output.stdout)
+        .context(format!("Failed to parse credentials from {}", program))?;
+    Ok((credentials.username, credentials.secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dockerfile::DockerFile;
+    use crate::executor::Capture;
+
+    /// A context for the `config` and `compose` files, whose commands are
+    /// recorded by the returned [Capture].
+    fn context(config: &str, compose: &str) -> (BuildContext, Arc<Capture>) {
+        let mut config = crate::config::parse(config).unwrap();
+        config.validate().unwrap();
+        let compose: DockerFile = serde_yaml::from_str(compose).unwrap();
+        let containers = DockerContainer::from_docker_file(compose).unwrap();
+        let mut context = BuildContext::new("1.0".into(), config, false, None, false, containers);
+        let capture = Arc::new(Capture::default());
+        context.set_executor(capture.clone());
+        (context, capture)
+    }
+
+    /// The command line of building the only service.
+    fn build(context: &BuildContext, capture: &Capture) -> String {
+        context.build(&context.containers[0], true).unwrap();
+        capture.commands().pop().unwrap()
+    }
+
+    const CONFIG: &str = "name: shop\nserver: example.org\nregistry: registry.example.org\n";
+
+    #[test]
+    fn build_flags() {
+        let compose = "services:
+  web:
+    build:
+      context: ./web
+      dockerfile: web/Dockerfile.prod
+      target: production
+      args:
+        NODE_ENV: production
+        WORKERS: 4
+";
+        let (context, capture) = context(CONFIG, compose);
+        let command = build(&context, &capture);
+        let expected = concat!(
+            "docker build --build-arg NODE_ENV=production --build-arg WORKERS=4 ",
+            "--build-arg VERSION=1.0 ./web -f web/Dockerfile.prod --target production ",
+        );
+        assert!(command.starts_with(expected), "{}", command);
+        assert!(
+            command.ends_with(" -t registry.example.org/web:1.0"),
+            "{}",
+            command
+        );
+        assert!(command.contains(" --label org.opencontainers.image.version=1.0 "));
+    }
+
+    #[test]
+    fn build_flags_without_a_target() {
+        let (context, capture) = context(CONFIG, "services:\n  web:\n    build: ./web\n");
+        let command = build(&context, &capture);
+        assert!(
+            command.starts_with("docker build --build-arg VERSION=1.0 ./web --label"),
+            "{}",
+            command
+        );
+        assert!(!command.contains("--target"));
+    }
+
+    #[test]
+    fn build_flags_with_platforms_push_with_buildx() {
+        let config = format!("{}platforms: [linux/amd64, linux/arm64]\n", CONFIG);
+        let (context, capture) = context(&config, "services:\n  web:\n    build: ./web\n");
+        let command = build(&context, &capture);
+        assert!(
+            command.starts_with("docker buildx build --platform linux/amd64,linux/arm64 --push "),
+            "{}",
+            command
+        );
+    }
+
+    #[test]
+    fn build_flags_with_extra_tags_and_cache() {
+        let config = format!(
+            "{}cache_from: [\"type=registry,ref={{registry}}/{{service}}:cache\"]\n",
+            CONFIG
+        );
+        let (mut context, capture) = context(&config, "services:\n  web:\n    build: ./web\n");
+        context.extra_tags = vec!["latest".into()];
+        let command = build(&context, &capture);
+        assert!(
+            command.contains(" --cache-from type=registry,ref=registry.example.org/web:cache "),
+            "{}",
+            command
+        );
+        assert!(
+            command
+                .ends_with(" -t registry.example.org/web:1.0 -t registry.example.org/web:latest"),
+            "{}",
+            command
+        );
+    }
+
+    #[test]
+    fn build_flags_on_the_server() {
+        let config = format!("{}build_on: remote\nssh:\n  port: 2222\n", CONFIG);
+        let (context, capture) = context(&config, "services:\n  web:\n    build: ./web\n");
+        let command = build(&context, &capture);
+        assert!(
+            command.starts_with("ssh -p 2222 example.org 'cd "),
+            "{}",
+            command
+        );
+        assert!(
+            command.contains("&& cd .dep-build && docker build "),
+            "{}",
+            command
+        );
+    }
+
+    #[test]
+    fn pushing_a_version_in_the_registry_pushes_only_the_extra_tags() {
+        let (mut context, capture) = context(CONFIG, "services:\n  web:\n    build: ./web\n");
+        context.extra_tags = vec!["latest".into()];
+        context.push_containers().unwrap();
+        let pushes: Vec<_> = capture
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("docker push"))
+            .collect();
+        assert_eq!(pushes, ["docker push registry.example.org/web:latest"]);
+    }
+
+    #[test]
+    fn dirty_versions_are_always_pushed() {
+        let (mut context, capture) = context(CONFIG, "services:\n  web:\n    build: ./web\n");
+        context.version = "1.0-dirty".into();
+        context.push_containers().unwrap();
+        let commands = capture.commands();
+        assert!(!commands.iter().any(|c| c.contains("manifest inspect")));
+        assert!(commands.contains(&"docker push registry.example.org/web:1.0-dirty".to_string()));
+    }
+
+    #[test]
+    fn versions_in_use_include_the_deployed_version_and_releases() {
+        let config = format!("{}releases: true\n", CONFIG);
+        let (mut context, capture) = context(&config, "services:\n  web:\n    build: ./web\n");
+        capture.respond("/deployed", "1.0\n");
+        capture.respond("ls releases", "0.8\n0.9\n1.0\n");
+        let versions = context.versions_in_use().unwrap();
+        assert_eq!(versions, ["1.0", "0.8", "0.9", "1.0"]);
+    }
+
+    #[test]
+    fn failed_migrations_switch_back_to_the_previous_release() {
+        let config = format!(
+            "{}releases: true\nmigrations:\n  - service: web\n    command: migrate\n",
+            CONFIG
+        );
+        let (context, capture) = context(&config, "services:\n  web:\n    build: ./web\n");
+        capture.respond("/deployed", "0.9\n");
+        capture.fail("run --rm -T web migrate");
+        assert!(context.deploy_pushed().is_err());
+        let switches: Vec<_> = capture
+            .commands()
+            .into_iter()
+            .filter(|c| c.contains("ln -sfn"))
+            .collect();
+        assert_eq!(switches.len(), 2);
+        assert!(switches[0].contains("ln -sfn releases/1.0 "));
+        assert!(switches[1].contains("ln -sfn releases/0.9 "));
+        assert!(!capture.commands().iter().any(|c| c.contains(" up ")));
+    }
+
+    #[test]
+    fn docker_context_deploys_do_not_use_ssh() {
+        let config = format!("{}deploy_mode: docker-context\nretention: 2\n", CONFIG);
+        let (mut context, capture) = context(&config, "services:\n  web:\n    image: nginx\n");
+        // Dry runs keep the state in .dep/servers out of the working tree.
+        context.dry_run = true;
+        context.deploy_pushed().unwrap();
+        let commands = capture.commands();
+        assert!(commands.iter().any(|c| c.contains("docker compose")));
+        assert!(
+            !commands.iter().any(|c| c.starts_with("ssh")),
+            "{:?}",
+            commands
+        );
+    }
+
+    #[test]
+    fn servers_after_a_failed_rolling_deploy_are_skipped() {
+        let (mut context, _) = context(CONFIG, "services:\n  web:\n    image: nginx\n");
+        let servers = ["a".to_string(), "b".to_string()];
+        let result = context.for_each_server("Deploying to", &servers, true, |ctx| {
+            match ctx.config.server.as_str() {
+                "a" => bail!("Failed"),
+                _ => Ok(()),
+            }
+        });
+        assert!(result.is_err());
+        assert_eq!(context.server_outcomes["a"], "failed");
+        assert_eq!(context.server_outcomes["b"], "skipped");
+    }
+
+    #[test]
+    fn rsync_args() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("conf/prod")).unwrap();
+        std::fs::write(dir.path().join("conf/prod/app.env"), "").unwrap();
+        let config = format!(
+            "{}additionalFiles:\n  - config/app.env\n  - public/\n  - {}/conf/**/*.env\n",
+            "name: shop\nserver: deploy@example.org\nssh:\n  port: 2222\n",
+            dir.path().display()
+        );
+        let (context, _) = context(&config, "services:\n  web:\n    image: nginx\n");
+        let tmp = tempfile::tempdir().unwrap();
+        let command = context.rsync_files(tmp.path(), "", &["-h"]).unwrap();
+        let expected = format!(
+            "rsync -e 'ssh -p 2222' -h --archive --relative {}/./ config/./app.env public/./ {}/conf/./prod/app.env deploy@example.org:shop",
+            tmp.path().display(),
+            dir.path().display()
+        );
+        assert_eq!(command_line(&command), expected);
+        assert!(tmp.path().join(DOCKER_COMPOSE_PATH).exists());
+    }
+
+    #[test]
+    fn rsync_args_with_releases_and_options() {
+        let config = format!(
+            "{}releases: true\nrsync:\n  exclude: [\"*.log\"]\n  delete: true\n  compress: true\n",
+            CONFIG
+        );
+        let (context, _) = context(&config, "services:\n  web:\n    image: nginx\n");
+        let tmp = tempfile::tempdir().unwrap();
+        let command = command_line(&context.rsync_files(tmp.path(), "", &[]).unwrap());
+        assert!(
+            command.starts_with("rsync --archive --relative '--exclude=*.log' --delete "),
+            "{}",
+            command
+        );
+        assert!(command.contains(" '--filter=P /.dep-versions' "));
+        assert!(command.contains(" --compress --link-dest=../../current/ "));
+        assert!(
+            command.ends_with(" example.org:shop/releases/1.0/"),
+            "{}",
+            command
+        );
+    }
+
+    #[test]
+    fn anchored_files() {
+        assert_eq!(anchor_file("config/app.env"), "config/./app.env");
+        assert_eq!(anchor_file("public/"), "public/./");
+        assert_eq!(anchor_file("app.env"), "./app.env");
+        assert_eq!(glob_base("config/**/*.env"), ".");
+        assert_eq!(glob_base("/etc/shop/*.env"), "/etc/shop");
+    }
+
+    #[test]
+    fn compose_up_and_ssh_args() {
+        let config = "name: shop
+server: deploy@example.org
+ssh:
+  port: 2222
+  identity_file: /home/deploy/.ssh/id_ed25519
+  proxy_jump: bastion.example.org
+  extra_args: [-o, StrictHostKeyChecking=accept-new]
+";
+        let (context, capture) = context(config, "services:\n  web:\n    image: nginx\n");
+        capture.respond("docker compose version", "docker compose\n");
+        context.compose_up().unwrap();
+        let command = capture.commands().pop().unwrap();
+        let expected = concat!(
+            "ssh -p 2222 -i /home/deploy/.ssh/id_ed25519 -J bastion.example.org ",
+            "-o StrictHostKeyChecking=accept-new deploy@example.org ",
+        );
+        assert!(command.starts_with(expected), "{}", command);
+        assert!(
+            command.ends_with(" && docker compose up -d'"),
+            "{}",
+            command
+        );
+    }
+}
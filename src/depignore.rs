@@ -0,0 +1,30 @@
+/// File with gitignore style patterns for paths that are never pushed.
+pub const DEPIGNORE_PATH: &str = ".depignore";
+
+/// Translates gitignore style patterns into rsync filter rules.
+///
+/// The last matching pattern wins in gitignore, while the first matching
+/// rule wins in rsync, so the rules are returned in reverse order. Patterns
+/// with a slash in the middle are anchored to the root of the transfer, as
+/// they are anchored to the directory of the ignore file in gitignore.
+pub fn rsync_filters(text: &str) -> Vec<String> {
+    let mut rules = vec![];
+    for line in text.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (rule, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => ('+', pattern),
+            None => ('-', line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let anchored = pattern.trim_end_matches('/').contains('/') && !pattern.starts_with('/');
+        let pattern = match anchored {
+            true => format!("/{}", pattern),
+            false => pattern.to_string(),
+        };
+        rules.push(format!("{} {}", rule, pattern));
+    }
+    rules.reverse();
+    rules
+}
@@ -1,10 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use anyhow::{Context, Result};
 use serde::Deserialize;
 
+use crate::config::BuildSecret;
+
 #[derive(Deserialize, Debug)]
 pub struct DockerFile {
     pub services: HashMap<String, DockerService>,
+    #[serde(default)]
+    pub secrets: HashMap<String, BuildSecret>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -24,6 +29,52 @@ pub struct DockerBuildAdvanced {
     pub context: String,
     pub dockerfile: Option<String>,
     pub target: Option<String>,
+    pub args: Option<BuildArgs>,
+    pub platforms: Option<Vec<String>>,
+    pub cache_from: Option<Vec<String>>,
+    pub cache_to: Option<Vec<String>>,
+    pub secrets: Option<Vec<ServiceSecret>>,
+    pub ssh: Option<Vec<String>>,
+}
+
+/// A reference to a top level secret, either by name or with a different id.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ServiceSecret {
+    Name(String),
+    Advanced {
+        source: String,
+        target: Option<String>,
+    },
+}
+
+/// `build.args`, either `KEY: value` or a list of `KEY=value`. An argument
+/// without a value is read from the environment by docker build.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum BuildArgs {
+    List(Vec<String>),
+    Map(BTreeMap<String, Option<serde_yaml::Value>>),
+}
+
+impl BuildArgs {
+    /// Values for `docker build --build-arg`.
+    fn build_args(self) -> Vec<String> {
+        match self {
+            BuildArgs::List(args) => args,
+            BuildArgs::Map(args) => args
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Some(serde_yaml::Value::String(value)) => format!("{}={}", key, value),
+                    Some(serde_yaml::Value::Null) | None => key,
+                    Some(value) => {
+                        let value = serde_yaml::to_string(&value).unwrap_or_default();
+                        format!("{}={}", key, value.trim_end())
+                    }
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -32,10 +83,19 @@ pub struct DockerContainer {
     pub build_dir: String,
     pub dockerfile: Option<String>,
     pub target: Option<String>,
+    /// Values for `docker build --build-arg`.
+    pub args: Vec<String>,
+    pub platforms: Vec<String>,
+    pub cache_from: Vec<String>,
+    pub cache_to: Vec<String>,
+    /// Values for `docker build --secret`.
+    pub secrets: Vec<String>,
+    /// Values for `docker build --ssh`.
+    pub ssh: Vec<String>,
 }
 
 impl DockerContainer {
-    pub fn from_docker_file(file: DockerFile) -> Vec<DockerContainer> {
+    pub fn from_docker_file(file: DockerFile) -> Result<Vec<DockerContainer>> {
         let mut output = vec![];
         for (service_name, service) in file.services.into_iter() {
             if let Some(build_dir) = service.build {
@@ -45,18 +105,44 @@ impl DockerContainer {
                         build_dir: s,
                         dockerfile: None,
                         target: None,
+                        args: vec![],
+                        platforms: vec![],
+                        cache_from: vec![],
+                        cache_to: vec![],
+                        secrets: vec![],
+                        ssh: vec![],
                     })
                 } else if let DockerBuild::Advanced(adv) = build_dir {
+                    let mut secrets = vec![];
+                    for secret in adv.secrets.unwrap_or_default() {
+                        let (source, id) = match &secret {
+                            ServiceSecret::Name(name) => (name, name),
+                            ServiceSecret::Advanced { source, target } => {
+                                (source, target.as_ref().unwrap_or(source))
+                            }
+                        };
+                        let secret = file.secrets.get(source).context(format!(
+                            "Service {} uses undefined secret {}",
+                            service_name, source
+                        ))?;
+                        secrets.push(secret.arg(id)?);
+                    }
                     output.push(DockerContainer {
                         name: service_name,
                         build_dir: adv.context,
                         dockerfile: adv.dockerfile,
                         target: adv.target,
+                        args: adv.args.map(BuildArgs::build_args).unwrap_or_default(),
+                        platforms: adv.platforms.unwrap_or_default(),
+                        cache_from: adv.cache_from.unwrap_or_default(),
+                        cache_to: adv.cache_to.unwrap_or_default(),
+                        secrets,
+                        ssh: adv.ssh.unwrap_or_default(),
                     })
                 }
             }
         }
         output.sort_by_key(|k| k.name.clone());
-        output
+        Ok(output)
     }
 }
@@ -0,0 +1,626 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::{debug, info, trace};
+
+use crate::config::TimeoutConfig;
+use crate::exit::{self, Failure};
+use crate::progress::Progress;
+use crate::{command_line, display_command, interrupt, output, progress, runlog};
+
+/// Runs the docker, ssh, rsync and bash commands of a
+/// [BuildContext](crate::BuildContext).
+///
+/// Commands that change something go through [Executor::status] and its
+/// variants, and are only printed during dry runs. Commands that only read
+/// something go through [Executor::query], and also run during dry runs.
+pub trait Executor: std::fmt::Debug + Send + Sync {
+    /// Runs a command and returns its exit status.
+    fn status(&self, command: &mut Command) -> Result<ExitStatus>;
+
+    /// Runs a command with `input` written to its stdin.
+    fn status_with_input(&self, command: &mut Command, input: &[u8]) -> Result<ExitStatus>;
+
+    /// Runs a command with every line of its output prefixed with `prefix`,
+    /// so that concurrent commands can be told apart.
+    fn status_prefixed(&self, command: &mut Command, prefix: &str) -> Result<ExitStatus>;
+
+    /// Runs a command that takes a while, such as a build or a push. On a
+    /// terminal, its output is replaced by a progress line labelled `label`.
+    fn status_progress(&self, command: &mut Command, label: &str) -> Result<ExitStatus>;
+
+    /// Runs a command like [Executor::status_progress] with a `label`, or
+    /// like [Executor::status] without one, and also returns the end of its
+    /// output, for telling why it failed.
+    fn status_tail(
+        &self,
+        command: &mut Command,
+        label: Option<&str>,
+    ) -> Result<(ExitStatus, String)>;
+
+    /// Runs a command that changes something, and returns its output.
+    fn output(&self, command: &mut Command) -> Result<Output>;
+
+    /// Runs a command that only reads something, and returns its output.
+    fn query(&self, command: &mut Command) -> Result<Output>;
+
+    /// Starts a command in the background, such as following logs. Returns
+    /// `None` when the executor does not run commands.
+    fn spawn(&self, command: &mut Command) -> Result<Option<Child>>;
+}
+
+/// Runs the commands, and kills ssh, rsync and pushes that run longer than
+/// their timeout.
+#[derive(Debug, Default)]
+pub struct System {
+    timeouts: TimeoutConfig,
+}
+
+impl System {
+    pub fn new(timeouts: TimeoutConfig) -> Self {
+        System { timeouts }
+    }
+
+    /// How long a command may run, by its program.
+    fn timeout(&self, command: &Command) -> Option<Timeout> {
+        let program = program(command);
+        let name = Path::new(&program).file_name().unwrap_or_default();
+        let args: Vec<_> = command.get_args().take(2).collect();
+        let (key, seconds) = match name.to_str() {
+            Some("ssh") => ("ssh", self.timeouts.ssh),
+            Some("rsync") => ("rsync", self.timeouts.rsync),
+            _ if args.first().is_some_and(|a| *a == "push") => ("push", self.timeouts.push),
+            _ if args == ["manifest", "push"] => ("push", self.timeouts.push),
+            _ => return None,
+        };
+        (seconds > 0).then(|| Timeout {
+            program,
+            key,
+            duration: Duration::from_secs(seconds),
+        })
+    }
+}
+
+impl Executor for System {
+    fn status(&self, command: &mut Command) -> Result<ExitStatus> {
+        if progress::dashboard() {
+            return self.status_progress(command, &program(command));
+        }
+        let start = Instant::now();
+        started(command)?;
+        let timeout = self.timeout(command);
+        let status = match runlog::is_active() {
+            true => tee(spawn_piped(command)?, None, timeout.as_ref())?,
+            false => {
+                let mut process = redirect(command)?
+                    .spawn()
+                    .context(format!("Failed to run {}", program(command)))?;
+                wait(&mut process, timeout.as_ref())?
+            }
+        };
+        finished(&status, &start);
+        Ok(status)
+    }
+
+    fn status_with_input(&self, command: &mut Command, input: &[u8]) -> Result<ExitStatus> {
+        let dashboard = progress::dashboard();
+        if dashboard || runlog::is_active() {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let start = Instant::now();
+        started(command)?;
+        let mut process = redirect(command)?
+            .stdin(Stdio::piped())
+            .spawn()
+            .context(format!("Failed to run {}", program(command)))?;
+        let mut stdin = process.stdin.take().context("No stdin")?;
+        stdin.write_all(input)?;
+        drop(stdin);
+        let timeout = self.timeout(command);
+        let status = match (dashboard, runlog::is_active()) {
+            (true, _) => watch(process, &program(command), None, timeout.as_ref())?,
+            (false, true) => tee(process, None, timeout.as_ref())?,
+            (false, false) => wait(&mut process, timeout.as_ref())?,
+        };
+        finished(&status, &start);
+        Ok(status)
+    }
+
+    fn status_prefixed(&self, command: &mut Command, prefix: &str) -> Result<ExitStatus> {
+        if progress::enabled() {
+            return self.status_progress(command, prefix);
+        }
+        let start = Instant::now();
+        started(command)?;
+        let mut process = spawn_piped(command)?;
+        let stdout = process.stdout.take().context("No stdout")?;
+        let stderr = process.stderr.take().context("No stderr")?;
+        let timeout = self.timeout(command);
+        let status = std::thread::scope(|scope| {
+            scope.spawn(|| print_prefixed(stdout, prefix));
+            scope.spawn(|| print_prefixed(stderr, prefix));
+            wait(&mut process, timeout.as_ref())
+        })?;
+        finished(&status, &start);
+        Ok(status)
+    }
+
+    fn status_progress(&self, command: &mut Command, label: &str) -> Result<ExitStatus> {
+        if !progress::enabled() {
+            return self.status(command);
+        }
+        let start = Instant::now();
+        started(command)?;
+        let timeout = self.timeout(command);
+        let status = watch(spawn_piped(command)?, label, None, timeout.as_ref())?;
+        finished(&status, &start);
+        Ok(status)
+    }
+
+    fn status_tail(
+        &self,
+        command: &mut Command,
+        label: Option<&str>,
+    ) -> Result<(ExitStatus, String)> {
+        let label = match label {
+            Some(label) if progress::enabled() => Some(label.to_string()),
+            _ if progress::dashboard() => Some(program(command)),
+            _ => None,
+        };
+        let start = Instant::now();
+        started(command)?;
+        let tail = Tail::default();
+        let timeout = self.timeout(command);
+        let process = spawn_piped(command)?;
+        let status = match label {
+            Some(label) => watch(process, &label, Some(&tail), timeout.as_ref())?,
+            None => tee(process, Some(&tail), timeout.as_ref())?,
+        };
+        finished(&status, &start);
+        Ok((status, tail.text()))
+    }
+
+    fn output(&self, command: &mut Command) -> Result<Output> {
+        let start = Instant::now();
+        started(command)?;
+        let output = match self.timeout(command) {
+            Some(timeout) => output_within(command, &timeout)?,
+            None => command
+                .output()
+                .context(format!("Failed to run {}", program(command)))?,
+        };
+        // Stdout is read by dep, and may be a decrypted secret.
+        runlog::write(&output.stderr);
+        if !output.stderr.is_empty() {
+            trace!("{}", String::from_utf8_lossy(&output.stderr).trim_end());
+        }
+        finished(&output.status, &start);
+        Ok(output)
+    }
+
+    fn query(&self, command: &mut Command) -> Result<Output> {
+        self.output(command)
+    }
+
+    fn spawn(&self, command: &mut Command) -> Result<Option<Child>> {
+        started(command)?;
+        let child = redirect(command)?
+            .spawn()
+            .context(format!("Failed to run {}", program(command)))?;
+        Ok(Some(child))
+    }
+}
+
+/// Prints the commands that change something instead of running them.
+#[derive(Debug, Default)]
+pub struct DryRun {
+    system: System,
+}
+
+impl DryRun {
+    /// Runs the queries with `timeouts`.
+    pub fn new(timeouts: TimeoutConfig) -> Self {
+        DryRun {
+            system: System::new(timeouts),
+        }
+    }
+}
+
+impl Executor for DryRun {
+    fn status(&self, command: &mut Command) -> Result<ExitStatus> {
+        info!("{}", display_command(command));
+        Ok(ExitStatus::default())
+    }
+
+    fn status_with_input(&self, command: &mut Command, _input: &[u8]) -> Result<ExitStatus> {
+        self.status(command)
+    }
+
+    fn status_prefixed(&self, command: &mut Command, _prefix: &str) -> Result<ExitStatus> {
+        self.status(command)
+    }
+
+    fn status_progress(&self, command: &mut Command, _label: &str) -> Result<ExitStatus> {
+        self.status(command)
+    }
+
+    fn status_tail(
+        &self,
+        command: &mut Command,
+        _label: Option<&str>,
+    ) -> Result<(ExitStatus, String)> {
+        Ok((self.status(command)?, String::new()))
+    }
+
+    fn output(&self, command: &mut Command) -> Result<Output> {
+        Ok(success(self.status(command)?, vec![]))
+    }
+
+    fn query(&self, command: &mut Command) -> Result<Output> {
+        self.system.query(command)
+    }
+
+    fn spawn(&self, command: &mut Command) -> Result<Option<Child>> {
+        self.status(command)?;
+        Ok(None)
+    }
+}
+
+/// Records the commands instead of running them, for testing code that
+/// uses a [BuildContext](crate::BuildContext). Commands succeed unless set
+/// with [Capture::fail], and queries print what was set with
+/// [Capture::respond].
+#[derive(Debug, Default)]
+pub struct Capture {
+    commands: Mutex<Vec<String>>,
+    responses: Mutex<Vec<(String, String)>>,
+    failures: Mutex<Vec<String>>,
+}
+
+impl Capture {
+    /// Makes queries whose command line contains `pattern` print `stdout`.
+    /// The first matching response is used.
+    pub fn respond(&self, pattern: &str, stdout: &str) {
+        let mut responses = self.responses.lock().unwrap();
+        responses.push((pattern.to_string(), stdout.to_string()));
+    }
+
+    /// Makes commands whose command line contains `pattern` exit with 1.
+    pub fn fail(&self, pattern: &str) {
+        self.failures.lock().unwrap().push(pattern.to_string());
+    }
+
+    /// The command lines of the commands that were run, in order.
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    fn record(&self, command: &Command) -> String {
+        let line = command_line(command);
+        self.commands.lock().unwrap().push(line.clone());
+        line
+    }
+
+    fn exit_status(&self, line: &str) -> ExitStatus {
+        let failures = self.failures.lock().unwrap();
+        match failures
+            .iter()
+            .any(|pattern| line.contains(pattern.as_str()))
+        {
+            true => exit_code(1),
+            false => ExitStatus::default(),
+        }
+    }
+}
+
+fn exit_code(code: i32) -> ExitStatus {
+    #[cfg(unix)]
+    return std::os::unix::process::ExitStatusExt::from_raw(code << 8);
+    #[cfg(windows)]
+    return std::os::windows::process::ExitStatusExt::from_raw(code as u32);
+}
+
+impl Executor for Capture {
+    fn status(&self, command: &mut Command) -> Result<ExitStatus> {
+        let line = self.record(command);
+        Ok(self.exit_status(&line))
+    }
+
+    fn status_with_input(&self, command: &mut Command, _input: &[u8]) -> Result<ExitStatus> {
+        self.status(command)
+    }
+
+    fn status_prefixed(&self, command: &mut Command, _prefix: &str) -> Result<ExitStatus> {
+        self.status(command)
+    }
+
+    fn status_progress(&self, command: &mut Command, _label: &str) -> Result<ExitStatus> {
+        self.status(command)
+    }
+
+    fn status_tail(
+        &self,
+        command: &mut Command,
+        _label: Option<&str>,
+    ) -> Result<(ExitStatus, String)> {
+        Ok((self.status(command)?, String::new()))
+    }
+
+    fn output(&self, command: &mut Command) -> Result<Output> {
+        self.query(command)
+    }
+
+    fn query(&self, command: &mut Command) -> Result<Output> {
+        let line = self.record(command);
+        let responses = self.responses.lock().unwrap();
+        let stdout = responses
+            .iter()
+            .find(|(pattern, _)| line.contains(pattern.as_str()))
+            .map(|(_, stdout)| stdout.clone());
+        Ok(success(
+            self.exit_status(&line),
+            stdout.unwrap_or_default().into_bytes(),
+        ))
+    }
+
+    fn spawn(&self, command: &mut Command) -> Result<Option<Child>> {
+        self.record(command);
+        Ok(None)
+    }
+}
+
+fn success(status: ExitStatus, stdout: Vec<u8>) -> Output {
+    Output {
+        status,
+        stdout,
+        stderr: vec![],
+    }
+}
+
+/// With JSON output, the output of commands goes to stderr, so that stdout
+/// only has events.
+fn redirect(command: &mut Command) -> Result<&mut Command> {
+    #[cfg(unix)]
+    if output::is_json() {
+        use std::os::fd::AsFd;
+        command.stdout(std::io::stderr().as_fd().try_clone_to_owned()?);
+    }
+    Ok(command)
+}
+
+/// Logs that a command is started, and prints it with `-v`. Fails instead
+/// when dep was interrupted.
+fn started(command: &Command) -> Result<()> {
+    interrupt::check()?;
+    runlog::started(command);
+    debug!("$ {}", command_line(command));
+    Ok(())
+}
+
+/// Logs how a command that was started at `start` exited, and prints it
+/// with `-vv`.
+fn finished(status: &ExitStatus, start: &Instant) {
+    runlog::finished(status, start);
+    trace!(
+        "{} after {:.2} seconds",
+        status,
+        start.elapsed().as_secs_f64()
+    );
+}
+
+/// Starts a command with its stdout and stderr piped.
+fn spawn_piped(command: &mut Command) -> Result<Child> {
+    command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to run {}", program(command)))
+}
+
+/// Waits for a process whose stdout and stderr are piped, copying its
+/// output to ours, to the log and to `tail`.
+fn tee(mut process: Child, tail: Option<&Tail>, timeout: Option<&Timeout>) -> Result<ExitStatus> {
+    let stdout = process.stdout.take().context("No stdout")?;
+    let stderr = process.stderr.take().context("No stderr")?;
+    std::thread::scope(|scope| {
+        scope.spawn(|| copy_output(stdout, output::is_json(), tail));
+        scope.spawn(|| copy_output(stderr, true, tail));
+        wait(&mut process, timeout)
+    })
+}
+
+fn copy_output(mut output: impl Read, to_stderr: bool, tail: Option<&Tail>) {
+    let mut buffer = [0; 8192];
+    while let Ok(n) = output.read(&mut buffer) {
+        if n == 0 {
+            break;
+        }
+        runlog::write(&buffer[..n]);
+        if let Some(tail) = tail {
+            tail.push(&buffer[..n]);
+        }
+        let _ = match to_stderr {
+            true => std::io::stderr().write_all(&buffer[..n]),
+            false => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&buffer[..n]).and_then(|_| stdout.flush())
+            }
+        };
+    }
+}
+
+/// How long a command may run, and the key of `timeouts` that sets it.
+struct Timeout {
+    program: String,
+    key: &'static str,
+    duration: Duration,
+}
+
+/// Waits for a process, and kills it when it runs longer than `timeout` or
+/// when dep is interrupted.
+fn wait(process: &mut Child, timeout: Option<&Timeout>) -> Result<ExitStatus> {
+    if timeout.is_none() && !interrupt::installed() {
+        return Ok(process.wait()?);
+    }
+    let start = Instant::now();
+    let signals = interrupt::signals();
+    loop {
+        if let Some(status) = process.try_wait()? {
+            if interrupt::signals() != signals {
+                bail!("Interrupted");
+            }
+            return Ok(status);
+        }
+        if interrupt::signals() != signals {
+            let _ = process.kill();
+            process.wait()?;
+            bail!("Interrupted");
+        }
+        let Some(timeout) = timeout else {
+            std::thread::sleep(Duration::from_millis(20));
+            continue;
+        };
+        if start.elapsed() >= timeout.duration {
+            let _ = process.kill();
+            process.wait()?;
+            let error = anyhow!(
+                "Killed {} after {} seconds. The limit is set by timeouts.{}",
+                timeout.program,
+                timeout.duration.as_secs(),
+                timeout.key
+            );
+            // A server that stops answering is as good as unreachable.
+            return Err(match timeout.key {
+                "ssh" => exit::mark(Failure::Ssh, error),
+                _ => error,
+            });
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Runs a command like [Command::output], and kills it when it runs longer
+/// than `timeout`. Unlike [Command::output], stderr is only captured when
+/// it is piped.
+fn output_within(command: &mut Command, timeout: &Timeout) -> Result<Output> {
+    let mut process = command
+        .stdout(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to run {}", program(command)))?;
+    let stdout = process.stdout.take().context("No stdout")?;
+    let stderr = process.stderr.take();
+    let read = |mut output: Box<dyn Read + Send>| {
+        let mut bytes = vec![];
+        let _ = output.read_to_end(&mut bytes);
+        bytes
+    };
+    std::thread::scope(|scope| {
+        let stdout = scope.spawn(|| read(Box::new(stdout)));
+        let stderr = scope.spawn(|| match stderr {
+            Some(stderr) => read(Box::new(stderr)),
+            None => vec![],
+        });
+        let status = wait(&mut process, Some(timeout))?;
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    })
+}
+
+fn program(command: &Command) -> String {
+    command.get_program().to_string_lossy().to_string()
+}
+
+fn print_prefixed(output: impl Read, prefix: &str) {
+    let mut reader = BufReader::new(output);
+    let mut line = vec![];
+    while let Ok(n) = reader.read_until(b'\n', &mut line) {
+        if n == 0 {
+            break;
+        }
+        info!("{} {}", prefix, String::from_utf8_lossy(&line).trim_end());
+        line.clear();
+    }
+}
+
+/// Waits for a process whose stdout and stderr are piped, with its output
+/// shown as a progress line labelled `label`, and kept in `tail`.
+fn watch(
+    mut process: Child,
+    label: &str,
+    tail: Option<&Tail>,
+    timeout: Option<&Timeout>,
+) -> Result<ExitStatus> {
+    let stdout = process.stdout.take().context("No stdout")?;
+    let stderr = process.stderr.take().context("No stderr")?;
+    let progress = Progress::start(label);
+    let status = std::thread::scope(|scope| {
+        scope.spawn(|| read_progress(stdout, &progress, tail));
+        scope.spawn(|| read_progress(stderr, &progress, tail));
+        wait(&mut process, timeout)
+    });
+    progress.finish(status.as_ref().is_ok_and(ExitStatus::success));
+    status
+}
+
+/// Reads the output of a command into its progress line. Lines ending with
+/// a carriage return are progress updates that are overwritten by the next
+/// line, and are not kept.
+fn read_progress(output: impl Read, progress: &Progress, tail: Option<&Tail>) {
+    let mut line = vec![];
+    for byte in BufReader::new(output).bytes() {
+        let Ok(byte) = byte else {
+            break;
+        };
+        match byte {
+            b'\n' => {
+                if let Some(tail) = tail {
+                    tail.push(&line);
+                    tail.push(b"\n");
+                }
+                runlog::message(&String::from_utf8_lossy(&line));
+                progress.output(&String::from_utf8_lossy(&line))
+            }
+            b'\r' => progress.update(&String::from_utf8_lossy(&line)),
+            _ => {
+                line.push(byte);
+                continue;
+            }
+        }
+        line.clear();
+    }
+    if !line.is_empty() {
+        if let Some(tail) = tail {
+            tail.push(&line);
+        }
+        runlog::message(&String::from_utf8_lossy(&line));
+        progress.output(&String::from_utf8_lossy(&line));
+    }
+}
+
+/// The end of the output of a command.
+#[derive(Default)]
+struct Tail(Mutex<Vec<u8>>);
+
+impl Tail {
+    /// How many bytes are kept.
+    const SIZE: usize = 8192;
+
+    fn push(&self, bytes: &[u8]) {
+        let mut tail = self.0.lock().unwrap();
+        tail.extend_from_slice(bytes);
+        let excess = tail.len().saturating_sub(Self::SIZE);
+        tail.drain(..excess);
+    }
+
+    fn text(self) -> String {
+        let tail = self.0.into_inner().unwrap();
+        String::from_utf8_lossy(&tail).to_string()
+    }
+}
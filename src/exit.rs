@@ -0,0 +1,133 @@
+//! Exit codes by the kind of failure, so that scripts can tell a broken
+//! build from a server that cannot be reached.
+//!
+//! Errors are marked with [Classify::failure] where they happen. The mark
+//! does not change the message, and the innermost mark wins, so a step can
+//! mark all of its errors while the commands in it mark theirs more
+//! precisely.
+
+use std::fmt::{self, Display};
+
+use anyhow::Result;
+
+/// What failed, with its exit code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    /// deployment.yaml, the compose files or a tag is invalid.
+    Config,
+    /// The build script or a docker build failed.
+    Build,
+    /// Pushing an image or the files failed.
+    Push,
+    /// ssh could not connect to the server.
+    Ssh,
+    /// The services did not get healthy, or a smoke test failed.
+    Healthcheck,
+}
+
+/// Every exit code with its meaning, for the documentation.
+pub const CODES: &[(i32, &str)] = &[
+    (0, "Success."),
+    (1, "Any other error."),
+    (2, "Invalid command line arguments."),
+    (3, "deployment.yaml, the compose files or a tag is invalid."),
+    (4, "The build script or a docker build failed."),
+    (5, "Pushing an image or the files failed."),
+    (6, "ssh could not connect to the server."),
+    (
+        7,
+        "The services did not get healthy, or a smoke test failed.",
+    ),
+    (130, "Interrupted with Ctrl-C or SIGTERM."),
+];
+
+impl Failure {
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::Config => 3,
+            Failure::Build => 4,
+            Failure::Push => 5,
+            Failure::Ssh => 6,
+            Failure::Healthcheck => 7,
+        }
+    }
+}
+
+/// The exit code for an error: the code of its innermost [Failure], or 1.
+pub fn code(error: &anyhow::Error) -> i32 {
+    innermost(error).map_or(1, Failure::code)
+}
+
+/// The innermost [Failure] of an error. The error that a [Marked] wraps is
+/// not one of its sources, as it shows as the mark itself, so it is
+/// searched separately.
+fn innermost(error: &anyhow::Error) -> Option<Failure> {
+    let marked = error
+        .chain()
+        .filter_map(|e| e.downcast_ref::<Marked>())
+        .last()?;
+    Some(innermost(&marked.error).unwrap_or(marked.failure))
+}
+
+/// An error marked with the kind of failure. It shows as the error itself.
+#[derive(Debug)]
+struct Marked {
+    failure: Failure,
+    error: anyhow::Error,
+}
+
+impl Display for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The alternate form of anyhow includes the causes, which are
+        // already the sources of this error.
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Marked {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Marks an error with the kind of failure.
+pub fn mark(failure: Failure, error: anyhow::Error) -> anyhow::Error {
+    anyhow::Error::new(Marked { failure, error })
+}
+
+pub trait Classify<T> {
+    /// Marks the error with the kind of failure.
+    fn failure(self, failure: Failure) -> Result<T>;
+}
+
+impl<T> Classify<T> for Result<T> {
+    fn failure(self, failure: Failure) -> Result<T> {
+        self.map_err(|error| mark(failure, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn unmarked_errors_exit_with_1() {
+        assert_eq!(code(&anyhow!("failed")), 1);
+    }
+
+    #[test]
+    fn the_innermost_failure_decides_the_code() {
+        let ssh: Result<()> = Err(anyhow!("connection refused")).failure(Failure::Ssh);
+        let push = ssh.context("Failed to push files").failure(Failure::Push);
+        assert_eq!(code(&push.unwrap_err()), 6);
+    }
+
+    #[test]
+    fn nested_marks_decide_the_code() {
+        let ssh = mark(Failure::Ssh, anyhow!("connection refused"));
+        let push = mark(Failure::Push, ssh).context("Deploy failed");
+        assert_eq!(code(&push), 6);
+        assert_eq!(format!("{:#}", push), "Deploy failed: connection refused");
+    }
+}
@@ -0,0 +1,217 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use glob::Pattern;
+
+/// The version format used when `version_format` is not configured.
+pub const DEFAULT_VERSION_FORMAT: &str = "{date}-{describe}";
+
+fn git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+pub fn sha() -> Result<String> {
+    git(&["rev-parse", "HEAD"])
+}
+
+/// The current branch, or `HEAD` when detached.
+pub fn branch() -> Result<String> {
+    git(&["rev-parse", "--abbrev-ref", "HEAD"])
+}
+
+/// Fails when there are uncommitted changes, or when HEAD is not on any
+/// remote branch, so that only pushed commits are deployed. The logs and
+/// state of dep in `.dep` do not count.
+pub fn check_clean() -> Result<()> {
+    let status = git(&["status", "--porcelain", "--", ":/", ":(exclude).dep"])?;
+    if !status.is_empty() {
+        let files: Vec<_> = status.lines().map(|l| format!("  {}", l.trim())).collect();
+        bail!(
+            "There are uncommitted changes:\n{}\nCommit and push them, or pass --allow-dirty",
+            files.join("\n")
+        );
+    }
+    if git(&["branch", "--remotes", "--contains", "HEAD"])?.is_empty() {
+        bail!(
+            "{} is not pushed to any remote branch. Push it, or pass --allow-dirty",
+            git(&["rev-parse", "--short", "HEAD"])?
+        );
+    }
+    Ok(())
+}
+
+/// Fails when HEAD is not on one of `allowed_branches`, which may be glob
+/// patterns such as `release/*`, or is not tagged while `require_tag` is set.
+pub fn check_policy(allowed_branches: &[String], require_tag: bool) -> Result<()> {
+    if !allowed_branches.is_empty() {
+        // CI often checks out a detached HEAD, which is on the branches that
+        // contain it.
+        let branches: Vec<String> = match branch()?.as_str() {
+            "HEAD" => git(&[
+                "branch",
+                "--all",
+                "--contains",
+                "HEAD",
+                "--format=%(refname)",
+            ])?
+            .lines()
+            .filter_map(|r| match r.strip_prefix("refs/remotes/") {
+                Some(remote) => Some(remote.split_once('/')?.1),
+                None => r.strip_prefix("refs/heads/"),
+            })
+            .map(str::to_string)
+            .collect(),
+            branch => vec![branch.to_string()],
+        };
+        let allowed = branches.iter().any(|branch| {
+            allowed_branches
+                .iter()
+                .any(|pattern| Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(branch)))
+        });
+        if !allowed {
+            bail!(
+                "Only the branches {} may be deployed, and HEAD is on {}",
+                allowed_branches.join(", "),
+                match branches.is_empty() {
+                    true => "no branch".to_string(),
+                    false => branches.join(", "),
+                }
+            );
+        }
+    }
+    if require_tag && git(&["tag", "--points-at", "HEAD"])?.is_empty() {
+        bail!("HEAD must be tagged to be deployed");
+    }
+    Ok(())
+}
+
+/// A commit in [log].
+pub struct Commit {
+    pub sha: String,
+    pub author: String,
+    pub subject: String,
+    /// The files the commit changed, as absolute paths.
+    pub files: Vec<PathBuf>,
+}
+
+impl Commit {
+    /// The commit on one line, like `1a2b3c4 Fix the login (Jane Doe)`.
+    pub fn line(&self) -> String {
+        format!("{} {} ({})", self.sha, self.subject, self.author)
+    }
+}
+
+/// The commits after `since` up to HEAD, newest first.
+pub fn log(since: &str) -> Result<Vec<Commit>> {
+    let root = std::fs::canonicalize(git(&["rev-parse", "--show-toplevel"])?)?;
+    let range = format!("{}..HEAD", since);
+    let log = git(&["log", "--format=%x1e%h%x09%an%x09%s", "--name-only", &range]).context(
+        format!("Failed to read the log since {}. Is it fetched?", since),
+    )?;
+    let mut commits = vec![];
+    for entry in log.split('\x1e').filter(|e| !e.trim().is_empty()) {
+        let mut lines = entry.lines();
+        let header = lines.next().unwrap_or_default();
+        let mut fields = header.splitn(3, '\t');
+        commits.push(Commit {
+            sha: fields.next().unwrap_or_default().to_string(),
+            author: fields.next().unwrap_or_default().to_string(),
+            subject: fields.next().unwrap_or_default().to_string(),
+            files: lines
+                .filter(|l| !l.is_empty())
+                .map(|l| root.join(l))
+                .collect(),
+        });
+    }
+    Ok(commits)
+}
+
+/// The newest tag that matches the glob `pattern`.
+pub fn newest_tag(pattern: &str) -> Result<Option<String>> {
+    let tags = git(&["tag", "--list", pattern, "--sort=-creatordate"])?;
+    Ok(tags.lines().next().map(str::to_string))
+}
+
+/// Tags HEAD with `tag`, unless it is tagged with it already, and pushes the
+/// tag to `origin`.
+pub fn push_tag(tag: &str) -> Result<()> {
+    if !git(&["tag", "--points-at", "HEAD"])?
+        .lines()
+        .any(|t| t == tag)
+    {
+        git(&["tag", tag, "HEAD"])?;
+    }
+    git(&["push", "origin", &format!("refs/tags/{}", tag)])?;
+    Ok(())
+}
+
+/// Fills in the placeholders of a version template:
+///
+/// - `{date}`: date of the last commit, e.g. `2024-06-01`
+/// - `{sha}`: abbreviated commit sha
+/// - `{describe}`: `git describe --always --dirty`
+/// - `{branch}`: current branch, with characters that are invalid in tags replaced by `-`
+/// - `{semver}`: most recent tag, without a leading `v`
+/// - `{dirty}`: `-dirty` when there are uncommitted changes, otherwise empty
+pub fn render(template: &str) -> Result<String> {
+    let mut output = template.to_string();
+    if output.contains("{date}") {
+        output = output.replace("{date}", &git(&["log", "-1", "--format=%as"])?);
+    }
+    if output.contains("{sha}") {
+        output = output.replace("{sha}", &git(&["rev-parse", "--short", "HEAD"])?);
+    }
+    if output.contains("{describe}") {
+        output = output.replace("{describe}", &git(&["describe", "--always", "--dirty"])?);
+    }
+    if output.contains("{branch}") {
+        let branch: String = branch()?
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || "_.-".contains(c) {
+                true => c,
+                false => '-',
+            })
+            .collect();
+        output = output.replace("{branch}", &branch);
+    }
+    if output.contains("{semver}") {
+        let tag = git(&["describe", "--tags", "--abbrev=0"])
+            .context("{semver} needs at least one git tag")?;
+        output = output.replace("{semver}", tag.strip_prefix('v').unwrap_or(&tag));
+    }
+    if output.contains("{dirty}") {
+        let dirty = match git(&["status", "--porcelain"])?.is_empty() {
+            true => "",
+            false => "-dirty",
+        };
+        output = output.replace("{dirty}", dirty);
+    }
+    Ok(output)
+}
+
+/// Files that differ from `commit`, including uncommitted and untracked
+/// files, as absolute paths.
+pub fn changed_files(commit: &str) -> Result<Vec<PathBuf>> {
+    let root = std::fs::canonicalize(git(&["rev-parse", "--show-toplevel"])?)?;
+    let changed = git(&["diff", "--name-only", commit, "--"])
+        .context(format!("Failed to diff against {}. Is it fetched?", commit))?;
+    let untracked = git(&["ls-files", "--others", "--exclude-standard", "--full-name"])?;
+    Ok(changed
+        .lines()
+        .chain(untracked.lines())
+        .filter(|l| !l.is_empty())
+        .map(|l| root.join(l))
+        .collect())
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Directory in the remote project folder with one record per deployment.
+pub const HISTORY_DIR: &str = ".dep-history";
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DeployRecord {
+    pub version: String,
+    pub git_sha: String,
+    pub date: DateTime<Utc>,
+    pub user: String,
+    /// Image digest for each service, or the image tag if the digest is unknown.
+    pub images: BTreeMap<String, String>,
+}
+
+impl DeployRecord {
+    /// File name of the record. Records sort chronologically by name.
+    pub fn file_name(&self) -> String {
+        format!(
+            "{}-{}.yaml",
+            self.date.format("%Y%m%dT%H%M%SZ"),
+            self.version
+        )
+    }
+
+    /// Parses the output of concatenating several records separated by `---`.
+    pub fn parse_all(text: &str) -> Result<Vec<DeployRecord>> {
+        let mut records = vec![];
+        // An empty text still has one, empty, document.
+        if text.trim().is_empty() {
+            return Ok(records);
+        }
+        for document in serde_yaml::Deserializer::from_str(text) {
+            records.push(DeployRecord::deserialize(document)?);
+        }
+        Ok(records)
+    }
+}
+
+pub fn print_history(records: &[DeployRecord]) {
+    println!("{:<20}  {:<30}  {:<10}  USER", "DATE", "VERSION", "GIT SHA");
+    for record in records {
+        let sha: String = record.git_sha.chars().take(10).collect();
+        println!(
+            "{:<20}  {:<30}  {:<10}  {}",
+            record.date.format("%Y-%m-%d %H:%M:%S"),
+            record.version,
+            sha,
+            record.user
+        );
+    }
+}
+
+/// Identifies the person running dep as `user@host`.
+pub fn deployer() -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    let host = Command::new("hostname")
+        .output()
+        .ok()
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    format!("{}@{}", user, host)
+}
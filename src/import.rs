@@ -0,0 +1,238 @@
+//! Guesses the settings of existing projects for `dep init`, and imports
+//! projects that are deployed by hand for `dep init --from-remote`.
+//!
+//! The compose file on the server becomes the local docker-compose.yaml, and
+//! the files it mounts or reads environment variables from are copied from
+//! the server to become `additionalFiles`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_yaml::Value;
+
+use crate::config::SshConfig;
+use crate::registry::ImageRef;
+use crate::{remote_shell_path, shell_quote, BuildContext, DepConfig, DOCKER_COMPOSE_PATH};
+
+/// Compose file names that docker compose looks for, in its order.
+const COMPOSE_FILES: &[&str] = &[
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yaml",
+    "docker-compose.yml",
+];
+
+/// Directories at least this large are assumed to hold data rather than
+/// configuration, and are not copied.
+const MAX_DIR_KB: u64 = 1024;
+
+/// An existing project on a server.
+pub struct RemoteProject {
+    pub server: String,
+    /// Directory of the project on the server, e.g. `/opt/example`.
+    pub path: String,
+    /// Name of the compose file on the server.
+    pub compose_file: String,
+    /// Contents of the compose file.
+    pub compose: String,
+    /// Files and directories in the project directory that the services
+    /// mount or read, and that are copied.
+    pub files: Vec<String>,
+    /// Directories that the services mount, but that are too large to copy.
+    pub data: Vec<String>,
+    /// Runs ssh and rsync in the project directory on the server.
+    context: BuildContext,
+}
+
+impl RemoteProject {
+    /// Reads the project at `from`, which is `server:path`, with a
+    /// `user@` in front of the server if needed. With `dry_run`, [Self::pull]
+    /// only prints the commands.
+    pub fn fetch(from: &str, dry_run: bool) -> Result<RemoteProject> {
+        let Some((server, path)) = from.split_once(':') else {
+            bail!(
+                "Invalid --from-remote {}, expected server:path, e.g. example.org:/opt/example",
+                from
+            );
+        };
+        let path = path.trim_end_matches('/');
+        if server.is_empty() || path.is_empty() {
+            bail!("Invalid --from-remote {}, expected server:path", from);
+        }
+        let context = context(server, path, dry_run);
+        let entries = context.ssh_output("ls -Ap")?;
+        let entries: Vec<&str> = entries.lines().collect();
+        let Some(compose_file) = COMPOSE_FILES.iter().find(|f| entries.contains(f)) else {
+            bail!("There is no compose file in {} on {}", path, server);
+        };
+        let compose = context.ssh_output(&format!("cat {}", compose_file))?;
+        let value: Value = serde_yaml::from_str(&compose)
+            .context(format!("Failed to parse {} on {}", compose_file, server))?;
+
+        let mut files = vec![];
+        let mut dirs = vec![];
+        for used in used_paths(&value) {
+            match (
+                entries.contains(&used.as_str()),
+                entries.contains(&format!("{}/", used).as_str()),
+            ) {
+                (true, _) => files.push(used),
+                (_, true) => dirs.push(used),
+                _ => {}
+            }
+        }
+        let mut data = vec![];
+        if !dirs.is_empty() {
+            let quoted: Vec<_> = dirs.iter().map(|d| shell_quote(d)).collect();
+            let sizes = context.ssh_output(&format!("du -sk {}", quoted.join(" ")))?;
+            for line in sizes.lines() {
+                let Some((size, dir)) = line.split_once(char::is_whitespace) else {
+                    continue;
+                };
+                match size.parse::<u64>() {
+                    Ok(size) if size < MAX_DIR_KB => files.push(dir.trim().to_string()),
+                    _ => data.push(dir.trim().to_string()),
+                }
+            }
+        }
+        files.sort();
+        data.sort();
+        Ok(RemoteProject {
+            server: server.to_string(),
+            path: path.to_string(),
+            compose_file: compose_file.to_string(),
+            compose,
+            files,
+            data,
+            context,
+        })
+    }
+
+    /// The name of the project, which is the name of its directory, like
+    /// docker compose names it.
+    pub fn name(&self) -> String {
+        let name = Path::new(&self.path).file_name().and_then(|n| n.to_str());
+        name.unwrap_or(&self.path).to_string()
+    }
+
+    /// Guesses the registry from the images of the services, with
+    /// [guess_registry].
+    pub fn registry(&self) -> Option<String> {
+        guess_registry(&serde_yaml::from_str(&self.compose).ok()?)
+    }
+
+    /// The local paths that [Self::pull] writes.
+    pub fn local_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(DOCKER_COMPOSE_PATH)];
+        paths.extend(self.files.iter().map(PathBuf::from));
+        paths
+    }
+
+    /// Writes the compose file to docker-compose.yaml, and copies the
+    /// `files` from the server with rsync.
+    pub fn pull(&self) -> Result<()> {
+        std::fs::write(DOCKER_COMPOSE_PATH, &self.compose)
+            .context(format!("Failed to write {}", DOCKER_COMPOSE_PATH))?;
+        let server = &self.context.config.server;
+        for file in self.files.iter() {
+            // The remote shell expands the source, like the scripts of ssh.
+            let source = remote_shell_path(&format!("{}/{}", self.path, file));
+            let mut rsync = self.context.rsync();
+            rsync
+                .arg("--archive")
+                .arg(format!("{}:{}", server, source))
+                .arg(".");
+            self.context.check(
+                &mut rsync,
+                format!("Failed to copy {} from {}", file, self.server),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A context for running commands in `path` on `server`, which may start
+/// with `user@`.
+fn context(server: &str, path: &str, dry_run: bool) -> BuildContext {
+    let (user, server) = match server.split_once('@') {
+        Some((user, server)) => (Some(user.to_string()), server.to_string()),
+        None => (None, server.to_string()),
+    };
+    let config = DepConfig {
+        server,
+        remote_path: Some(path.to_string()),
+        ssh: SshConfig {
+            user,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    BuildContext::new(String::new(), config, false, None, dry_run, vec![])
+}
+
+/// Guesses the registry from the images of the services in `compose`: the
+/// registry and namespace that most images are in, e.g. `ghcr.io/acme` for
+/// `ghcr.io/acme/web:1.2`. Images on Docker Hub are left out.
+pub fn guess_registry(compose: &Value) -> Option<String> {
+    let services = compose.get("services")?.as_mapping()?;
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for service in services.values() {
+        let Some(image) = service.get("image").and_then(|i| i.as_str()) else {
+            continue;
+        };
+        let image = ImageRef::parse(image);
+        if image.host == "registry-1.docker.io" {
+            continue;
+        }
+        let registry = match image.repository.rsplit_once('/') {
+            Some((namespace, _)) => format!("{}/{}", image.host, namespace),
+            None => image.host,
+        };
+        *counts.entry(registry).or_default() += 1;
+    }
+    let most = counts.into_iter().max_by_key(|(_, count)| *count);
+    most.map(|(registry, _)| registry)
+}
+
+/// The top level files and directories in the project directory that the
+/// services mount as volumes or read with `env_file`.
+pub fn used_paths(compose: &Value) -> Vec<String> {
+    let mut paths = vec![];
+    let services = compose.get("services").and_then(|s| s.as_mapping());
+    for service in services.into_iter().flat_map(|s| s.values()) {
+        let volumes = service.get("volumes").and_then(|v| v.as_sequence());
+        for volume in volumes.into_iter().flatten() {
+            let source = match volume {
+                Value::String(s) => s.split(':').next(),
+                Value::Mapping(m) => m.get("source").and_then(|s| s.as_str()),
+                _ => None,
+            };
+            // Named volumes have no path.
+            paths.extend(source.filter(|s| s.starts_with('.')));
+        }
+        let env_files = match service.get("env_file") {
+            Some(Value::String(s)) => vec![s.as_str()],
+            Some(Value::Sequence(files)) => files
+                .iter()
+                .filter_map(|f| f.as_str().or_else(|| f.get("path")?.as_str()))
+                .collect(),
+            _ => vec![],
+        };
+        paths.extend(env_files);
+    }
+    // Paths outside of the project directory stay where they are.
+    let inside = |path: &&str| !path.starts_with(['/', '~']) && !path.starts_with("..");
+    let mut top: Vec<String> = paths
+        .into_iter()
+        .filter(inside)
+        .filter_map(|path| {
+            let mut components = Path::new(path).components();
+            components.find(|c| matches!(c, std::path::Component::Normal(_)))
+        })
+        .filter_map(|c| c.as_os_str().to_str().map(|s| s.to_string()))
+        .collect();
+    top.sort();
+    top.dedup();
+    top
+}
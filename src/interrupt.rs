@@ -0,0 +1,114 @@
+//! Ctrl-C and SIGTERM for commands that change something. Instead of dying
+//! right away, dep kills the running command and fails every command that
+//! follows, so that the error unwinds through the normal paths: temporary
+//! files are removed, the deploy lock is released in [cleanup], and the
+//! steps that were done are printed with [summary].
+//!
+//! A second signal also kills the cleanup, and a third exits right away.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+static CLEANING: AtomicBool = AtomicBool::new(false);
+
+/// The headers of the steps that were started, in order.
+static STEPS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Handles SIGINT and SIGTERM for the rest of the process.
+pub fn install() {
+    #[cfg(unix)]
+    // SAFETY: the handler only touches atomics and calls _exit, which are
+    // async-signal-safe.
+    unsafe {
+        let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    INSTALLED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn handle(_signal: libc::c_int) {
+    if SIGNALS.fetch_add(1, Ordering::SeqCst) >= 2 {
+        // SAFETY: _exit is async-signal-safe.
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Whether signals are handled, in which case commands are watched for
+/// them while they run.
+pub fn installed() -> bool {
+    INSTALLED.load(Ordering::SeqCst)
+}
+
+/// How many signals were received, for telling whether one arrived while
+/// a command ran.
+pub fn signals() -> usize {
+    SIGNALS.load(Ordering::SeqCst)
+}
+
+pub fn interrupted() -> bool {
+    signals() > 0
+}
+
+/// Fails when dep was interrupted, so that the next command does not start,
+/// unless it is part of the [cleanup].
+pub fn check() -> Result<()> {
+    if interrupted() && !CLEANING.load(Ordering::SeqCst) {
+        bail!("Interrupted");
+    }
+    Ok(())
+}
+
+/// Runs `f`, whose commands still run after an interrupt, such as
+/// releasing the deploy lock.
+pub fn cleanup<T>(f: impl FnOnce() -> T) -> T {
+    let cleaning = CLEANING.swap(true, Ordering::SeqCst);
+    let value = f();
+    CLEANING.store(cleaning, Ordering::SeqCst);
+    value
+}
+
+/// Sleeps for `duration`, and fails as soon as dep is interrupted.
+pub fn sleep(duration: Duration) -> Result<()> {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        check()?;
+        let left = duration.saturating_sub(start.elapsed());
+        std::thread::sleep(left.min(Duration::from_millis(100)));
+    }
+    check()
+}
+
+/// Records that a step such as `Building web` started, for the [summary].
+pub(crate) fn step(header: &str) {
+    if installed() && !interrupted() {
+        STEPS.lock().unwrap().push(header.to_string());
+    }
+}
+
+/// What was done before dep was interrupted, and the step it was
+/// interrupted in.
+pub fn summary() -> String {
+    let steps = STEPS.lock().unwrap();
+    let Some((current, done)) = steps.split_last() else {
+        return "Interrupted before anything was done".to_string();
+    };
+    let mut summary = String::from("Done before the interrupt:\n");
+    for step in done {
+        summary.push_str(&format!("  {}\n", step));
+    }
+    if done.is_empty() {
+        summary.push_str("  nothing\n");
+    }
+    summary.push_str(&format!(
+        "Interrupted in: {}\nThe steps after it did not run.",
+        current
+    ));
+    summary
+}
@@ -0,0 +1,246 @@
+//! Builds docker compose projects and deploys them to servers over ssh.
+//!
+//! This is the library behind the `dep` command line tool. A deploy reads
+//! `deployment.yaml` into a [DepConfig], the services to build from the
+//! compose files with [read_docker_compose], and runs the steps with a
+//! [BuildContext]:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use dep_core::{read_docker_compose, BuildContext, DepConfig};
+//!
+//! let mut config = DepConfig::read(Path::new(dep_core::DEP_CONFIG_PATH))?;
+//! config.validate()?;
+//! let containers = read_docker_compose(&config)?;
+//! let version = dep_core::git::render(dep_core::git::DEFAULT_VERSION_FORMAT)?;
+//! let mut context = BuildContext::new(version, config, false, None, false, containers);
+//! context.deploy()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Progress is reported with `tracing`. [output::init] prints it to stdout,
+//! like the command line tool does.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use tracing::{debug, info};
+
+mod buildhash;
+
+pub mod check;
+
+pub mod ci;
+
+pub mod compose;
+
+pub mod config;
+pub use config::DepConfig;
+
+mod context;
+pub use context::BuildContext;
+
+mod depignore;
+
+pub mod dockerfile;
+use dockerfile::{DockerContainer, DockerFile};
+
+pub mod executor;
+
+pub mod exit;
+
+pub mod git;
+
+pub mod import;
+
+mod history;
+use history::HISTORY_DIR;
+
+pub mod interrupt;
+
+mod lock;
+use lock::LOCK_PATH;
+
+mod notify;
+
+pub mod output;
+
+mod pin;
+
+mod progress;
+
+mod ps;
+
+mod registry;
+
+pub mod runlog;
+
+pub mod schema;
+
+pub mod template;
+
+mod state;
+
+pub const DOCKER_COMPOSE_PATH: &str = "docker-compose.yaml";
+pub const DEP_CONFIG_PATH: &str = "deployment.yaml";
+/// File next to the generated docker-compose.yaml on the server with the
+/// version, git sha and images that were pushed, for other tools on the
+/// server.
+pub const DEPLOY_MANIFEST_PATH: &str = "dep-deploy.json";
+/// Directory in the remote project folder where every pushed version of the
+/// generated docker-compose.yaml is kept.
+const VERSIONS_DIR: &str = ".dep-versions";
+/// Directory in the remote project folder with one directory per pushed
+/// version, when `releases` is enabled.
+const RELEASES_DIR: &str = "releases";
+/// Symlink in the remote project folder to the deployed release.
+const CURRENT_RELEASE: &str = "current";
+/// Seconds to wait for services to become healthy in a rolling deploy when
+/// `healthcheck_timeout` is not set.
+const ROLLING_HEALTHCHECK_TIMEOUT: u64 = 300;
+/// File on the server with the colour that receives traffic, with
+/// `strategy: blue-green`.
+const COLOR_PATH: &str = ".dep-versions/color";
+/// Directory in the remote project folder that the build contexts are
+/// copied to with `build_on: remote`.
+const BUILD_DIR: &str = ".dep-build";
+/// Append-only log in the remote project folder of every deploy, rollback
+/// and command that dep runs on the server, by whom.
+const AUDIT_LOG_PATH: &str = "dep-audit.log";
+/// Local directory with a directory per server for the generated compose
+/// files, with `deploy_mode: docker-context`.
+const CONTEXT_DIR: &str = ".dep/servers";
+/// Files and directories on the server that dep manages itself. They are
+/// never deleted by `rsync.delete`.
+const REMOTE_STATE: &[&str] = &[
+    VERSIONS_DIR,
+    HISTORY_DIR,
+    LOCK_PATH,
+    BUILD_DIR,
+    AUDIT_LOG_PATH,
+];
+
+fn header(msg: &str) {
+    interrupt::step(msg);
+    if output::is_json() {
+        runlog::message(msg);
+        output::event("phase_started", json!({ "phase": msg }));
+        return;
+    }
+    if ci::is_github() {
+        runlog::message(msg);
+        ci::start_group(msg);
+        return;
+    }
+    info!("\x1b[45;37;1m{}\x1b[0m", msg);
+}
+
+fn header_elapsed(msg: &str, instant: &Instant) {
+    if output::is_json() {
+        let seconds = instant.elapsed().as_secs_f64();
+        runlog::message(&format!("{} in {:.2} seconds", msg, seconds));
+        output::event(
+            "phase_finished",
+            json!({ "phase": msg, "seconds": seconds }),
+        );
+        return;
+    }
+    ci::end_group();
+    info!(
+        "\x1b[45;37;1m{} in {:.2} seconds\x1b[0m",
+        msg,
+        instant.elapsed().as_secs_f64()
+    );
+}
+
+/// The closest directory at or above `dir` with a deployment.yaml, like cargo
+/// finds Cargo.toml, so that dep can run from the subdirectories of a project.
+pub fn find_project_root(dir: &Path) -> Option<PathBuf> {
+    let root = dir
+        .ancestors()
+        .find(|dir| dir.join(DEP_CONFIG_PATH).is_file())?;
+    if root != dir {
+        debug!("Using {} in {}", DEP_CONFIG_PATH, root.display());
+    }
+    Some(root.to_path_buf())
+}
+
+/// Checks that `tag` is a valid docker image tag.
+pub fn validate_tag(tag: &str) -> Result<()> {
+    let valid_first = tag
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+    let valid_rest = tag
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    if !valid_first || !valid_rest || tag.len() > 128 {
+        bail!(
+            "Invalid tag {:?}. Tags may contain letters, digits, _, . and -, must not start with . or -, and be at most 128 characters",
+            tag
+        );
+    }
+    Ok(())
+}
+
+/// The compose files to merge, in order.
+pub fn compose_files(config: &DepConfig) -> Vec<PathBuf> {
+    match config.compose_files.is_empty() {
+        true => vec![PathBuf::from(DOCKER_COMPOSE_PATH)],
+        false => config.compose_files.clone(),
+    }
+}
+
+/// Reads the services that are built from the compose files.
+pub fn read_docker_compose(config: &DepConfig) -> Result<Vec<DockerContainer>> {
+    let compose = compose::read(&compose_files(config))?;
+    let docker_file: DockerFile = serde_yaml::from_value(compose)
+        .context(format!("Failed to parse {}", DOCKER_COMPOSE_PATH))?;
+
+    DockerContainer::from_docker_file(docker_file)
+}
+
+/// Formats a command the way it could be typed into a shell.
+fn display_command(command: &Command) -> String {
+    format!("$ {}", command_line(command))
+}
+
+pub fn command_line(command: &Command) -> String {
+    let mut parts = vec![];
+    for (key, value) in command.get_envs() {
+        if let Some(value) = value {
+            parts.push(format!(
+                "{}={}",
+                key.to_string_lossy(),
+                shell_quote(&value.to_string_lossy())
+            ));
+        }
+    }
+    parts.push(shell_quote(&command.get_program().to_string_lossy()));
+    for arg in command.get_args() {
+        parts.push(shell_quote(&arg.to_string_lossy()));
+    }
+    parts.join(" ")
+}
+
+/// Quotes a remote path, but keeps a leading `~/` so the shell expands it.
+fn remote_shell_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shell_quote(rest)),
+        None => shell_quote(path),
+    }
+}
+
+/// Quotes `s` for a shell, unless it only has characters that are safe.
+pub fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@,%+".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
@@ -0,0 +1,55 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// File in the remote project folder that exists while a deploy is running.
+pub const LOCK_PATH: &str = ".dep-lock";
+
+/// Locks older than this are assumed to be left behind by a deploy that
+/// was killed, and are taken over.
+pub const STALE_AFTER_SECONDS: u64 = 60 * 60;
+
+/// Exit code of the lock script when somebody else holds the lock.
+pub const LOCKED_EXIT_CODE: i32 = 75;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DeployLock {
+    pub owner: String,
+    pub pid: u32,
+    pub version: String,
+    pub date: DateTime<Utc>,
+}
+
+impl DeployLock {
+    fn contents(&self) -> anyhow::Result<String> {
+        Ok(serde_yaml::to_string(self)?.trim_end().to_string())
+    }
+
+    /// Shell script that creates the lock file unless a fresh lock exists,
+    /// in which case it prints that lock and exits with [LOCKED_EXIT_CODE].
+    pub fn acquire_script(&self, force: bool) -> anyhow::Result<String> {
+        let contents = crate::shell_quote(&self.contents()?);
+        Ok(format!(
+            r#"if ! (set -o noclobber; printf '%s' {contents} > {path}) 2>/dev/null; then
+  age=$(( $(date +%s) - $(stat -c %Y {path}) ))
+  if [ {force} = false ] && [ "$age" -lt {stale} ]; then cat {path}; exit {code}; fi
+  echo "Taking over the lock from $(grep '^owner:' {path})" >&2
+  printf '%s' {contents} > {path}
+fi"#,
+            contents = contents,
+            path = LOCK_PATH,
+            force = force,
+            stale = STALE_AFTER_SECONDS,
+            code = LOCKED_EXIT_CODE,
+        ))
+    }
+
+    /// Shell script that removes the lock file if it is still this lock.
+    pub fn release_script(&self) -> anyhow::Result<String> {
+        let contents = crate::shell_quote(&self.contents()?);
+        Ok(format!(
+            r#"if [ "$(cat {path} 2>/dev/null)" = {contents} ]; then rm -f {path}; fi"#,
+            path = LOCK_PATH,
+            contents = contents,
+        ))
+    }
+}
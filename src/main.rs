@@ -1,330 +1,442 @@
-use anyhow::{bail, Context, Result};
-use clap::Parser;
 use std::fs::File;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::time::Instant;
 
-use serde_yaml::Value;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{CommandFactory, FromArgMatches, Parser};
 
-mod dockerfile;
-use dockerfile::{DockerContainer, DockerFile};
+use dep_core::config::{DepConfig, InitAnswers, TimeoutConfig, Transport};
+use dep_core::exit::{self, Classify, Failure};
+use dep_core::output::{ColorChoice, Format};
+use dep_core::{check, ci, compose, compose_files, interrupt, runlog, schema};
 
-mod config;
-use config::DepConfig;
+mod completions;
+mod manual;
+use completions::Shell;
+use dep_core::import::RemoteProject;
+use dep_core::template::Template;
+use dep_core::{git, read_docker_compose, validate_tag, BuildContext};
+use dep_core::{DEP_CONFIG_PATH, DOCKER_COMPOSE_PATH};
 
-const DOCKER_COMPOSE_PATH: &str = "docker-compose.yaml";
-const DEP_CONFIG_PATH: &str = "deployment.yaml";
-
-fn header(msg: &str) {
-    println!("\x1b[45;37;1m{}\x1b[0m", msg);
-}
-
-fn header_elapsed(msg: &str, instant: &Instant) {
-    println!(
-        "\x1b[45;37;1m{} in {:.2} seconds\x1b[0m",
-        msg,
-        instant.elapsed().as_secs_f64()
-    );
-}
-
-fn git_version() -> Result<String> {
-    let date = Command::new("git")
-        .arg("log")
-        .arg("-1")
-        .arg("--format=%as")
-        .output()?
-        .stdout;
-    let date = String::from_utf8(date)?.trim().to_string();
-    let version = Command::new("git")
-        .arg("describe")
-        .arg("--always")
-        .arg("--dirty")
-        .output()?
-        .stdout;
-    let version = String::from_utf8(version)?.trim().to_string();
-    Ok(format!("{}-{}", date, version))
-}
-
-#[derive(Debug)]
-struct BuildContext {
-    registry: String,
-    version: String,
-    config: DepConfig,
+#[derive(Parser)]
+#[command(author, version, about, long_about=None, disable_help_subcommand = true)]
+struct Cli {
+    /// Run docker image pull before building and deploying.
+    #[arg(global = true, short, long, value_name = "PULL")]
     pull: bool,
-    containers: Vec<DockerContainer>,
-}
 
-impl BuildContext {
-    fn new(
-        version: String,
-        config: DepConfig,
-        pull: bool,
-        containers: Vec<DockerContainer>,
-    ) -> Self {
-        BuildContext {
-            registry: config.registry.clone(),
-            version,
-            config,
-            pull,
-            containers,
-        }
-    }
+    /// Server to use instead of the one in deployment.yaml. With `servers`,
+    /// only this server is deployed to.
+    #[arg(global = true, short, long, value_name = "server")]
+    server: Option<String>,
 
-    fn transform_docker_compose(&self) -> Result<String> {
-        let input_text = std::fs::read_to_string(DOCKER_COMPOSE_PATH)?;
-        let mut input: Value = serde_yaml::from_str(&input_text)?;
-        let services = input
-            .get_mut("services")
-            .and_then(|k| k.as_mapping_mut())
-            .context("No services in docker-compose")?;
-
-        for (service_name, service) in services.iter_mut() {
-            let build = service.get("build");
-            if let Some(_) = build {
-                if let Value::String(service_name) = service_name {
-                    let service = service.as_mapping_mut().context("service is not a map")?;
-                    let container: Vec<_> = self
-                        .containers
-                        .iter()
-                        .filter(|c| &c.name == service_name)
-                        .collect();
-                    let container = container[0];
-                    service.insert(
-                        Value::String("image".to_string()),
-                        Value::String(self.image(container)),
-                    );
-                    service.remove(Value::String("build".into()));
-                }
-            }
-        }
+    /// Only use the servers in these comma separated inventory groups.
+    #[arg(global = true, long, value_delimiter = ',', value_name = "GROUPS")]
+    group: Vec<String>,
 
-        let output = serde_yaml::to_string(&input)?;
-
-        Ok(output)
-    }
-
-    fn run_build_script(&self) -> Result<()> {
-        let prefix = r"
-set -o errexit
-set -o nounset
-set -o pipefail";
-        if let Some(build_script) = &self.config.build {
-            header("Running build script");
-            let script = format!("{}\n{}", prefix, build_script);
-            println!("Executing\x1b[48;2;10;10;10m\n{}\x1b[0m", script);
-            let mut process = Command::new("bash").stdin(Stdio::piped()).spawn()?;
-            let stdin = process.stdin.as_mut().context("No stdin")?;
-            writeln!(stdin, "{}", script)?;
-            if !process.wait()?.success() {
-                bail!("Failed to execut build script");
-            }
-        }
-        Ok(())
-    }
+    #[arg(global = true, short, long, value_name = "registry")]
+    registry: Option<String>,
 
-    fn build_all(&self) -> Result<()> {
-        self.run_build_script()?;
-        let start = Instant::now();
-        for container in self.containers.iter() {
-            self.build(container)?;
-            println!();
-        }
-        header_elapsed("Built all containers", &start);
-        Ok(())
-    }
-
-    fn deploy(&self) -> Result<()> {
-        let start = Instant::now();
-        self.push()?;
-        header("Deploying");
-        if self.pull {
-            let status = Command::new("ssh")
-                .arg(&self.config.server)
-                .arg(format!("cd {} && docker compose pull", self.config.name))
-                .status()?;
-            if !status.success() {
-                bail!("Failed to docker compose pull");
-            }
-        }
-        let status = Command::new("ssh")
-            .arg(&self.config.server)
-            .arg(format!("cd {} && docker compose up -d", self.config.name))
-            .status()?;
-        if !status.success() {
-            bail!("Failed to run docker compose up -d");
-        }
-        header_elapsed("Deployed", &start);
+    /// Copy images to the server over ssh instead of using a registry.
+    #[arg(global = true, long)]
+    no_registry: bool,
 
-        Ok(())
-    }
+    /// Print the commands that would be executed without running them.
+    #[arg(global = true, long)]
+    dry_run: bool,
 
-    fn push(&self) -> Result<()> {
-        let start = Instant::now();
-        self.push_containers()?;
-        self.push_files()?;
-        header_elapsed("Pushed everything", &start);
-        Ok(())
-    }
+    /// Use this version instead of the one computed from git.
+    #[arg(global = true, long, env = "DEP_VERSION", value_name = "TAG")]
+    tag: Option<String>,
 
-    fn push_containers(&self) -> Result<()> {
-        self.build_all()?;
-        for container in self.containers.iter() {
-            let status = Command::new("docker")
-                .arg("push")
-                .arg(self.image(container))
-                .status()?;
-            if !status.success() {
-                bail!("Failed to push container {}", container.name);
-            }
-        }
+    /// Environment from deployment.yaml to use.
+    #[arg(global = true, short, long, value_name = "ENV")]
+    env: Option<String>,
 
-        Ok(())
-    }
+    /// Number of docker builds to run in parallel.
+    #[arg(global = true, short, long, value_name = "N")]
+    jobs: Option<usize>,
 
-    fn remote_dir(&self) -> String {
-        format!("{}:{}", self.config.server, self.config.name)
-    }
+    /// Push images even if the registry already has a tag for the version.
+    #[arg(global = true, long)]
+    force_push: bool,
 
-    fn push_files(&self) -> Result<()> {
-        let tmp_dir = tempfile::tempdir()?;
-        let compose_txt = self.transform_docker_compose()?;
-        let mut tmp_file_path = tmp_dir.path().to_owned();
-        tmp_file_path.push(DOCKER_COMPOSE_PATH);
-        std::fs::write(tmp_file_path, compose_txt)?;
+    /// Deploy even if another deploy holds the lock on the server.
+    #[arg(global = true, long)]
+    force_unlock: bool,
 
-        // tmp_dir_path must have a trailing slash.
-        let tmp_dir_path = format!("{}/", tmp_dir.path().display());
-        let mut all_paths: Vec<String> = vec![tmp_dir_path];
-        let additional_files = match &self.config.additional_files {
-            Some(files) => files.clone(),
-            None => vec![],
-        };
+    /// Deploy to a `protected` server without asking for the project name,
+    /// e.g. in CI.
+    #[arg(global = true, short, long)]
+    yes: bool,
 
-        for add in additional_files.into_iter() {
-            all_paths.push(add.display().to_string());
-        }
+    /// Push and deploy even if `require_clean` is set and there are
+    /// uncommitted or unpushed changes.
+    #[arg(global = true, long)]
+    allow_dirty: bool,
 
-        let mut proc = Command::new("rsync");
-        proc.arg("--verbose")
-            .arg("--archive")
-            .arg("-h")
-            .arg("--progress")
-            .args(all_paths)
-            .arg(self.remote_dir());
-
-        match proc.status()?.success() {
-            true => Ok(()),
-            false => bail!("Failed to push rsync"),
-        }
-    }
+    /// Build every service, even if `skip_unchanged` is set and its build
+    /// context is unchanged.
+    #[arg(global = true, long)]
+    rebuild: bool,
 
-    fn build(&self, container: &DockerContainer) -> Result<()> {
-        header(&format!("Building {}", self.image(container)));
-        let mut builder = Command::new("docker");
-        builder.arg("build");
-        builder
-            .arg("--build-arg")
-            .arg(format!("VERSION={}", &self.version));
-        if self.pull {
-            builder.arg("--pull");
-        }
-        builder.arg(&container.build_dir);
-        if let Some(file) = &container.dockerfile {
-            builder.arg("-f").arg(file.to_string());
-        }
-        if let Some(target) = &container.target {
-            builder.arg("--target").arg(target);
-        }
-        builder.arg("-t").arg(self.image(container));
+    /// Keep building the other services when a build fails, and print which
+    /// builds failed at the end.
+    #[arg(global = true, short, long)]
+    keep_going: bool,
 
-        let status = builder.status()?;
-        if !status.success() {
-            bail!("Failed to execute docker build")
-        }
-        Ok(())
-    }
+    /// Print the commands that are run, and with -vv their exit codes.
+    /// Defaults to the level in RUST_LOG.
+    #[arg(global = true, short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
 
-    fn image(&self, c: &DockerContainer) -> String {
-        format!("{}/{}:{}", self.registry, c.name, self.version)
-    }
-}
+    /// Only print the output of builds, pushes and rsync when they fail.
+    #[arg(global = true, short, long)]
+    quiet: bool,
 
-#[derive(Parser)]
-#[command(author, version, about, long_about=None)]
-struct Cli {
-    /// Run docker image pull before building and deploying.
-    #[arg(global = true, short, long, value_name = "PULL")]
-    pull: bool,
+    /// Run in CI mode: no prompts or colors, GitHub Actions annotations and
+    /// a job summary. Defaults to on when CI is set.
+    #[arg(global = true, long)]
+    ci: bool,
 
-    #[arg(global = true, short, long, value_name = "server")]
-    server: Option<String>,
+    /// When to color the output.
+    #[arg(global = true, long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
 
-    #[arg(global = true, short, long, value_name = "registry")]
-    registry: Option<String>,
+    /// Print progress as JSON events on stdout, one per line.
+    #[arg(global = true, long, value_enum, default_value_t = Format::Text)]
+    output: Format,
 
     /// Directory to change into before running the commands
     #[arg(short, long)]
     directory: Option<PathBuf>,
 
+    /// Deployment config to use instead of deployment.yaml, e.g.
+    /// deployment.staging.yaml. Relative to --directory.
+    #[arg(global = true, long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Same as --config, given before the command. It is not global, since
+    /// `dep logs -f` follows the logs.
+    #[arg(short = 'f', value_name = "PATH", conflicts_with = "config")]
+    config_short: Option<PathBuf>,
+
     #[command(subcommand)]
     command: CliCommand,
 }
 
+#[derive(clap::Args)]
+struct ServiceSelection {
+    /// Only build and push these services. The other services keep their
+    /// currently deployed image.
+    services: Vec<String>,
+
+    /// Comma separated list of services, as an alternative to positional arguments.
+    #[arg(long, value_delimiter = ',', value_name = "SERVICES")]
+    only: Vec<String>,
+
+    /// Only build and push the services whose build context changed since
+    /// the last deploy.
+    #[arg(long, conflicts_with_all = ["services", "only"])]
+    affected: bool,
+}
+
+impl ServiceSelection {
+    fn names(&self) -> Vec<String> {
+        self.services
+            .iter()
+            .chain(self.only.iter())
+            .cloned()
+            .collect()
+    }
+}
+
 #[derive(clap::Subcommand)]
 enum CliCommand {
     /// Build.
-    Build,
+    Build {
+        #[command(flatten)]
+        selection: ServiceSelection,
+    },
     /// Build and push to the server.
     Push {
         #[arg(short, long)]
         no_docker: bool,
+
+        #[command(flatten)]
+        selection: ServiceSelection,
     },
     /// Build, push, and deploy to the server.
-    Deploy,
+    Deploy {
+        #[command(flatten)]
+        selection: ServiceSelection,
+
+        /// Recreate all containers, even if nothing changed.
+        #[arg(long)]
+        force_recreate: bool,
+
+        /// Remove containers of services that are no longer in the compose file.
+        #[arg(long)]
+        remove_orphans: bool,
+
+        /// Show a dashboard with a pane per service instead of the output.
+        #[arg(long)]
+        ui: bool,
+    },
+    /// Show what deploy would build, push, copy and run, without changing
+    /// anything.
+    Plan {
+        #[command(flatten)]
+        selection: ServiceSelection,
+    },
+    /// Show a diff of the files on the server against the files deploy would
+    /// push, and the image tags that would change.
+    Diff,
+    /// List the services whose build context changed since the last deploy.
+    Affected,
+    /// List the commits since the last deploy, grouped by service.
+    Changelog,
+    /// Show the logs of the services on the server.
+    Logs {
+        /// Services to show logs for. Defaults to all services.
+        services: Vec<String>,
+
+        /// Follow log output.
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Show logs since a timestamp or relative time, such as 1h.
+        #[arg(long)]
+        since: Option<String>,
+    },
+    /// Run a command in a service on the server, e.g. `dep exec web -- sh`.
+    Exec {
+        /// Service to run the command in.
+        service: String,
+
+        /// Don't allocate a terminal, e.g. when piping input to the command.
+        #[arg(short = 'T', long)]
+        no_tty: bool,
+
+        /// Command to run.
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Run a command in a new container of a service on the server, e.g.
+    /// `dep run web -- ./manage.py migrate`.
+    Run {
+        /// Service to run the command in.
+        service: String,
+
+        /// Don't allocate a terminal, e.g. when piping input to the command.
+        #[arg(short = 'T', long)]
+        no_tty: bool,
+
+        /// Command to run. Defaults to the command of the service.
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+    /// Open a shell on the server in the project directory.
+    Ssh,
+    /// Stop and remove the containers on the server.
+    Down,
+    /// Restart services on the server.
+    Restart {
+        /// Services to restart. Defaults to all services.
+        services: Vec<String>,
+    },
+    /// Stop services on the server without removing them.
+    Stop {
+        /// Services to stop. Defaults to all services.
+        services: Vec<String>,
+    },
+    /// List the containers on the server with their state, health and tag.
+    Ps {
+        /// Print the containers as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Compare the versions running on the server with the local version.
+    Status,
+    /// Check that the files and containers on the server match the local
+    /// version, and fail if they have drifted.
+    Verify,
+    /// List previous deployments on the server.
+    History,
+    /// Redeploy a previously pushed version.
+    Rollback {
+        /// Version to deploy. Defaults to the previously deployed version.
+        version: Option<String>,
+    },
+    /// Remove old locally built images and dangling build cache.
+    Clean {
+        /// Number of versions to keep per service. Defaults to `retention`,
+        /// or 3.
+        #[arg(long, value_name = "N")]
+        keep: Option<usize>,
+    },
+    /// Manage the images in the registry.
+    Registry {
+        #[command(subcommand)]
+        command: RegistryCommand,
+    },
+    /// Pin the images of services that are not built to their current digest.
+    Pin {
+        /// Write the pinned images to this compose override file instead of
+        /// changing the compose files.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Check the images of services that are not built for newer versions.
+    Outdated,
     /// Display git version.
     Version,
+    /// Check that docker, buildx, ssh, rsync and git work, and that the
+    /// servers and the registry can be reached.
+    Doctor,
     /// Display the generated docker-compose.yaml file.
     Compose,
-    /// Interactive wizard to create a deployment.yaml file.
-    Init,
+    /// Check deployment.yaml and the compose files.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Print a completion script for a shell, e.g.
+    /// `dep completions bash > /etc/bash_completion.d/dep`.
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// List the services in the compose files, for shell completion.
+    #[command(name = "__services", hide = true)]
+    Services,
+    /// Print the man page, e.g. `dep man > /usr/share/man/man1/dep.1`.
+    Man {
+        /// Write the man page and a page per command to this directory
+        /// instead.
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+    /// Print the JSON Schema of deployment.yaml, for editors.
+    Schema,
+    /// Interactive wizard to create a deployment.yaml file. The answers can
+    /// be given with --name, --server and --registry.
+    Init {
+        /// Import a project that is deployed by hand from `server:path` on a
+        /// server: its compose file, and the files it mounts.
+        #[arg(long, value_name = "SERVER:PATH")]
+        from_remote: Option<String>,
+
+        /// Also create a docker-compose.yaml from a starter template.
+        #[arg(long, value_enum, conflicts_with = "from_remote")]
+        template: Option<Template>,
+
+        /// Name of the project.
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Don't ask anything, and use the defaults for the answers that are
+        /// not given, e.g. in scripts.
+        #[arg(long)]
+        non_interactive: bool,
+    },
+    /// Print this message or the help of the given subcommand(s).
+    Help {
+        /// Print the reference of every command, option and deployment.yaml
+        /// key.
+        #[arg(long)]
+        all: bool,
+        command: Vec<String>,
+    },
 }
 
-fn read_docker_compose() -> Result<Vec<DockerContainer>> {
-    let docker_path = Path::new(DOCKER_COMPOSE_PATH);
-    let open =
-        File::open(docker_path).context(format!("Failed to open {}", DOCKER_COMPOSE_PATH))?;
+#[derive(clap::Subcommand)]
+enum ConfigCommand {
+    /// Check deployment.yaml and the compose files, and report every problem
+    /// with its file and line.
+    Validate,
+}
 
-    let docker_file: DockerFile = serde_yaml::from_reader(open)
-        .context(format!("Failed to parse {}", DOCKER_COMPOSE_PATH))?;
+#[derive(clap::Subcommand)]
+enum RegistryCommand {
+    /// Delete old tags of each service from the registry.
+    Prune {
+        /// Number of tags to keep per service. Defaults to `retention`.
+        #[arg(long, value_name = "N")]
+        keep: Option<usize>,
+    },
+}
 
-    Ok(DockerContainer::from_docker_file(docker_file))
+impl Cli {
+    /// The deployment config, from --config or else deployment.yaml.
+    fn config_path(&self) -> &Path {
+        self.config.as_deref().unwrap_or(Path::new(DEP_CONFIG_PATH))
+    }
 }
 
 fn read_dep(cli: &Cli) -> Result<DepConfig> {
-    let path = Path::new(DEP_CONFIG_PATH);
-    let open =
-        File::open(path).context(format!("Failed to open config file: {}", DEP_CONFIG_PATH))?;
-    let mut deserialized: DepConfig = serde_yaml::from_reader(open)
-        .context(format!("Failed to parse config file: {}", DEP_CONFIG_PATH))?;
+    if cli.config.is_none() && !Path::new(DEP_CONFIG_PATH).exists() {
+        bail!(
+            "There is no {} in this directory or its parents. Create one with `dep init`",
+            DEP_CONFIG_PATH
+        );
+    }
+    let mut deserialized = DepConfig::read(cli.config_path())?;
+    if let Some(env) = &cli.env {
+        deserialized.apply_environment(env)?;
+    }
     if let Some(registry) = &cli.registry {
         deserialized.registry = registry.clone();
     }
+    deserialized.select_groups(&cli.group)?;
     if let Some(server) = &cli.server {
         deserialized.server = server.clone();
+        deserialized.servers.clear();
+    }
+    deserialized.validate()?;
+    if cli.no_registry && deserialized.transport.is_registry() {
+        deserialized.transport = Transport::Ssh;
     }
 
     Ok(deserialized)
 }
 
-fn init() -> Result<()> {
-    let dep_path = Path::new(DEP_CONFIG_PATH);
-    if Path::exists(&dep_path) {
-        print!(
-            "{} already exists. Are you sure you want to overwrite it? (y/n) ",
-            DEP_CONFIG_PATH
-        );
+fn init(
+    dep_path: &Path,
+    from_remote: Option<&str>,
+    template: Option<Template>,
+    answers: &InitAnswers,
+    dry_run: bool,
+) -> Result<()> {
+    if ci::is_active() && !answers.non_interactive {
+        bail!("dep init asks questions, and does not run in CI without --non-interactive");
+    }
+    let remote = from_remote
+        .map(|from| RemoteProject::fetch(from, dry_run))
+        .transpose()?;
+    let mut paths = vec![dep_path.to_path_buf()];
+    if let Some(remote) = &remote {
+        paths.extend(remote.local_paths());
+    }
+    if template.is_some() {
+        paths.push(PathBuf::from(DOCKER_COMPOSE_PATH));
+    }
+    let existing: Vec<_> = paths.iter().filter(|p| p.exists()).collect();
+    if !existing.is_empty() {
+        let names: Vec<_> = existing.iter().map(|p| p.display().to_string()).collect();
+        if answers.non_interactive {
+            bail!("{} already exists", names.join(", "));
+        }
+        match names.len() {
+            1 => print!(
+                "{} already exists. Are you sure you want to overwrite it? (y/n) ",
+                names[0]
+            ),
+            _ => print!(
+                "{} already exist. Are you sure you want to overwrite them? (y/n) ",
+                names.join(", ")
+            ),
+        }
         std::io::stdout().flush()?;
         let mut answer = String::new();
         std::io::stdin().read_line(&mut answer)?;
@@ -333,46 +445,455 @@ fn init() -> Result<()> {
             return Ok(());
         }
     }
-    let config = DepConfig::create_interactive();
+    let config = match (&remote, template) {
+        (Some(remote), _) => {
+            let config = DepConfig::create_imported(remote, answers)?;
+            remote.pull()?;
+            config
+        }
+        (None, Some(template)) => {
+            let config = DepConfig::create_templated(template, answers)?;
+            std::fs::write(DOCKER_COMPOSE_PATH, template.compose(&config.name))?;
+            for (path, contents) in template.files() {
+                if !path.exists() {
+                    if let Some(dir) = path.parent() {
+                        std::fs::create_dir_all(dir)?;
+                    }
+                    std::fs::write(&path, contents)?;
+                }
+            }
+            println!("Created {} from the template", DOCKER_COMPOSE_PATH);
+            for step in template.next_steps() {
+                println!("- {}", step);
+            }
+            config
+        }
+        (None, None) => DepConfig::create_interactive(answers)?,
+    };
     let mut write_handle = File::create(dep_path)?;
     serde_yaml::to_writer(&mut write_handle, &config)?;
+
+    let Some(remote) = remote else {
+        return Ok(());
+    };
+    println!(
+        "Imported {} and {} file(s) from {}",
+        remote.compose_file,
+        remote.files.len(),
+        remote.server
+    );
+    for dir in remote.data.iter() {
+        println!(
+            "{} looks like data, and is left on the server. Add it to rsync.protect before setting rsync.delete",
+            dir
+        );
+    }
+    if remote.compose_file != DOCKER_COMPOSE_PATH {
+        println!(
+            "Remove {} on the server before the first deploy, since docker compose reads it instead of {}",
+            remote.compose_file, DOCKER_COMPOSE_PATH
+        );
+    }
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// Asks for the project name before deploying to a `protected` server,
+/// unless `--yes` is set.
+fn confirm_protected(cli: &Cli, dep: &DepConfig) -> Result<()> {
+    if !dep.protected || cli.yes || cli.dry_run {
+        return Ok(());
+    }
+    let servers = match dep.servers.is_empty() {
+        true => dep.server.clone(),
+        false => dep.servers.join(", "),
+    };
+    let target = match &cli.env {
+        Some(env) => format!("The {} environment ({})", env, servers),
+        None => servers,
+    };
+    if ci::is_active() || !std::io::stdin().is_terminal() {
+        bail!(
+            "{} is protected. Pass --yes to deploy without confirming",
+            target
+        );
+    }
+    print!(
+        "{} is protected. Type the project name, {}, to continue: ",
+        target, dep.name
+    );
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if answer.trim() != dep.name {
+        bail!("Stopped, {:?} is not the project name", answer.trim());
+    }
+    Ok(())
+}
+
+/// Prints the problems in the deployment config at `path` and the compose
+/// files, and fails if there are any.
+fn validate(path: &Path) -> Result<()> {
+    let problems = check::check(path);
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    match problems.len() {
+        0 => {
+            println!("{} and the compose files are valid", path.display());
+            Ok(())
+        }
+        1 => Err(anyhow!("Found 1 problem")).failure(Failure::Config),
+        n => Err(anyhow!("Found {} problems", n)).failure(Failure::Config),
+    }
+}
+
+/// Prints the help of a subcommand, like the help subcommand of clap.
+fn help(path: &[String]) -> Result<()> {
+    let mut command = Cli::command();
+    command.build();
+    let mut command = &mut command;
+    for name in path {
+        command = match command.find_subcommand_mut(name) {
+            Some(subcommand) => subcommand,
+            None => bail!("Unknown command {}", path.join(" ")),
+        };
+    }
+    command.print_long_help()?;
+    Ok(())
+}
+
+fn main() {
+    let matches = Cli::command().get_matches();
+    let command = matches.subcommand_name().unwrap_or_default().to_string();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    cli.config = cli.config.or(cli.config_short.take());
+    let result = run(cli);
+    ci::end_group();
+    if let Err(e) = ci::write_summary(&command, result.as_ref().err()) {
+        eprintln!("Error: {:?}", e);
+    }
+    let code = match result {
+        Ok(code) => code,
+        Err(e) => {
+            if ci::is_github() {
+                println!("{}", ci::annotation("error", &format!("{:#}", e)));
+            }
+            eprintln!("Error: {:?}", e);
+            if interrupt::interrupted() {
+                eprintln!("{}", interrupt::summary());
+            }
+            if let Some(path) = runlog::path() {
+                runlog::message(&format!("Error: {:?}", e));
+                eprintln!("The full log is in {}", path.display());
+            }
+            match interrupt::interrupted() {
+                true => 130,
+                false => exit::code(&e),
+            }
+        }
+    };
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
+
+/// Runs the command, and returns the exit code of dep, which is the exit
+/// code of the remote command for `exec`, `run` and `ssh`.
+fn run(cli: Cli) -> Result<i32> {
+    dep_core::output::set_format(cli.output);
+    let ci = cli.ci || ci::detect();
+    ci::set_active(ci);
+    // CI logs rarely show colors, unless asked for.
+    match cli.color {
+        ColorChoice::Auto if ci => dep_core::output::set_color(ColorChoice::Never),
+        color => dep_core::output::set_color(color),
+    }
+    dep_core::output::set_quiet(cli.quiet);
+    dep_core::output::init(cli.verbose);
 
     if let Some(dir) = &cli.directory {
         std::env::set_current_dir(dir)
             .context(format!("Failed to change directory to {}", dir.display()))?;
     }
 
-    if let CliCommand::Init = &cli.command {
-        init()?;
-        std::process::exit(0);
+    if let CliCommand::Init {
+        from_remote,
+        template,
+        name,
+        non_interactive,
+    } = &cli.command
+    {
+        let answers = InitAnswers {
+            config_path: cli.config_path().to_path_buf(),
+            name: name.clone(),
+            server: cli.server.clone(),
+            registry: cli.registry.clone(),
+            non_interactive: *non_interactive,
+        };
+        init(
+            cli.config_path(),
+            from_remote.as_deref(),
+            *template,
+            &answers,
+            cli.dry_run,
+        )?;
+        return Ok(0);
+    }
+    // Like git and cargo, dep runs from anywhere in the project, as if it was
+    // run next to deployment.yaml.
+    if cli.config.is_none() {
+        let dir = std::env::current_dir()?;
+        if let Some(root) = dep_core::find_project_root(&dir) {
+            std::env::set_current_dir(&root)
+                .context(format!("Failed to change directory to {}", root.display()))?;
+        }
+    }
+    match &cli.command {
+        CliCommand::Completions { shell } => {
+            print!("{}", completions::generate(Cli::command(), *shell));
+            return Ok(0);
+        }
+        CliCommand::Man { dir: None } => {
+            print!("{}", manual::man(Cli::command())?);
+            return Ok(0);
+        }
+        CliCommand::Man { dir: Some(dir) } => {
+            manual::write_pages(Cli::command(), dir).context(format!(
+                "Failed to write the man pages to {}",
+                dir.display()
+            ))?;
+            return Ok(0);
+        }
+        CliCommand::Schema => {
+            println!("{}", serde_json::to_string_pretty(&schema::schema())?);
+            return Ok(0);
+        }
+        CliCommand::Help { all: true, .. } => {
+            print!("{}", manual::reference(Cli::command()));
+            return Ok(0);
+        }
+        CliCommand::Help { command, .. } => return help(command).map(|_| 0),
+        CliCommand::Config {
+            command: ConfigCommand::Validate,
+        } => return validate(cli.config_path()).map(|_| 0),
+        _ => {}
     }
 
-    let containers = read_docker_compose()?;
-    let dep = read_dep(&cli)?;
+    // Commands that change something keep a log of their output.
+    let log = match &cli.command {
+        CliCommand::Build { .. } => Some("build"),
+        CliCommand::Push { .. } => Some("push"),
+        CliCommand::Deploy { .. } => Some("deploy"),
+        CliCommand::Rollback { .. } => Some("rollback"),
+        CliCommand::Down => Some("down"),
+        CliCommand::Restart { .. } => Some("restart"),
+        CliCommand::Stop { .. } => Some("stop"),
+        CliCommand::Registry { .. } => Some("registry"),
+        CliCommand::Clean { .. } => Some("clean"),
+        _ => None,
+    };
+    if let Some(name) = log {
+        runlog::start(name)?;
+        // They also clean up after themselves when interrupted.
+        interrupt::install();
+    }
 
-    let build_context = BuildContext::new(git_version()?, dep, cli.pull, containers);
+    let mut dep = read_dep(&cli).failure(Failure::Config)?;
+    let deploys = matches!(
+        cli.command,
+        CliCommand::Push { .. } | CliCommand::Deploy { .. }
+    );
+    if deploys && dep.require_clean && !cli.allow_dirty {
+        git::check_clean()?;
+    }
+    if deploys {
+        git::check_policy(&dep.allowed_branches, dep.require_tag)?;
+    }
+    if matches!(
+        cli.command,
+        CliCommand::Deploy { .. } | CliCommand::Rollback { .. }
+    ) {
+        confirm_protected(&cli, &dep)?;
+    }
+    if let CliCommand::Services = &cli.command {
+        let compose = compose::read(&compose_files(&dep))?;
+        let services = compose.get("services").and_then(|s| s.as_mapping());
+        for name in services.into_iter().flat_map(|s| s.keys()) {
+            if let Some(name) = name.as_str() {
+                println!("{}", name);
+            }
+        }
+        return Ok(0);
+    }
+    let containers = read_docker_compose(&dep).failure(Failure::Config)?;
+
+    let version = match &cli.tag {
+        Some(tag) => {
+            validate_tag(tag).failure(Failure::Config)?;
+            tag.clone()
+        }
+        // dep doctor checks git itself.
+        None if matches!(cli.command, CliCommand::Doctor) => String::new(),
+        None => {
+            let format = dep.version_format.as_deref();
+            let version = git::render(format.unwrap_or(git::DEFAULT_VERSION_FORMAT))?;
+            validate_tag(&version)
+                .context("Invalid version_format")
+                .failure(Failure::Config)?;
+            version
+        }
+    };
+
+    // Following logs and interactive commands run as long as the user wants.
+    let interactive = matches!(
+        cli.command,
+        CliCommand::Logs { follow: true, .. }
+            | CliCommand::Exec { .. }
+            | CliCommand::Run { .. }
+            | CliCommand::Ssh
+    );
+    if interactive {
+        dep.timeouts = TimeoutConfig::none();
+    }
+
+    let mut build_context =
+        BuildContext::new(version, dep, cli.pull, cli.jobs, cli.dry_run, containers);
+    if let CliCommand::Doctor = &cli.command {
+        return build_context.doctor().map(|_| 0);
+    }
+
+    for tag in build_context.config.extra_tags.iter() {
+        let tag = git::render(tag)?;
+        validate_tag(&tag)
+            .context("Invalid extra_tags")
+            .failure(Failure::Config)?;
+        build_context.extra_tags.push(tag);
+    }
+    build_context.force_push = cli.force_push;
+    build_context.force_unlock = cli.force_unlock;
+    build_context.keep_going = cli.keep_going;
+    // --pull asks for fresh base images, so everything is rebuilt.
+    build_context.skip_unchanged = build_context.config.skip_unchanged && !cli.rebuild && !cli.pull;
+
+    let selection = match &cli.command {
+        CliCommand::Build { selection } => Some((selection, false)),
+        CliCommand::Push { selection, .. }
+        | CliCommand::Deploy { selection, .. }
+        | CliCommand::Plan { selection } => Some((selection, true)),
+        _ => None,
+    };
+    if let Some((selection, keep_deployed)) = selection {
+        if selection.affected {
+            let services = build_context.affected_services()?;
+            if services.is_empty() {
+                println!("No services changed since the last deploy");
+            }
+            build_context.select_services(&services, keep_deployed)?;
+        } else {
+            let services = selection.names();
+            if !services.is_empty() {
+                build_context.select_services(&services, keep_deployed)?;
+            }
+        }
+    }
 
     match cli.command {
         CliCommand::Version => {
-            println!("version: {}", git_version()?);
+            println!("version: {}", build_context.version);
         }
-        CliCommand::Build => build_context.build_all()?,
-        CliCommand::Push { no_docker } => match no_docker {
-            true => build_context.push_files()?,
-            false => build_context.push()?,
-        },
+        CliCommand::Build { .. } => build_context.build_all(false)?,
+        CliCommand::Push { no_docker, .. } => build_context.push(!no_docker)?,
         CliCommand::Compose => {
             let output = build_context.transform_docker_compose()?;
             println!("{}", output);
         }
-        CliCommand::Deploy => build_context.deploy()?,
-        CliCommand::Init => {}
+        CliCommand::Deploy {
+            force_recreate,
+            remove_orphans,
+            ui,
+            ..
+        } => {
+            build_context.ui = ui;
+            let compose_up = &mut build_context.config.compose_up;
+            compose_up.force_recreate |= force_recreate;
+            compose_up.remove_orphans |= remove_orphans;
+            build_context.deploy()?
+        }
+        CliCommand::Plan { .. } => build_context.plan()?,
+        CliCommand::Diff => build_context.diff()?,
+        CliCommand::Rollback { version } => {
+            let action = format!("rollback {}", version.as_deref().unwrap_or("previous"));
+            build_context.audited(&action, |ctx| ctx.with_lock(|ctx| ctx.rollback(version)))?
+        }
+        CliCommand::Logs {
+            services,
+            follow,
+            since,
+        } => build_context.logs(&services, follow, since)?,
+        CliCommand::Exec {
+            service,
+            no_tty,
+            command,
+        } => {
+            return build_context.exec("exec", &service, &command, !no_tty);
+        }
+        CliCommand::Run {
+            service,
+            no_tty,
+            command,
+        } => {
+            return build_context.exec("run --rm", &service, &command, !no_tty);
+        }
+        CliCommand::Ssh => return build_context.shell(),
+        CliCommand::Down => build_context.audited("down", |ctx| {
+            ctx.with_lock(|ctx| ctx.compose_command("down", &[]))
+        })?,
+        CliCommand::Restart { services } => {
+            let action = format!("restart {}", services.join(" "));
+            build_context.audited(action.trim_end(), |ctx| {
+                ctx.with_lock(|ctx| ctx.compose_command("restart", &services))
+            })?
+        }
+        CliCommand::Stop { services } => {
+            let action = format!("stop {}", services.join(" "));
+            build_context.audited(action.trim_end(), |ctx| {
+                ctx.with_lock(|ctx| ctx.compose_command("stop", &services))
+            })?
+        }
+        CliCommand::Ps { json } => build_context.ps(json)?,
+        CliCommand::Status => build_context.status()?,
+        CliCommand::Verify => build_context.verify()?,
+        CliCommand::Affected => {
+            for service in build_context.affected_services()? {
+                println!("{}", service);
+            }
+        }
+        CliCommand::Changelog => build_context.changelog()?,
+        CliCommand::History => build_context.history()?,
+        CliCommand::Pin { output } => build_context.pin(output)?,
+        CliCommand::Outdated => build_context.outdated()?,
+        CliCommand::Registry { command } => match command {
+            RegistryCommand::Prune { keep } => {
+                let keep = keep.or(build_context.config.retention).context(format!(
+                    "Set --keep or retention in {}",
+                    build_context.config.path().display()
+                ))?;
+                build_context.prune_registry(keep)?
+            }
+        },
+        CliCommand::Clean { keep } => {
+            let keep = keep.or(build_context.config.retention).unwrap_or(3);
+            build_context.clean(keep)?
+        }
+        CliCommand::Init { .. }
+        | CliCommand::Completions { .. }
+        | CliCommand::Services
+        | CliCommand::Man { .. }
+        | CliCommand::Schema
+        | CliCommand::Config { .. }
+        | CliCommand::Doctor
+        | CliCommand::Help { .. } => {}
     }
 
-    Ok(())
+    Ok(0)
 }
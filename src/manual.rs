@@ -0,0 +1,252 @@
+//! The reference of every command, option and deployment.yaml key, as the
+//! text of `dep help --all` and as the man page of `dep man`.
+
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use clap::{Arg, Command};
+use clap_mangen::roff::{bold, italic, roman, Roff};
+use clap_mangen::Man;
+
+use dep_core::exit::CODES;
+use dep_core::schema;
+
+/// A command with its path from the top level, e.g. `dep registry prune`.
+struct Page<'a> {
+    path: String,
+    command: &'a Command,
+}
+
+/// The visible commands, depth first.
+fn pages(command: &Command) -> Vec<Page<'_>> {
+    let mut pages = vec![];
+    collect(command, command.get_name().to_string(), &mut pages);
+    pages
+}
+
+fn collect<'a>(command: &'a Command, path: String, pages: &mut Vec<Page<'a>>) {
+    for subcommand in command.get_subcommands().filter(|c| !c.is_hide_set()) {
+        let path = format!("{} {}", path, subcommand.get_name());
+        pages.push(Page {
+            path: path.clone(),
+            command: subcommand,
+        });
+        collect(subcommand, path, pages);
+    }
+}
+
+/// The options of a command, without the global ones and `--help` unless
+/// `globals`.
+fn options(command: &Command, globals: bool) -> Vec<&Arg> {
+    command
+        .get_arguments()
+        .filter(|a| !a.is_hide_set() && !a.is_positional())
+        .filter(|a| globals || !(a.is_global_set() || a.get_id() == "help"))
+        .collect()
+}
+
+fn positionals(command: &Command) -> Vec<&Arg> {
+    command
+        .get_arguments()
+        .filter(|a| !a.is_hide_set() && a.is_positional())
+        .collect()
+}
+
+/// The names of an option with its value, e.g. `-j, --jobs <N>`.
+fn names(arg: &Arg) -> String {
+    let mut names = vec![];
+    if let Some(short) = arg.get_short() {
+        names.push(format!("-{}", short));
+    }
+    if let Some(long) = arg.get_long() {
+        names.push(format!("--{}", long));
+    }
+    let mut names = names.join(", ");
+    if arg.get_action().takes_values() {
+        let _ = write!(names, " <{}>", value_name(arg));
+    }
+    names
+}
+
+fn value_name(arg: &Arg) -> String {
+    match arg.get_value_names().and_then(|names| names.first()) {
+        Some(name) => name.to_string(),
+        None => arg.get_id().as_str().to_uppercase(),
+    }
+}
+
+/// The usage of a positional argument, e.g. `[SERVICES]...`.
+fn usage(arg: &Arg) -> String {
+    let name = value_name(arg);
+    let multiple = arg.get_num_args().is_some_and(|n| n.max_values() > 1);
+    match (arg.is_required_set(), multiple) {
+        (true, false) => format!("<{}>", name),
+        (true, true) => format!("<{}>...", name),
+        (false, false) => format!("[{}]", name),
+        (false, true) => format!("[{}]...", name),
+    }
+}
+
+/// The help of an argument, with its possible values, default and
+/// environment variable.
+fn help(arg: &Arg) -> String {
+    let mut help = arg
+        .get_long_help()
+        .or(arg.get_help())
+        .map(|h| h.to_string().replace('\n', " "))
+        .unwrap_or_default();
+    let values: Vec<String> = match arg.get_action().takes_values() {
+        true => arg
+            .get_possible_values()
+            .iter()
+            .filter(|v| !v.is_hide_set())
+            .map(|v| v.get_name().to_string())
+            .collect(),
+        false => vec![],
+    };
+    if !values.is_empty() {
+        let _ = write!(help, " [possible values: {}]", values.join(", "));
+    }
+    let defaults: Vec<String> = arg
+        .get_default_values()
+        .iter()
+        .map(|v| v.to_string_lossy().to_string())
+        .collect();
+    if !defaults.is_empty() && arg.get_action().takes_values() {
+        let _ = write!(help, " [default: {}]", defaults.join(","));
+    }
+    if let Some(env) = arg.get_env() {
+        let _ = write!(help, " [env: {}]", env.to_string_lossy());
+    }
+    help.trim().to_string()
+}
+
+fn about(command: &Command) -> String {
+    command
+        .get_long_about()
+        .or(command.get_about())
+        .map(|a| a.to_string().replace('\n', " "))
+        .unwrap_or_default()
+}
+
+fn synopsis(page: &Page) -> String {
+    let mut synopsis = page.path.clone();
+    if !options(page.command, true).is_empty() {
+        synopsis.push_str(" [OPTIONS]");
+    }
+    for arg in positionals(page.command) {
+        let _ = write!(synopsis, " {}", usage(arg));
+    }
+    if page.command.has_subcommands() {
+        synopsis.push_str(" <COMMAND>");
+    }
+    synopsis
+}
+
+/// The reference as plain text, for `dep help --all`.
+pub fn reference(mut command: Command) -> String {
+    command.build();
+    let mut text = String::new();
+    let top = Page {
+        path: command.get_name().to_string(),
+        command: &command,
+    };
+    let _ = writeln!(text, "{}\n\nUsage: {}\n", about(&command), synopsis(&top));
+    let _ = writeln!(text, "Global options:");
+    write_options(&mut text, options(&command, true));
+
+    for page in pages(&command) {
+        let _ = writeln!(text, "\n{}\n", page.path);
+        let _ = writeln!(text, "  {}\n", about(page.command));
+        let _ = writeln!(text, "  Usage: {}", synopsis(&page));
+        let arguments = positionals(page.command);
+        if !arguments.is_empty() {
+            let _ = writeln!(text, "\n  Arguments:");
+            for arg in arguments {
+                let _ = writeln!(text, "    {}", usage(arg));
+                let _ = writeln!(text, "        {}", help(arg));
+            }
+        }
+        let options = options(page.command, false);
+        if !options.is_empty() {
+            let _ = writeln!(text, "\n  Options:");
+            write_options(&mut text, options);
+        }
+    }
+
+    let _ = writeln!(text, "\n{}\n", dep_core::DEP_CONFIG_PATH);
+    for (key, description) in schema::keys() {
+        let _ = writeln!(text, "  {}\n      {}", key, description);
+    }
+
+    let _ = writeln!(text, "\nExit codes\n");
+    for (code, description) in CODES {
+        let _ = writeln!(text, "  {:<5}{}", code, description);
+    }
+    text
+}
+
+fn write_options(text: &mut String, options: Vec<&Arg>) {
+    for arg in options {
+        let _ = writeln!(text, "    {}", names(arg));
+        let help = help(arg);
+        if !help.is_empty() {
+            let _ = writeln!(text, "        {}", help);
+        }
+    }
+}
+
+/// The man page of dep in roff, for `dep man`: the page clap_mangen
+/// generates, followed by the keys of deployment.yaml, the exit codes and
+/// the files dep uses.
+pub fn man(command: Command) -> io::Result<String> {
+    let mut page = vec![];
+    Man::new(command).render(&mut page)?;
+    let mut roff = Roff::new();
+    roff.control("SH", ["CONFIGURATION"]);
+    roff.text([
+        roman("The project is configured in "),
+        italic(dep_core::DEP_CONFIG_PATH),
+        roman("."),
+    ]);
+    for (key, description) in schema::keys() {
+        roff.control("TP", [])
+            .text([bold(key)])
+            .text([roman(description)]);
+    }
+    roff.control("SH", ["EXIT STATUS"]);
+    for (code, description) in CODES {
+        roff.control("TP", [])
+            .text([bold(code.to_string())])
+            .text([roman(*description)]);
+    }
+    roff.control("SH", ["FILES"]);
+    let files = [
+        (
+            dep_core::DEP_CONFIG_PATH,
+            "The configuration of the project.",
+        ),
+        (
+            dep_core::DOCKER_COMPOSE_PATH,
+            "The services that are deployed.",
+        ),
+        (dep_core::runlog::LOGS_DIR, "The logs of previous runs."),
+    ];
+    for (path, description) in files {
+        roff.control("TP", [])
+            .text([italic(path)])
+            .text([roman(description)]);
+    }
+    let mut page = String::from_utf8_lossy(&page).into_owned();
+    page.push_str(&roff.to_roff());
+    Ok(page)
+}
+
+/// Writes the man page of dep and a page per command, such as
+/// `dep-deploy.1`, to `dir`.
+pub fn write_pages(command: Command, dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    clap_mangen::generate_to(command.clone(), dir)?;
+    std::fs::write(dir.join("dep.1"), man(command)?)
+}
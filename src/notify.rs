@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct Notification {
+    /// URL of the webhook.
+    pub url: String,
+    /// What the webhook expects.
+    #[serde(default)]
+    pub kind: NotificationKind,
+    /// Environment variable with a token that is sent as
+    /// `Authorization: Bearer <token>`, such as a Grafana service account
+    /// token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationKind {
+    Slack,
+    Discord,
+    /// Posts an annotation of the deploy to the annotations API of Grafana,
+    /// `<grafana>/api/annotations`.
+    Grafana,
+    /// Posts the [DeployEvent] as JSON.
+    #[default]
+    Webhook,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeployEvent {
+    pub project: String,
+    pub version: String,
+    pub server: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_seconds: f64,
+    pub status: DeployStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The commits since the previous deploy, one per line.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeployStatus {
+    Success,
+    Failure,
+}
+
+impl DeployEvent {
+    pub fn new(
+        project: &str,
+        version: &str,
+        server: &str,
+        duration: Duration,
+        error: Option<String>,
+    ) -> Self {
+        let finished_at = Utc::now();
+        DeployEvent {
+            project: project.to_string(),
+            version: version.to_string(),
+            server: server.to_string(),
+            started_at: finished_at - duration,
+            finished_at,
+            duration_seconds: (duration.as_secs_f64() * 100.0).round() / 100.0,
+            status: match error {
+                None => DeployStatus::Success,
+                Some(_) => DeployStatus::Failure,
+            },
+            error,
+            changes: vec![],
+        }
+    }
+
+    fn summary(&self) -> String {
+        match &self.error {
+            None => {
+                let mut summary = format!(
+                    "Deployed {} {} to {} in {:.1} seconds",
+                    self.project, self.version, self.server, self.duration_seconds
+                );
+                for change in self.changes.iter() {
+                    summary.push_str(&format!("\n• {}", change));
+                }
+                summary
+            }
+            Some(error) => format!(
+                "Failed to deploy {} {} to {}: {}",
+                self.project, self.version, self.server, error
+            ),
+        }
+    }
+
+    /// The JSON body to post for the given kind of webhook.
+    pub fn payload(&self, kind: NotificationKind) -> serde_json::Value {
+        match kind {
+            NotificationKind::Slack => json!({ "text": self.summary() }),
+            NotificationKind::Discord => json!({ "content": self.summary() }),
+            NotificationKind::Grafana => json!({
+                "time": self.started_at.timestamp_millis(),
+                "timeEnd": self.finished_at.timestamp_millis(),
+                "tags": ["deploy", self.project, self.version],
+                "text": self.summary(),
+            }),
+            NotificationKind::Webhook => json!(self),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::layer::{self, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::{ci, progress, runlog};
+
+/// How dep reports its progress.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// Coloured headers and messages for people.
+    #[default]
+    Text,
+    /// One JSON event per line on stdout. Messages and the output of the
+    /// commands go to stderr.
+    Json,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+static COLOR: AtomicBool = AtomicBool::new(true);
+
+/// When the headers and messages are colored.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorChoice {
+    /// When stdout is a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Prints the `tracing` events above any progress lines and writes them to
+/// the log, for the rest of this process. `verbose` is the number of `-v`
+/// flags. Without any, the levels are read from `RUST_LOG`, and default to
+/// info. Messages up to info are written to the log at every level, and the
+/// log has the commands anyway.
+pub fn init(verbose: u8) {
+    let filter = match verbose {
+        0 => EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy(),
+        1 => EnvFilter::new("debug"),
+        _ => EnvFilter::new("trace"),
+    };
+    tracing_subscriber::registry()
+        .with(Printer.with_filter(filter))
+        .with(runlog::Layer.with_filter(LevelFilter::INFO))
+        .init();
+}
+
+/// Prints messages above any progress lines. With JSON output, messages go
+/// to stderr, so that stdout only has events. With `-v`, messages start
+/// with the time.
+struct Printer;
+
+impl<S: Subscriber> Layer<S> for Printer {
+    fn on_event(&self, event: &Event<'_>, _context: layer::Context<'_, S>) {
+        let text = message(event);
+        let text = match LevelFilter::current() >= LevelFilter::DEBUG && !text.is_empty() {
+            true => format!("{} {}", chrono::Local::now().format("%H:%M:%S%.3f"), text),
+            false => text,
+        };
+        let text = match (ci::is_github(), *event.metadata().level()) {
+            (true, Level::ERROR) => ci::annotation("error", &styled(&text)),
+            (true, Level::WARN) => ci::annotation("warning", &styled(&text)),
+            _ => styled(&text),
+        };
+        match is_json() {
+            true => eprintln!("{}", text),
+            false => progress::println(&text),
+        }
+    }
+}
+
+/// The message of a `tracing` event, with warnings and errors starting with
+/// a colored `warning: ` or `error: `.
+pub(crate) fn message(event: &Event<'_>) -> String {
+    struct Visitor(String);
+    impl Visit for Visitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+    let mut visitor = Visitor(String::new());
+    event.record(&mut visitor);
+    match *event.metadata().level() {
+        Level::ERROR => format!("\x1b[31merror\x1b[0m: {}", visitor.0),
+        Level::WARN => format!("\x1b[33mwarning\x1b[0m: {}", visitor.0),
+        _ => visitor.0,
+    }
+}
+
+/// Sets whether everything in this process prints colors.
+pub fn set_color(choice: ColorChoice) {
+    let color = match choice {
+        ColorChoice::Auto => {
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+            !no_color && std::io::stdout().is_terminal()
+        }
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    COLOR.store(color, Ordering::SeqCst);
+}
+
+pub fn is_color() -> bool {
+    COLOR.load(Ordering::SeqCst)
+}
+
+/// The text, without its colors if colors are off.
+pub fn styled(text: &str) -> String {
+    match is_color() {
+        true => text.to_string(),
+        false => String::from_utf8_lossy(&strip_colors(text.as_bytes())).into_owned(),
+    }
+}
+
+/// Removes the ANSI escape sequences that color text, such as `\x1b[1m`.
+pub(crate) fn strip_colors(text: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(text.len());
+    let mut bytes = text.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == 0x1b {
+            // Skips to the final byte of the sequence, e.g. `m` in `\x1b[1m`.
+            for &byte in bytes.by_ref() {
+                if byte.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+            continue;
+        }
+        stripped.push(byte);
+    }
+    stripped
+}
+
+/// Sets how progress is reported by everything in this process.
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::SeqCst);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+/// Captures the output of builds, pushes and rsync instead of printing it,
+/// and only prints it for the ones that fail.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::SeqCst);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::SeqCst)
+}
+
+/// Whether events are printed, or kept for the job summary in CI. Events
+/// whose fields take a while to look up are only made if so.
+pub fn wants_events() -> bool {
+    is_json() || ci::is_active()
+}
+
+/// Prints an event such as `{"event":"image_built","time":"...",...}` with
+/// JSON output, and keeps it for the job summary in CI. `fields` must be a
+/// JSON object.
+pub fn event(name: &str, fields: Value) {
+    if !wants_events() {
+        return;
+    }
+    let mut event = json!({
+        "event": name,
+        "time": chrono::Utc::now(),
+    });
+    if let (Some(event), Value::Object(fields)) = (event.as_object_mut(), fields) {
+        event.extend(fields);
+    }
+    ci::record(&event);
+    if is_json() {
+        println!("{}", event);
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::BTreeMap;
+
+use serde_yaml::Value;
+
+/// Images of the services in a compose file that are not built by dep,
+/// such as `postgres:16`. Images that use variables are left out.
+pub fn third_party_images(compose: &Value) -> BTreeMap<String, String> {
+    let mut images = BTreeMap::new();
+    let Some(services) = compose.get("services").and_then(|s| s.as_mapping()) else {
+        return images;
+    };
+    for (name, service) in services {
+        if service.get("build").is_some() {
+            continue;
+        }
+        let (Some(name), Some(image)) = (name.as_str(), service.get("image")) else {
+            continue;
+        };
+        if let Some(image) = image.as_str().filter(|i| !i.contains('$')) {
+            images.insert(name.to_string(), image.to_string());
+        }
+    }
+    images
+}
+
+/// Removes the digest from an image reference, so `postgres:16@sha256:...`
+/// becomes `postgres:16`.
+pub fn strip_digest(image: &str) -> &str {
+    image.split('@').next().unwrap_or(image)
+}
+
+/// Replaces `image: <old>` with `image: <new>` in the text of a compose file,
+/// keeping quotes, comments and formatting as they are.
+pub fn replace_image(text: &str, old: &str, new: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let value = trimmed.strip_prefix("image:").map(|v| v.trim());
+        let value = value.map(|v| v.split(" #").next().unwrap_or(v).trim());
+        let value = value.map(|v| v.trim_matches(|c| c == '"' || c == '\''));
+        match value {
+            Some(value) if value == old => output.push_str(&line.replacen(old, new, 1)),
+            _ => output.push_str(line),
+        }
+    }
+    output
+}
@@ -0,0 +1,504 @@
+//! Progress lines for the build, push and rsync commands when stdout is a
+//! terminal, and the dashboard of `dep deploy --ui`.
+//!
+//! The output of these commands is captured instead of printed, and a line
+//! per running command shows its progress parsed from the output: the build
+//! step, the number of pushed layers or the rsync percentage. The captured
+//! output is printed when the command fails.
+//!
+//! The dashboard has a pane per service with its state and the tail of the
+//! output of its commands. While it is shown, the output of every command
+//! is captured, and messages replace the line below the panes.
+
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::output;
+
+/// Progress lines are redrawn at most this often.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lines of output kept for each pane of the dashboard.
+const PANE_OUTPUT: usize = 3;
+
+static BOARD: Mutex<Board> = Mutex::new(Board {
+    lines: Vec::new(),
+    next_id: 0,
+    drawn: 0,
+    last_draw: None,
+    panes: Vec::new(),
+    message: String::new(),
+    failures: Vec::new(),
+});
+
+/// Whether commands show progress lines instead of their output. With
+/// `--quiet`, the output is captured without showing progress lines.
+pub fn enabled() -> bool {
+    !output::is_json() && (output::is_quiet() || std::io::stdout().is_terminal())
+}
+
+/// Whether the dashboard is shown, in which case the output of every
+/// command should be captured.
+pub fn dashboard() -> bool {
+    !BOARD.lock().unwrap().panes.is_empty()
+}
+
+/// Shows the dashboard with a pane per service, if progress lines are
+/// [enabled]. Returns whether it is shown.
+pub fn start_dashboard(services: &[String]) -> bool {
+    if !enabled() || services.is_empty() {
+        return false;
+    }
+    let mut board = BOARD.lock().unwrap();
+    board.panes = services
+        .iter()
+        .map(|service| Pane {
+            service: service.clone(),
+            state: State::Waiting,
+            start: None,
+            end: None,
+            output: vec![],
+        })
+        .collect();
+    board.redraw();
+    true
+}
+
+/// Sets the state of the pane of `service` on the dashboard.
+pub fn set_state(service: &str, state: State) {
+    let mut board = BOARD.lock().unwrap();
+    if let Some(pane) = board.panes.iter_mut().find(|p| p.service == service) {
+        pane.set_state(state);
+        board.redraw();
+    }
+}
+
+/// Sets the state of every pane on the dashboard that has not failed.
+pub fn set_states(state: State) {
+    let mut board = BOARD.lock().unwrap();
+    for pane in board.panes.iter_mut() {
+        if pane.state != State::Failed {
+            pane.set_state(state);
+        }
+    }
+    board.redraw();
+}
+
+/// Replaces the dashboard with a summary of the state and duration of each
+/// service, followed by the output of the commands that failed. Services
+/// that were still running failed unless `success`.
+pub fn finish_dashboard(success: bool) {
+    let mut board = BOARD.lock().unwrap();
+    if board.panes.is_empty() {
+        return;
+    }
+    for pane in board.panes.iter_mut() {
+        if !success && pane.state.is_running() {
+            pane.set_state(State::Failed);
+        }
+    }
+    let mut out = std::io::stdout().lock();
+    board.clear(&mut out);
+    write_line(&mut out, "\x1b[45;37;1mSummary\x1b[0m");
+    let width = board.panes.iter().map(|p| p.service.len()).max();
+    for pane in board.panes.iter() {
+        write_line(
+            &mut out,
+            &format!(
+                "{:width$}  {}",
+                pane.service,
+                pane.status(),
+                width = width.unwrap_or(0)
+            ),
+        );
+    }
+    for (label, output) in std::mem::take(&mut board.failures) {
+        write_line(&mut out, &format!("\n\x1b[31m{} failed\x1b[0m:", label));
+        for text in output {
+            write_line(&mut out, &text);
+        }
+    }
+    board.panes.clear();
+    board.message.clear();
+    board.draw(&mut out);
+}
+
+/// Prints a message above the progress lines, or shows it below the panes
+/// of the dashboard.
+pub fn println(message: &str) {
+    let mut board = BOARD.lock().unwrap();
+    if !board.panes.is_empty() {
+        if !message.trim().is_empty() {
+            board.message = message.lines().last().unwrap_or_default().to_string();
+            board.redraw();
+        }
+        return;
+    }
+    let mut out = std::io::stdout().lock();
+    board.clear(&mut out);
+    write_line(&mut out, message);
+    board.draw(&mut out);
+}
+
+/// The state of a service on the dashboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Waiting,
+    Building,
+    Built,
+    Unchanged,
+    Pushing,
+    Pushed,
+    Deploying,
+    Deployed,
+    Failed,
+}
+
+impl State {
+    fn is_done(self) -> bool {
+        matches!(self, State::Deployed | State::Failed)
+    }
+
+    fn is_running(self) -> bool {
+        matches!(self, State::Building | State::Pushing | State::Deploying)
+    }
+
+    fn colored(self) -> String {
+        let color = match self {
+            State::Waiting => 2,
+            State::Building | State::Pushing | State::Deploying => 33,
+            State::Failed => 31,
+            _ => 32,
+        };
+        let name = format!("{:?}", self).to_lowercase();
+        format!("\x1b[{}m{:9}\x1b[0m", color, name)
+    }
+}
+
+/// The progress line of a running command.
+pub struct Progress {
+    id: usize,
+}
+
+impl Progress {
+    /// Shows a new progress line labelled `label`. On the dashboard, the
+    /// progress of a command labelled with the name of a service is shown
+    /// in the pane of the service.
+    pub fn start(label: &str) -> Progress {
+        let mut board = BOARD.lock().unwrap();
+        let id = board.next_id;
+        board.next_id += 1;
+        board.lines.push(Line {
+            id,
+            label: label.to_string(),
+            start: Instant::now(),
+            done: 0,
+            total: 0,
+            unit: "",
+            layers: BTreeMap::new(),
+            last: String::new(),
+            output: vec![],
+        });
+        board.redraw();
+        Progress { id }
+    }
+
+    /// Updates the progress from a line of output that is overwritten by
+    /// the next one, such as an rsync progress update.
+    pub fn update(&self, text: &str) {
+        let mut board = BOARD.lock().unwrap();
+        if let Some(line) = board.lines.iter_mut().find(|l| l.id == self.id) {
+            line.update(text);
+        }
+        let due = board
+            .last_draw
+            .is_none_or(|last| last.elapsed() >= REDRAW_INTERVAL);
+        if due {
+            board.redraw();
+        }
+    }
+
+    /// Updates the progress from a line of output, and keeps the line to
+    /// print it if the command fails.
+    pub fn output(&self, text: &str) {
+        {
+            let mut board = BOARD.lock().unwrap();
+            let Board { lines, panes, .. } = &mut *board;
+            if let Some(line) = lines.iter_mut().find(|l| l.id == self.id) {
+                line.output.push(text.to_string());
+                if let Some(pane) = panes.iter_mut().find(|p| p.service == line.label) {
+                    pane.output.push(text.to_string());
+                    let excess = pane.output.len().saturating_sub(PANE_OUTPUT);
+                    pane.output.drain(..excess);
+                }
+            }
+        }
+        self.update(text);
+    }
+
+    /// Replaces the progress line with whether the command succeeded, and
+    /// its output if it did not. On the dashboard, the output is kept for
+    /// the summary instead.
+    pub fn finish(self, success: bool) {
+        let mut board = BOARD.lock().unwrap();
+        let Some(index) = board.lines.iter().position(|l| l.id == self.id) else {
+            return;
+        };
+        let line = board.lines.remove(index);
+        if !board.panes.is_empty() {
+            if !success {
+                if let Some(pane) = board.panes.iter_mut().find(|p| p.service == line.label) {
+                    pane.set_state(State::Failed);
+                }
+                board.failures.push((line.label, line.output));
+            }
+            board.redraw();
+            return;
+        }
+        let mut out = std::io::stdout().lock();
+        board.clear(&mut out);
+        let seconds = line.start.elapsed().as_secs_f64();
+        if success {
+            write_line(
+                &mut out,
+                &format!(
+                    "{} \x1b[32mdone\x1b[0m in {:.2} seconds",
+                    line.label, seconds
+                ),
+            );
+        } else {
+            write_line(
+                &mut out,
+                &format!(
+                    "{} \x1b[31mfailed\x1b[0m after {:.2} seconds",
+                    line.label, seconds
+                ),
+            );
+            for text in line.output.iter() {
+                write_line(&mut out, text);
+            }
+        }
+        board.draw(&mut out);
+    }
+}
+
+struct Board {
+    lines: Vec<Line>,
+    next_id: usize,
+    /// Number of lines on the screen below the last message.
+    drawn: usize,
+    last_draw: Option<Instant>,
+    /// The panes of the dashboard, which is shown when there are any.
+    panes: Vec<Pane>,
+    /// The last message, shown below the panes.
+    message: String,
+    /// The labels and output of the commands that failed on the dashboard.
+    failures: Vec<(String, Vec<String>)>,
+}
+
+impl Board {
+    fn clear(&mut self, out: &mut impl Write) {
+        if self.drawn > 0 {
+            // Moves the cursor to the first progress line and clears the
+            // screen below it.
+            let _ = write!(out, "\x1b[{}F\x1b[J", self.drawn);
+        }
+        self.drawn = 0;
+    }
+
+    fn draw(&mut self, out: &mut impl Write) {
+        let width = terminal_width();
+        let mut rows = vec![];
+        if !self.panes.is_empty() {
+            let name_width = self.panes.iter().map(|p| p.service.len()).max();
+            let name_width = name_width.unwrap_or(0);
+            // The panes get the same number of output lines, so that the
+            // dashboard fits on the screen.
+            let free = terminal_height().saturating_sub(self.lines.len() + 1);
+            let tail = (free / self.panes.len()).saturating_sub(1).min(PANE_OUTPUT);
+            for pane in self.panes.iter() {
+                let line = self.lines.iter().find(|l| l.label == pane.service);
+                let mut header = format!("{:name_width$}  {}", pane.service, pane.status());
+                if let Some(line) = line.filter(|_| pane.state.is_running()) {
+                    header += &line.progress();
+                }
+                rows.push(header);
+                let skip = pane.output.len().saturating_sub(tail);
+                for text in pane.output.iter().skip(skip) {
+                    rows.push(format!("  \x1b[2m│\x1b[0m {}", text));
+                }
+            }
+        }
+        for line in self.lines.iter() {
+            if self.panes.is_empty() && output::is_quiet() {
+                break;
+            }
+            if !self.panes.iter().any(|p| p.service == line.label) {
+                rows.push(format!("{}{}", line.label, line.progress()));
+            }
+        }
+        if !self.panes.is_empty() {
+            rows.push(self.message.clone());
+        }
+        for row in rows.iter() {
+            // Longer lines would wrap, and the cursor would not move back
+            // to the first progress line.
+            let row: String = row.chars().take(width.saturating_sub(1)).collect();
+            write_line(out, &format!("{}\x1b[0m", row));
+        }
+        let _ = out.flush();
+        self.drawn = rows.len();
+        self.last_draw = Some(Instant::now());
+    }
+
+    fn redraw(&mut self) {
+        let mut out = std::io::stdout().lock();
+        self.clear(&mut out);
+        self.draw(&mut out);
+    }
+}
+
+struct Pane {
+    service: String,
+    state: State,
+    start: Option<Instant>,
+    end: Option<Instant>,
+    /// The last lines of output of the commands of the service.
+    output: Vec<String>,
+}
+
+impl Pane {
+    fn set_state(&mut self, state: State) {
+        if self.start.is_none() && state != State::Waiting {
+            self.start = Some(Instant::now());
+        }
+        if state.is_done() || state == State::Unchanged {
+            self.end = Some(Instant::now());
+        }
+        self.state = state;
+    }
+
+    /// The state and how long the service has taken so far.
+    fn status(&self) -> String {
+        match self.start {
+            Some(start) => {
+                let end = self.end.unwrap_or_else(Instant::now);
+                let seconds = end.duration_since(start).as_secs_f64();
+                format!("{} {:>6.1}s", self.state.colored(), seconds)
+            }
+            None => self.state.colored(),
+        }
+    }
+}
+
+struct Line {
+    id: usize,
+    label: String,
+    start: Instant,
+    done: u64,
+    total: u64,
+    /// What `done` and `total` count, e.g. `steps`.
+    unit: &'static str,
+    /// Whether each layer of an image push is pushed.
+    layers: BTreeMap<String, bool>,
+    /// The last line of output.
+    last: String,
+    output: Vec<String>,
+}
+
+impl Line {
+    fn update(&mut self, text: &str) {
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        if let Some((done, total)) = build_step(text) {
+            (self.done, self.total, self.unit) = (done, total, "steps");
+        } else if let Some((layer, pushed)) = pushed_layer(text) {
+            let pushed = pushed || self.layers.get(layer) == Some(&true);
+            self.layers.insert(layer.to_string(), pushed);
+            self.done = self.layers.values().filter(|pushed| **pushed).count() as u64;
+            self.total = self.layers.len() as u64;
+            self.unit = "layers";
+        } else if let Some(percent) = percent(text) {
+            (self.done, self.total, self.unit) = (percent, 100, "%");
+        }
+        self.last = text.to_string();
+    }
+
+    /// The elapsed time, the progress bar if the progress is known, and
+    /// the last line of output.
+    fn progress(&self) -> String {
+        let mut text = format!(" {:>4}s", self.start.elapsed().as_secs());
+        if let Some(filled) = (self.done.min(self.total) * 20).checked_div(self.total) {
+            let filled = filled as usize;
+            text += &format!(
+                " [{}{}] {}/{} {}",
+                "=".repeat(filled),
+                " ".repeat(20 - filled),
+                self.done,
+                self.total,
+                self.unit
+            );
+        }
+        text += "  ";
+        text += &self.last;
+        text
+    }
+}
+
+/// Writes a line, without its colors if colors are off.
+fn write_line(out: &mut impl Write, text: &str) {
+    let _ = writeln!(out, "{}", output::styled(text));
+}
+
+fn terminal_width() -> usize {
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
+
+fn terminal_height() -> usize {
+    std::env::var("LINES")
+        .ok()
+        .and_then(|lines| lines.parse().ok())
+        .unwrap_or(24)
+}
+
+/// The step of a build, from `#8 [build 2/5] RUN make` with BuildKit or
+/// `Step 2/5 : RUN make` with the legacy builder.
+fn build_step(text: &str) -> Option<(u64, u64)> {
+    let step = match text.strip_prefix("Step ") {
+        Some(rest) => rest.split_whitespace().next()?,
+        None => {
+            let start = text.find('[')? + 1;
+            let end = start + text[start..].find(']')?;
+            text[start..end].split_whitespace().last()?
+        }
+    };
+    let (done, total) = step.split_once('/')?;
+    Some((done.parse().ok()?, total.parse().ok()?))
+}
+
+/// The layer of a push and whether it is pushed, from `5f70bf18a086: Pushed`.
+fn pushed_layer(text: &str) -> Option<(&str, bool)> {
+    let (layer, status) = text.split_once(": ")?;
+    if layer.len() != 12 || !layer.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let pushed = status == "Pushed"
+        || status == "Layer already exists"
+        || status.starts_with("Mounted from");
+    Some((layer, pushed))
+}
+
+/// The percentage of an rsync progress update such as
+/// `1.05M  45%  1.20MB/s  0:00:01`.
+fn percent(text: &str) -> Option<u64> {
+    text.split_whitespace()
+        .find_map(|word| word.strip_suffix('%')?.parse().ok())
+        .filter(|percent| *percent <= 100)
+}
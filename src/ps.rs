@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A container as reported by `docker compose ps --format json`.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct PsEntry {
+    #[serde(default)]
+    pub name: String,
+    pub service: String,
+    pub image: String,
+    pub state: String,
+    /// Health status, or empty if the service has no healthcheck.
+    #[serde(default)]
+    pub health: String,
+    /// Human readable status, such as `Up 2 hours`.
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub exit_code: i32,
+}
+
+impl PsEntry {
+    /// Parses the output of `docker compose ps --format json`. Older compose
+    /// versions print a single JSON array, newer ones print one object per line.
+    pub fn parse_all(text: &str) -> Result<Vec<PsEntry>> {
+        let text = text.trim();
+        if text.starts_with('[') {
+            return serde_json::from_str(text).context("Failed to parse docker compose ps");
+        }
+        let mut entries = vec![];
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            entries.push(serde_json::from_str(line).context("Failed to parse docker compose ps")?);
+        }
+        Ok(entries)
+    }
+
+    /// Whether the container is restarting, or has exited with an error.
+    pub fn is_crashed(&self) -> bool {
+        self.state == "restarting" || (self.state == "exited" && self.exit_code != 0)
+    }
+}
+
+/// Returns the tag of an image reference, e.g. `1.2` for `registry:5000/app:1.2`.
+/// Images that are pinned to a digest without a tag return the digest,
+/// shortened to 12 characters like docker does, e.g. `sha256:0123456789ab`.
+pub fn image_tag(image: &str) -> &str {
+    let (name, digest) = match image.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (image, None),
+    };
+    match (name.rsplit_once(':'), digest) {
+        (Some((_, tag)), _) if !tag.contains('/') => tag,
+        (_, Some(digest)) => {
+            let hex = digest.find(':').map_or(0, |i| i + 1);
+            &digest[..digest.len().min(hex + 12)]
+        }
+        _ => "latest",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::image_tag;
+
+    #[test]
+    fn image_tag_of_tagged_images() {
+        assert_eq!(image_tag("registry:5000/app:1.2"), "1.2");
+        assert_eq!(image_tag("registry:5000/app"), "latest");
+        assert_eq!(image_tag("app:1.2@sha256:0123456789abcdef"), "1.2");
+    }
+
+    #[test]
+    fn image_tag_of_digest_pinned_images() {
+        assert_eq!(
+            image_tag("app@sha256:0123456789abcdef0123"),
+            "sha256:0123456789ab"
+        );
+        assert_eq!(
+            image_tag("registry:5000/app@sha256:0123456789abcdef"),
+            "sha256:0123456789ab"
+        );
+    }
+}
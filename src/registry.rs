@@ -0,0 +1,412 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+/// Media types of the manifests that are requested from the registry.
+const MANIFEST_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// An image reference split into its parts, with Docker Hub defaults filled in.
+#[derive(Debug)]
+pub struct ImageRef {
+    /// Host of the registry, e.g. `registry-1.docker.io`.
+    pub host: String,
+    /// Repository in the registry, e.g. `library/postgres`.
+    pub repository: String,
+    pub tag: String,
+}
+
+impl ImageRef {
+    pub fn parse(image: &str) -> ImageRef {
+        let image = image.split('@').next().unwrap_or(image);
+        let (name, tag) = match image.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, tag),
+            _ => (image, "latest"),
+        };
+        let (host, repository) = match name.split_once('/') {
+            Some((host, rest))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), rest.to_string())
+            }
+            Some(_) => ("registry-1.docker.io".to_string(), name.to_string()),
+            None => (
+                "registry-1.docker.io".to_string(),
+                format!("library/{}", name),
+            ),
+        };
+        let host = match host.as_str() {
+            "docker.io" | "index.docker.io" => "registry-1.docker.io".to_string(),
+            _ => host,
+        };
+        ImageRef {
+            host,
+            repository,
+            tag: tag.to_string(),
+        }
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Quotes a value for a curl config file.
+pub(crate) fn curl_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Sends a request with curl. Credentials and headers are passed on stdin,
+/// so they don't show up in the process list.
+fn send(method: &str, url: &str, config: &[String]) -> Result<Response> {
+    let mut process = Command::new("curl")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--include")
+        .arg("--location")
+        .arg("--request")
+        .arg(method)
+        .arg("--config")
+        .arg("-")
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run curl")?;
+    let stdin = process.stdin.as_mut().context("No stdin")?;
+    stdin.write_all(config.join("\n").as_bytes())?;
+    let output = process.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "Failed to {} {}: {}",
+            method,
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let mut text = String::from_utf8(output.stdout)?;
+    // Redirects and `100 Continue` add a header block per response.
+    loop {
+        let (head, body) = text
+            .split_once("\r\n\r\n")
+            .context(format!("Invalid response from {}", url))?;
+        if body.starts_with("HTTP/") {
+            text = body.to_string();
+            continue;
+        }
+        let mut lines = head.lines();
+        let status = lines
+            .next()
+            .and_then(|l| l.split_whitespace().nth(1))
+            .and_then(|s| s.parse().ok())
+            .context(format!("Invalid response from {}", url))?;
+        let headers = lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .collect();
+        return Ok(Response {
+            status,
+            headers,
+            body: body.to_string(),
+        });
+    }
+}
+
+enum Authorization {
+    Anonymous,
+    Basic(String, String),
+    Bearer(String),
+}
+
+/// A client for the Docker Registry HTTP API V2, scoped to one repository.
+pub struct Client {
+    host: String,
+    repository: String,
+    authorization: Authorization,
+}
+
+impl Client {
+    /// Authenticates for `actions` on the repository, such as `pull` or
+    /// `pull,delete`. Without credentials, an anonymous token is requested.
+    pub fn new(
+        image: &ImageRef,
+        credentials: Option<(String, String)>,
+        actions: &str,
+    ) -> Result<Client> {
+        let mut client = Client {
+            host: image.host.clone(),
+            repository: image.repository.clone(),
+            authorization: Authorization::Anonymous,
+        };
+        let response = send("GET", &format!("https://{}/v2/", client.host), &[])?;
+        if response.status != 401 {
+            return Ok(client);
+        }
+        let challenge = response
+            .header("www-authenticate")
+            .context(format!("{} did not say how to authenticate", client.host))?;
+        if challenge.starts_with("Basic") {
+            let (username, password) = credentials.context(format!(
+                "{} needs credentials. Set registry_auth",
+                client.host
+            ))?;
+            client.authorization = Authorization::Basic(username, password);
+            return Ok(client);
+        }
+        let challenge = challenge
+            .strip_prefix("Bearer ")
+            .context(format!("Unsupported authentication: {}", challenge))?;
+        let token = token(challenge, &client.repository, credentials, actions)?;
+        client.authorization = Authorization::Bearer(token);
+        Ok(client)
+    }
+
+    fn request(&self, method: &str, path: &str, accept: &[&str]) -> Result<Response> {
+        let mut config = vec![];
+        match &self.authorization {
+            Authorization::Anonymous => {}
+            Authorization::Basic(username, password) => {
+                let user = format!("{}:{}", username, password);
+                config.push(format!("user = {}", curl_quote(&user)));
+            }
+            Authorization::Bearer(token) => {
+                let header = format!("Authorization: Bearer {}", token);
+                config.push(format!("header = {}", curl_quote(&header)));
+            }
+        }
+        if !accept.is_empty() {
+            let header = format!("Accept: {}", accept.join(", "));
+            config.push(format!("header = {}", curl_quote(&header)));
+        }
+        send(method, &format!("https://{}{}", self.host, path), &config)
+    }
+
+    /// Lists all tags of the repository.
+    pub fn tags(&self) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct TagList {
+            tags: Option<Vec<String>>,
+        }
+
+        let mut tags = vec![];
+        let mut path = format!("/v2/{}/tags/list?n=1000", self.repository);
+        loop {
+            let response = self.request("GET", &path, &[])?;
+            if response.status != 200 {
+                bail!(
+                    "Failed to list the tags of {}: {}",
+                    self.repository,
+                    response.status
+                );
+            }
+            let list: TagList = serde_json::from_str(&response.body)
+                .context(format!("Failed to parse the tags of {}", self.repository))?;
+            tags.extend(list.tags.unwrap_or_default());
+            // The next page is given as `Link: </v2/...>; rel="next"`.
+            let next = response
+                .header("link")
+                .and_then(|l| l.strip_prefix('<'))
+                .and_then(|l| l.split_once('>'))
+                .map(|(path, _)| path.to_string());
+            match next {
+                Some(next) => path = next,
+                None => break,
+            }
+        }
+        Ok(tags)
+    }
+
+    /// Returns the digest and contents of a manifest.
+    fn manifest(&self, reference: &str) -> Result<(String, Manifest)> {
+        let path = format!("/v2/{}/manifests/{}", self.repository, reference);
+        let response = self.request("GET", &path, MANIFEST_TYPES)?;
+        if response.status != 200 {
+            bail!(
+                "Failed to get the manifest of {}:{}: {}",
+                self.repository,
+                reference,
+                response.status
+            );
+        }
+        let digest = response
+            .header("docker-content-digest")
+            .context("The registry did not return a Docker-Content-Digest")?
+            .to_string();
+        let manifest = serde_json::from_str(&response.body)
+            .context(format!("Failed to parse the manifest of {}", reference))?;
+        Ok((digest, manifest))
+    }
+
+    /// Returns the manifest digest of a tag and when its image was created.
+    /// For multi-platform images, the first platform's date is used.
+    pub fn created(&self, tag: &str) -> Result<(String, Option<DateTime<Utc>>)> {
+        #[derive(Deserialize)]
+        struct ImageConfig {
+            created: Option<DateTime<Utc>>,
+        }
+
+        let (digest, manifest) = self.manifest(tag)?;
+        let manifest = match manifest.manifests.first() {
+            Some(platform) => self.manifest(&platform.digest)?.1,
+            None => manifest,
+        };
+        let Some(config) = manifest.config else {
+            return Ok((digest, None));
+        };
+        let path = format!("/v2/{}/blobs/{}", self.repository, config.digest);
+        let response = self.request("GET", &path, &[])?;
+        if response.status != 200 {
+            bail!(
+                "Failed to get the image config of {}: {}",
+                tag,
+                response.status
+            );
+        }
+        let image: ImageConfig = serde_json::from_str(&response.body)
+            .context(format!("Failed to parse the image config of {}", tag))?;
+        Ok((digest, image.created))
+    }
+
+    /// Deletes a manifest, and with it every tag that points to it.
+    pub fn delete(&self, digest: &str) -> Result<()> {
+        let path = format!("/v2/{}/manifests/{}", self.repository, digest);
+        let response = self.request("DELETE", &path, &[])?;
+        match response.status {
+            200 | 202 => Ok(()),
+            405 => bail!("{} does not allow deleting images", self.host),
+            status => bail!(
+                "Failed to delete {}@{}: {}",
+                self.repository,
+                digest,
+                status
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Descriptor {
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    config: Option<Descriptor>,
+    /// Platform manifests of a manifest list or image index.
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+/// Gets a bearer token from the realm of a `WWW-Authenticate` challenge.
+fn token(
+    challenge: &str,
+    repository: &str,
+    credentials: Option<(String, String)>,
+    actions: &str,
+) -> Result<String> {
+    #[derive(Deserialize)]
+    struct Token {
+        token: Option<String>,
+        access_token: Option<String>,
+    }
+
+    let param = |name: &str| {
+        challenge.split(',').find_map(|p| {
+            let (k, v) = p.trim().split_once('=')?;
+            (k == name).then(|| v.trim_matches('"').to_string())
+        })
+    };
+    let realm = param("realm").context("No realm in the authentication challenge")?;
+    let mut url = format!("{}?scope=repository:{}:{}", realm, repository, actions);
+    if let Some(service) = param("service") {
+        url.push_str(&format!("&service={}", service));
+    }
+    let mut config = vec![];
+    if let Some((username, password)) = credentials {
+        let user = format!("{}:{}", username, password);
+        config.push(format!("user = {}", curl_quote(&user)));
+    }
+    let response = send("GET", &url, &config)?;
+    if response.status != 200 {
+        bail!(
+            "Failed to get a token for {}: {}",
+            repository,
+            response.status
+        );
+    }
+    let token: Token = serde_json::from_str(&response.body)?;
+    token
+        .token
+        .or(token.access_token)
+        .context(format!("No token for {} in the response", repository))
+}
+
+/// A tag such as `16.2-alpine`, split into its version numbers and the rest.
+struct VersionTag<'a> {
+    prefix: &'a str,
+    numbers: Vec<u64>,
+    suffix: &'a str,
+}
+
+impl<'a> VersionTag<'a> {
+    fn parse(tag: &'a str) -> Option<Self> {
+        let prefix = if tag.starts_with('v') { "v" } else { "" };
+        let rest = &tag[prefix.len()..];
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let version = rest[..end].trim_end_matches('.');
+        let suffix = &rest[version.len()..];
+        if version.is_empty() {
+            return None;
+        }
+        let numbers = version
+            .split('.')
+            .map(|n| n.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        Some(VersionTag {
+            prefix,
+            numbers,
+            suffix,
+        })
+    }
+
+    /// Whether the tags follow the same scheme, such as `16-alpine` and
+    /// `17-alpine`, but not `16-alpine` and `16.2-alpine`.
+    fn same_scheme(&self, other: &VersionTag) -> bool {
+        self.prefix == other.prefix
+            && self.suffix == other.suffix
+            && self.numbers.len() == other.numbers.len()
+    }
+}
+
+/// The newest tag that follows the same versioning scheme as `current`
+/// and is newer than it.
+pub fn newer_tag<'a>(current: &str, tags: &'a [String]) -> Option<&'a str> {
+    let current = VersionTag::parse(current)?;
+    tags.iter()
+        .filter_map(|t| Some((t.as_str(), VersionTag::parse(t)?)))
+        .filter(|(_, v)| v.same_scheme(&current))
+        .filter(|(_, v)| v.numbers > current.numbers)
+        .max_by(|(_, a), (_, b)| a.numbers.cmp(&b.numbers))
+        .map(|(t, _)| t)
+}
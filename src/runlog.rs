@@ -0,0 +1,80 @@
+//! The log of a run in `.dep/logs`, with the messages and the full output,
+//! duration and exit code of every command, for finding out why a deploy
+//! failed after the fact. Only stderr is logged for commands whose output
+//! dep reads, since that may be a decrypted secret.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer;
+
+use crate::{command_line, output};
+
+pub const LOGS_DIR: &str = ".dep/logs";
+
+static LOG: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+/// Starts writing the log of this process to a new file named after the
+/// time and `name`, e.g. `.dep/logs/2026-10-16T12-30-00Z-deploy.log`.
+pub fn start(name: &str) -> Result<()> {
+    std::fs::create_dir_all(LOGS_DIR).context(format!("Failed to create {}", LOGS_DIR))?;
+    let time = chrono::Utc::now().format("%Y-%m-%dT%H-%M-%SZ");
+    let path = Path::new(LOGS_DIR).join(format!("{}-{}.log", time, name));
+    let file = File::create(&path).context(format!("Failed to create {}", path.display()))?;
+    *LOG.lock().unwrap() = Some((path, file));
+    Ok(())
+}
+
+/// The file the log is written to, if it was started.
+pub fn path() -> Option<PathBuf> {
+    LOG.lock().unwrap().as_ref().map(|(path, _)| path.clone())
+}
+
+pub fn is_active() -> bool {
+    LOG.lock().unwrap().is_some()
+}
+
+/// Writes output or a message to the log, without colors.
+pub fn write(text: &[u8]) {
+    if let Some((_, file)) = LOG.lock().unwrap().as_mut() {
+        let _ = file.write_all(&output::strip_colors(text));
+    }
+}
+
+/// Writes a line to the log.
+pub fn message(text: &str) {
+    write(format!("{}\n", text).as_bytes());
+}
+
+/// Writes the messages of `tracing` events to the log.
+pub struct Layer;
+
+impl<S: Subscriber> layer::Layer<S> for Layer {
+    fn on_event(&self, event: &Event<'_>, _context: layer::Context<'_, S>) {
+        message(&output::message(event));
+    }
+}
+
+/// Writes that a command is started.
+pub fn started(command: &Command) {
+    message(&format!("$ {}", command_line(command)));
+}
+
+/// Writes how a command that was started at `start` exited.
+pub fn finished(status: &ExitStatus, start: &Instant) {
+    let code = match status.code() {
+        Some(code) => code.to_string(),
+        None => "signal".to_string(),
+    };
+    message(&format!(
+        "[exit {} after {:.2} seconds]",
+        code,
+        start.elapsed().as_secs_f64()
+    ));
+}
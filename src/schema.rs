@@ -0,0 +1,121 @@
+//! The JSON Schema of deployment.yaml, for editors that validate and
+//! complete it. It is derived from [DepConfig] with schemars, and the
+//! descriptions are the doc comments of its fields.
+
+use serde_json::{json, Value};
+
+use crate::DepConfig;
+
+/// The JSON Schema of deployment.yaml.
+pub fn schema() -> Value {
+    let mut schema = serde_json::to_value(schemars::schema_for!(DepConfig))
+        .expect("a schema is always valid JSON");
+    schema["title"] = json!(crate::DEP_CONFIG_PATH);
+    schema
+}
+
+/// The keys of deployment.yaml with their description, for the reference in
+/// `dep help --all` and `dep man`. The keys of settings that are a single
+/// object, such as `ssh`, are joined with dots, e.g. `ssh.port`.
+pub fn keys() -> Vec<(String, String)> {
+    let schema = schema();
+    let mut keys = vec![];
+    collect_keys(&schema, &schema, "", &mut keys);
+    keys
+}
+
+fn collect_keys(root: &Value, object: &Value, prefix: &str, keys: &mut Vec<(String, String)>) {
+    let properties = object["properties"].as_object().into_iter().flatten();
+    for (name, property) in properties {
+        let key = format!("{}{}", prefix, name);
+        let resolved = resolve(root, property);
+        if resolved["properties"].is_object() {
+            collect_keys(root, resolved, &format!("{}.", key), keys);
+            continue;
+        }
+        let mut description = describe(property)
+            .or_else(|| describe(resolved))
+            .unwrap_or_default()
+            .replace('\n', " ");
+        let values = values(resolved);
+        if !values.is_empty() {
+            description = format!("{} One of {}.", description, values.join(", "));
+        }
+        // Defaults that are not false, zero or empty are worth mentioning.
+        match &property["default"] {
+            Value::Bool(true) => description += " Defaults to true.",
+            Value::Number(n) if n.as_f64() != Some(0.0) => {
+                description += &format!(" Defaults to {}.", n)
+            }
+            Value::String(s) if !s.is_empty() => description += &format!(" Defaults to `{}`.", s),
+            _ => {}
+        }
+        keys.push((key, description.trim().to_string()));
+    }
+}
+
+/// The possible values of an enum, which schemars writes as `oneOf` the
+/// values when they have descriptions.
+fn values(schema: &Value) -> Vec<String> {
+    let alternatives = match schema["oneOf"].as_array() {
+        Some(alternatives) => alternatives.iter().collect(),
+        None => vec![schema],
+    };
+    alternatives
+        .iter()
+        .filter_map(|a| a["enum"].as_array())
+        .flatten()
+        .filter_map(|v| Some(format!("`{}`", v.as_str()?)))
+        .collect()
+}
+
+/// The schema that `schema` refers to, also through an optional value,
+/// which schemars writes as `anyOf` the reference and null.
+fn resolve<'a>(root: &'a Value, schema: &'a Value) -> &'a Value {
+    let alternatives = schema["allOf"].as_array().or(schema["anyOf"].as_array());
+    let reference = match alternatives {
+        Some(alternatives) if alternatives.len() <= 2 => alternatives
+            .iter()
+            .find_map(|a| a["$ref"].as_str())
+            .filter(|_| {
+                alternatives
+                    .iter()
+                    .all(|a| a["$ref"].is_string() || a["type"] == "null")
+            }),
+        _ => schema["$ref"].as_str(),
+    };
+    match reference.and_then(|r| r.strip_prefix("#/definitions/")) {
+        Some(name) => resolve(root, &root["definitions"][name]),
+        None => schema,
+    }
+}
+
+fn describe(schema: &Value) -> Option<String> {
+    schema["description"].as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_keys_are_joined_with_dots() {
+        let keys = keys();
+        let find = |key: &str| keys.iter().find(|(k, _)| k == key).map(|(_, d)| d.clone());
+        assert_eq!(find("ssh.port").unwrap(), "Port of the ssh server.");
+        assert!(find("transport")
+            .unwrap()
+            .ends_with("One of `registry`, `ssh`, `ssh-zstd`."));
+        assert!(find("retry.attempts").unwrap().ends_with("Defaults to 3."));
+        assert!(find("ssh").is_none());
+        assert!(find("environments").is_some());
+    }
+
+    #[test]
+    fn environments_allow_the_overridden_keys() {
+        let schema = schema();
+        let environment = &schema["definitions"]["Environment"]["properties"];
+        assert!(environment["server"].is_object());
+        assert!(environment["build"].is_null());
+    }
+}
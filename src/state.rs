@@ -0,0 +1,42 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Local file that remembers which build contexts have been pushed.
+pub const BUILD_STATE_PATH: &str = ".dep/build-state.yaml";
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct BuildState {
+    /// Pushed images by repository, e.g. `registry.example.org/web`.
+    #[serde(default)]
+    pub images: BTreeMap<String, PushedImage>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PushedImage {
+    /// Hash of the build context the image was built from.
+    pub hash: String,
+    pub image: String,
+}
+
+impl BuildState {
+    pub fn read() -> Result<Self> {
+        if !Path::new(BUILD_STATE_PATH).exists() {
+            return Ok(BuildState::default());
+        }
+        let text = std::fs::read_to_string(BUILD_STATE_PATH)
+            .context(format!("Failed to read {}", BUILD_STATE_PATH))?;
+        serde_yaml::from_str(&text).context(format!("Failed to parse {}", BUILD_STATE_PATH))
+    }
+
+    pub fn write(&self) -> Result<()> {
+        let path = Path::new(BUILD_STATE_PATH);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_yaml::to_string(self)?)
+            .context(format!("Failed to write {}", BUILD_STATE_PATH))
+    }
+}
@@ -0,0 +1,202 @@
+//! Starter projects for `dep init --template`.
+//!
+//! Every template has restart policies, healthchecks and rotated logs, and
+//! waits for the healthchecks when deploying.
+
+use std::path::PathBuf;
+
+/// Keeps at most 3 log files of 10 MB for each container.
+const LOGGING: &str = "    logging:
+      driver: json-file
+      options:
+        max-size: 10m
+        max-file: \"3\"
+";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Template {
+    /// A web app behind traefik, with certificates from Let's Encrypt.
+    TraefikWeb,
+    /// A web app with postgres and redis.
+    PostgresRedis,
+    /// A static site in `public/`, served by nginx.
+    StaticSite,
+}
+
+impl Template {
+    /// The docker-compose.yaml of the template, for the project `name`.
+    pub fn compose(self, name: &str) -> String {
+        let compose = match self {
+            Template::TraefikWeb => TRAEFIK_WEB,
+            Template::PostgresRedis => POSTGRES_REDIS,
+            Template::StaticSite => STATIC_SITE,
+        };
+        // Dots separate the parts of traefik labels.
+        compose
+            .replace("{logging}\n", LOGGING)
+            .replace("{router}", &name.replace('.', "-"))
+            .replace("{name}", name)
+    }
+
+    /// Files that the template needs besides docker-compose.yaml, with their
+    /// contents. They are only created when they do not exist.
+    pub fn files(self) -> Vec<(PathBuf, &'static str)> {
+        match self {
+            Template::StaticSite => vec![(PathBuf::from("public/index.html"), INDEX_HTML)],
+            _ => vec![],
+        }
+    }
+
+    /// What is left to do after creating the project.
+    pub fn next_steps(self) -> &'static [&'static str] {
+        match self {
+            Template::TraefikWeb => &[
+                "Add a Dockerfile for web that listens on port 8080",
+                "Replace the host name and the Let's Encrypt email in docker-compose.yaml",
+            ],
+            Template::PostgresRedis => &[
+                "Add a Dockerfile for web that listens on port 8080",
+                "Set POSTGRES_PASSWORD in .env on the server, or push .env with secrets",
+            ],
+            Template::StaticSite => &["Put the site in public/"],
+        }
+    }
+
+    /// The `additionalFiles` of the template.
+    pub fn additional_files(self) -> Vec<PathBuf> {
+        match self {
+            Template::StaticSite => vec![PathBuf::from("public")],
+            _ => vec![],
+        }
+    }
+}
+
+const TRAEFIK_WEB: &str = r#"services:
+  traefik:
+    image: traefik:v3.1
+    restart: unless-stopped
+    command:
+      - --ping=true
+      - --providers.docker=true
+      - --providers.docker.exposedbydefault=false
+      - --entrypoints.web.address=:80
+      - --entrypoints.web.http.redirections.entrypoint.to=websecure
+      - --entrypoints.websecure.address=:443
+      - --certificatesresolvers.letsencrypt.acme.tlschallenge=true
+      # The address that Let's Encrypt sends expiry warnings to.
+      - --certificatesresolvers.letsencrypt.acme.email=admin@example.org
+      - --certificatesresolvers.letsencrypt.acme.storage=/letsencrypt/acme.json
+    ports:
+      - "80:80"
+      - "443:443"
+    volumes:
+      - /var/run/docker.sock:/var/run/docker.sock:ro
+      - letsencrypt:/letsencrypt
+    healthcheck:
+      test: ["CMD", "traefik", "healthcheck", "--ping"]
+      interval: 10s
+      timeout: 5s
+      retries: 3
+{logging}
+  web:
+    build: .
+    restart: unless-stopped
+    labels:
+      - traefik.enable=true
+      - traefik.http.routers.{router}.rule=Host(`{name}.example.org`)
+      - traefik.http.routers.{router}.entrypoints=websecure
+      - traefik.http.routers.{router}.tls.certresolver=letsencrypt
+      - traefik.http.services.{router}.loadbalancer.server.port=8080
+    healthcheck:
+      # Change this to a request that checks that the app works.
+      test: ["CMD", "wget", "-q", "-O", "/dev/null", "http://localhost:8080/"]
+      interval: 10s
+      timeout: 5s
+      retries: 3
+      start_period: 30s
+{logging}
+volumes:
+  letsencrypt:
+"#;
+
+const POSTGRES_REDIS: &str = r#"services:
+  web:
+    build: .
+    restart: unless-stopped
+    ports:
+      - "127.0.0.1:8080:8080"
+    environment:
+      DATABASE_URL: postgres://{name}:${POSTGRES_PASSWORD:?Set POSTGRES_PASSWORD in .env}@db:5432/{name}
+      REDIS_URL: redis://redis:6379/0
+    depends_on:
+      db:
+        condition: service_healthy
+      redis:
+        condition: service_healthy
+    healthcheck:
+      # Change this to a request that checks that the app works.
+      test: ["CMD", "wget", "-q", "-O", "/dev/null", "http://localhost:8080/"]
+      interval: 10s
+      timeout: 5s
+      retries: 3
+      start_period: 30s
+{logging}
+  db:
+    image: postgres:16-alpine
+    restart: unless-stopped
+    environment:
+      POSTGRES_USER: {name}
+      POSTGRES_DB: {name}
+      POSTGRES_PASSWORD: ${POSTGRES_PASSWORD:?Set POSTGRES_PASSWORD in .env}
+    volumes:
+      - postgres:/var/lib/postgresql/data
+    healthcheck:
+      test: ["CMD-SHELL", "pg_isready -U {name} -d {name}"]
+      interval: 10s
+      timeout: 5s
+      retries: 5
+{logging}
+  redis:
+    image: redis:7-alpine
+    restart: unless-stopped
+    command: ["redis-server", "--appendonly", "yes"]
+    volumes:
+      - redis:/data
+    healthcheck:
+      test: ["CMD", "redis-cli", "ping"]
+      interval: 10s
+      timeout: 5s
+      retries: 5
+{logging}
+volumes:
+  postgres:
+  redis:
+"#;
+
+const STATIC_SITE: &str = r#"services:
+  web:
+    image: nginx:1.27-alpine
+    restart: unless-stopped
+    ports:
+      - "80:80"
+    volumes:
+      - ./public:/usr/share/nginx/html:ro
+    healthcheck:
+      test: ["CMD", "wget", "-q", "-O", "/dev/null", "http://localhost/"]
+      interval: 10s
+      timeout: 5s
+      retries: 3
+{logging}
+"#;
+
+const INDEX_HTML: &str = "<!doctype html>
+<html>
+  <head>
+    <meta charset=\"utf-8\">
+    <title>It works</title>
+  </head>
+  <body>
+    <h1>It works</h1>
+  </body>
+</html>
+";